@@ -0,0 +1,59 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::config::normalize_path;
+use crate::fs::FileSystem;
+use crate::mtimecache::MtimeCache;
+
+/// One `compile_commands.json` entry; the compiler invocation itself is ignored.
+#[derive(Debug, Deserialize)]
+struct Entry {
+    directory: PathBuf,
+    file: PathBuf,
+}
+
+/// Loaded databases keyed by path.
+static CACHE: MtimeCache<PathBuf, Vec<Entry>> = MtimeCache::new();
+
+/// `directory` of every entry in the compilation database `database` whose
+/// `file` is `annotated_file`, in database order without duplicates. Relative
+/// `file`s are taken from their entry's `directory`, relative `directory`s from
+/// the database's. A missing or malformed database maps nothing.
+pub fn directories_for(
+    fs: &dyn FileSystem,
+    database: &Path,
+    annotated_file: &Path,
+) -> Vec<PathBuf> {
+    let Some(entries) = load(fs, database) else {
+        return Vec::new();
+    };
+    let Some(database_dir) = database.parent() else {
+        return Vec::new();
+    };
+    let annotated_file = normalize_path(annotated_file, fs);
+
+    let mut directories: Vec<PathBuf> = Vec::new();
+    for entry in entries.iter() {
+        let directory = normalize_path(&database_dir.join(&entry.directory), fs);
+        if normalize_path(&directory.join(&entry.file), fs) == annotated_file
+            && !directories.contains(&directory)
+        {
+            directories.push(directory);
+        }
+    }
+    directories
+}
+
+/// Returns the parsed database, rereading it only when its mtime changed.
+fn load(fs: &dyn FileSystem, database: &Path) -> Option<Arc<Vec<Entry>>> {
+    let modified = fs.metadata(database).ok()?.modified;
+    if let Some(entries) = CACHE.get(database, modified) {
+        return Some(entries);
+    }
+
+    let text = fs.read_to_string(database).ok()?;
+    let entries: Vec<Entry> = serde_json::from_str(&text).ok()?;
+    Some(CACHE.insert(database.to_path_buf(), modified, entries))
+}
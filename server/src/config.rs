@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::fs::{fold_dots, FileSystem};
+
+/// Environment variable supplying `baseDir` when the option is not set.
+pub const BASE_DIR_ENV: &str = "FIRRTL_SOURCE_LOCATOR_BASE_DIR";
+
+/// Which annotations Go to Definition deduplicates destinations across.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupScope {
+    /// Only the annotation under the caret.
+    #[default]
+    Annotation,
+    /// Every annotation of the document, when invoked on the `// @[` trigger.
+    File,
+}
+
+/// Which location notations are recognized.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LocationSyntax {
+    /// Only FIRRTL/Chisel `@[...]` annotations.
+    #[default]
+    Firrtl,
+    /// `@[...]` plus MLIR/CIRCT `loc("A.scala":10:3)` locations.
+    All,
+}
+
+/// Marker drawn under target columns in hovers.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndicatorStyle {
+    /// `^`
+    #[default]
+    Caret,
+    /// `‾`
+    Underline,
+    /// `~`
+    Tilde,
+}
+
+impl IndicatorStyle {
+    pub fn marker(self) -> char {
+        match self {
+            Self::Caret => '^',
+            Self::Underline => '‾',
+            Self::Tilde => '~',
+        }
+    }
+}
+
+/// What to do when a relative locator path exists under several source roots.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AmbiguousResolution {
+    /// Use the first existing candidate (sibling directory, then roots in order).
+    #[default]
+    First,
+    /// Return a link for every existing candidate.
+    All,
+    /// Like `all`, and tell the user that the picker lists several roots.
+    Prompt,
+}
+
+/// Shape of Go to Definition responses.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GotoResponseKind {
+    /// `LocationLink`s, with target and selection ranges.
+    #[default]
+    Link,
+    /// Plain `Location`s, for clients that render links poorly.
+    Location,
+}
+
+/// Server options read from the client's `initializationOptions`.
+///
+/// Keys use camelCase to match Zed's `lsp.firrtl-source-locator.initialization_options`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct Config {
+    /// Directory names (or root-relative paths) skipped when walking source roots,
+    /// in addition to the built-in VCS/build directories.
+    pub exclude_dirs: Vec<String>,
+    /// Only treat `@[...]` as a locator when a `//` comment marker directly precedes it.
+    pub annotation_require_comment_prefix: bool,
+    /// Base line for relative `+N`/`-N` locator lines; defaults to the annotation's own line.
+    pub relative_line_base: Option<u32>,
+    /// Extra directories relative locator paths are resolved against, after the
+    /// annotated file's own directory. Relative entries are anchored at the workspace root.
+    pub source_roots: Vec<PathBuf>,
+    pub ambiguous_resolution: AmbiguousResolution,
+    /// Whether Go to Definition on the `// @[` trigger collapses destinations per
+    /// annotation or across the whole document.
+    pub goto_dedup_scope: DedupScope,
+    /// Select the mapped column on jump; when off the cursor lands there with no selection.
+    pub select_target_on_jump: bool,
+    /// Widen a jump's target range to the identifier at the mapped column, from its first character.
+    pub select_target_identifier: bool,
+    /// Document extensions (without the dot) the server answers for; empty means all.
+    pub restrict_to_extensions: Vec<String>,
+    /// Publish warnings for locator lines past the end of their target file.
+    /// Off by default because it reads every referenced target.
+    pub diagnose_line_overflow: bool,
+    /// Relative path prefixes (e.g. `chisel3/`) mapped to the absolute root the
+    /// rest of the path resolves against.
+    pub root_aliases: HashMap<String, PathBuf>,
+    /// How long a disk read of an unopened document may take before it is
+    /// treated as unavailable, in milliseconds.
+    pub read_timeout_ms: u64,
+    /// JSON file mapping module names to source files, consulted for locator
+    /// paths like `MyModule` that have neither a separator nor an extension.
+    pub module_index: Option<PathBuf>,
+    /// Add the nearest enclosing definition (`in def bar`) of the target line to
+    /// token hovers. Off by default because it reads the whole target file.
+    pub show_enclosing_symbol: bool,
+    /// Largest file read from disk for an unopened document; bigger files are
+    /// treated as unavailable. Documents open in the editor are exempt.
+    pub max_document_bytes: u64,
+    /// Keep Go to Definition links in token order instead of sorting them by
+    /// target file, line and column.
+    pub preserve_link_order: bool,
+    /// Location notations to recognize besides `@[...]`.
+    pub location_syntax: LocationSyntax,
+    /// Marker character of the column indicator line in hovers.
+    pub indicator_style: IndicatorStyle,
+    /// How many target lines read from disk are kept in memory for hovers; `0`
+    /// disables the cache.
+    pub source_line_cache_size: usize,
+    /// Hovering the trigger of, or the gap between, several annotations on one
+    /// line shows one summary of all their tokens.
+    pub merge_same_line_annotations: bool,
+    /// Directory relative locator paths are tried against before the annotated
+    /// file's directory and `sourceRoots`. Falls back to `FIRRTL_SOURCE_LOCATOR_BASE_DIR`.
+    pub base_dir: Option<PathBuf>,
+    /// When a locator points into another `.fir`/`.firrtl` file, token hovers
+    /// also show the annotations on that line, one level deep.
+    pub follow_firrtl_targets: bool,
+    /// Longest target line shown in full in hovers; longer lines are cut to a
+    /// window of this many characters around the marked columns.
+    pub max_source_line_width: Option<usize>,
+    /// Target extensions (without the dot) whose locator columns are 0-based
+    /// instead of FIRRTL's 1-based columns.
+    pub zero_based_columns_for: Vec<String>,
+    /// Git revision (e.g. `HEAD`) to show target lines from instead of the
+    /// working tree; files not tracked at that revision are read as usual.
+    pub source_revision: Option<String>,
+    /// Also treat double-quoted strings that are exactly one `path:line:col`
+    /// locator as annotations, e.g. values in JSON attribute files.
+    pub bare_locator_strings: bool,
+    /// Directories resolved targets must lie in; targets elsewhere get no link
+    /// and are never read. Empty allows every target.
+    pub allowed_resolve_roots: Vec<PathBuf>,
+    /// Prefix removed from locator paths shown in hovers, e.g. `/home/ci/workspace/`.
+    /// Navigation still uses the full path.
+    pub strip_display_prefix: Option<String>,
+    /// `compile_commands.json` whose `directory` entries relative locator paths of
+    /// the listed files resolve against, after `baseDir`.
+    pub compile_commands: Option<PathBuf>,
+    /// Start summary hovers with a sentence describing what the annotation maps to.
+    pub explanatory_hover: bool,
+    /// List the previous and next tokens of the annotation (`from` / `into`) in token hovers.
+    pub show_chain_context: bool,
+    /// Footer a token hover with how often its location is referenced in the file.
+    pub show_reference_count: bool,
+    /// Also resolve relative locator paths against the nearest ancestor of the
+    /// annotated file containing one of `projectRootMarkers`.
+    pub resolve_from_project_root: bool,
+    /// File or directory names marking a project root for `resolveFromProjectRoot`.
+    pub project_root_markers: Vec<String>,
+    /// Most Go to Definition links returned at once; later ones are dropped and logged.
+    pub max_goto_links: Option<usize>,
+    /// Whether Go to Definition answers with `LocationLink`s or plain `Location`s.
+    pub goto_response_kind: GotoResponseKind,
+    /// Also recognize fullwidth `＠［...］` annotations, as substituted by some input methods.
+    pub unicode_brackets: bool,
+    /// Read gzip-compressed targets: paths ending in `.gz`, or a `.gz` sibling
+    /// when the resolved file does not exist.
+    pub support_gzip_sources: bool,
+    /// Character opening an annotation before its `[`, as in `@[...]` or `#[...]`.
+    pub annotation_sigil: char,
+    /// Parse a token identical to the one before it in its annotation only once.
+    pub collapse_duplicate_tokens: bool,
+    /// Put the annotated line of the hovered document above a token hover's source lines.
+    pub show_generated_line: bool,
+    /// Resolve `jar:file:<jar>!/<entry>` locators to a copy of the entry extracted from the jar.
+    pub support_jar_sources: bool,
+    /// How many Go to Definition jumps `firrtl.recentJumps` remembers; `0` keeps none.
+    pub recent_jumps_limit: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            exclude_dirs: Vec::new(),
+            annotation_require_comment_prefix: false,
+            relative_line_base: None,
+            source_roots: Vec::new(),
+            ambiguous_resolution: AmbiguousResolution::default(),
+            goto_dedup_scope: DedupScope::default(),
+            select_target_on_jump: true,
+            select_target_identifier: false,
+            restrict_to_extensions: Vec::new(),
+            diagnose_line_overflow: false,
+            root_aliases: HashMap::new(),
+            read_timeout_ms: 2000,
+            module_index: None,
+            show_enclosing_symbol: false,
+            max_document_bytes: 16 * 1024 * 1024,
+            preserve_link_order: false,
+            location_syntax: LocationSyntax::default(),
+            indicator_style: IndicatorStyle::default(),
+            source_line_cache_size: 256,
+            merge_same_line_annotations: false,
+            base_dir: None,
+            follow_firrtl_targets: false,
+            max_source_line_width: None,
+            zero_based_columns_for: Vec::new(),
+            source_revision: None,
+            bare_locator_strings: false,
+            allowed_resolve_roots: Vec::new(),
+            strip_display_prefix: None,
+            compile_commands: None,
+            explanatory_hover: false,
+            show_chain_context: false,
+            show_reference_count: false,
+            resolve_from_project_root: false,
+            project_root_markers: vec![
+                "build.sbt".to_string(),
+                "Cargo.toml".to_string(),
+                ".git".to_string(),
+            ],
+            max_goto_links: None,
+            goto_response_kind: GotoResponseKind::default(),
+            unicode_brackets: false,
+            support_gzip_sources: false,
+            annotation_sigil: '@',
+            collapse_duplicate_tokens: false,
+            show_generated_line: false,
+            support_jar_sources: false,
+            recent_jumps_limit: 50,
+        }
+    }
+}
+
+impl Config {
+    /// The annotation opener, `@[` unless `annotationSigil` says otherwise.
+    pub fn annotation_opener(&self) -> String {
+        format!("{}[", self.annotation_sigil)
+    }
+
+    /// Reads the options key by key: valid keys are applied, and every unknown or
+    /// ill-typed key is skipped with a warning naming it.
+    pub fn from_initialization_options(options: Option<Value>) -> (Self, Vec<String>) {
+        let options = match options {
+            None | Some(Value::Null) => return (Self::default(), Vec::new()),
+            Some(Value::Object(options)) => options,
+            Some(_) => {
+                return (
+                    Self::default(),
+                    vec!["initializationOptions must be a JSON object; using defaults".to_string()],
+                )
+            }
+        };
+
+        let mut warnings = Vec::new();
+        let mut valid = Map::new();
+        for (key, value) in options {
+            let single = Value::Object(Map::from_iter([(key.clone(), value.clone())]));
+            match serde_json::from_value::<Self>(single) {
+                Ok(_) => {
+                    valid.insert(key, value);
+                }
+                Err(err) => warnings.push(format!("ignoring option `{key}`: {err}")),
+            }
+        }
+
+        let config = serde_json::from_value(Value::Object(valid)).unwrap_or_default();
+        (config, warnings)
+    }
+
+    /// Whether requests on the document at `path` should be answered under `restrictToExtensions`.
+    pub fn allows_document(&self, path: &str) -> bool {
+        self.restrict_to_extensions.is_empty()
+            || extension_listed(&self.restrict_to_extensions, path)
+    }
+
+    /// Whether `path` lies in one of `allowedResolveRoots` (always, when none are
+    /// set). Existing paths are canonicalized so `..` and symlinks cannot leave a root.
+    pub fn allows_target(&self, path: &Path, fs: &dyn FileSystem) -> bool {
+        if self.allowed_resolve_roots.is_empty() {
+            return true;
+        }
+        let target = normalize_path(path, fs);
+        self.allowed_resolve_roots
+            .iter()
+            .any(|root| target.starts_with(normalize_path(root, fs)))
+    }
+
+    /// Whether locator columns into the target at `path` count from 0 under `zeroBasedColumnsFor`.
+    pub fn zero_based_columns(&self, path: &str) -> bool {
+        extension_listed(&self.zero_based_columns_for, path)
+    }
+
+    /// Splits `path` into the root of its longest matching `rootAliases` prefix
+    /// and the remainder.
+    pub fn match_root_alias<'a>(&'a self, path: &'a str) -> Option<(&'a Path, &'a str)> {
+        self.root_aliases
+            .iter()
+            .filter_map(|(prefix, root)| {
+                let prefix = prefix.trim_end_matches('/');
+                let rest = path.strip_prefix(prefix)?;
+                let rest = match rest.strip_prefix('/') {
+                    Some(rest) => rest,
+                    None if rest.is_empty() => rest,
+                    None => return None,
+                };
+                Some((prefix.len(), root.as_path(), rest))
+            })
+            .max_by_key(|&(prefix_len, _, _)| prefix_len)
+            .map(|(_, root, rest)| (root, rest))
+    }
+
+    /// Makes relative path options (`sourceRoots`, `moduleIndex`, `baseDir`, ...)
+    /// absolute against the first workspace root.
+    pub fn anchor_source_roots(&mut self, workspace_roots: &[PathBuf]) {
+        let Some(workspace_root) = workspace_roots.first() else {
+            return;
+        };
+        for root in self
+            .source_roots
+            .iter_mut()
+            .chain(&mut self.module_index)
+            .chain(&mut self.base_dir)
+            .chain(&mut self.allowed_resolve_roots)
+            .chain(&mut self.compile_commands)
+        {
+            if root.is_relative() {
+                *root = workspace_root.join(&*root);
+            }
+        }
+    }
+
+    /// Takes `baseDir` from `env_value` when the option is unset.
+    pub fn apply_base_dir_env(&mut self, env_value: Option<PathBuf>) {
+        if self.base_dir.is_none() {
+            self.base_dir = env_value.filter(|dir| !dir.as_os_str().is_empty());
+        }
+    }
+
+    /// Drops a `baseDir` that is not an existing directory, returning the warning to show.
+    pub fn validate_base_dir(&mut self, fs: &dyn FileSystem) -> Option<String> {
+        let dir = self.base_dir.take_if(|dir| !fs.is_dir(dir))?;
+        Some(format!(
+            "ignoring baseDir `{}`: not an existing directory",
+            dir.display()
+        ))
+    }
+}
+
+/// Whether the extension of `path` is in `extensions`, ignoring case and a leading dot.
+fn extension_listed(extensions: &[String], path: &str) -> bool {
+    let Some(extension) = Path::new(path).extension().and_then(|ext| ext.to_str()) else {
+        return false;
+    };
+    extensions.iter().any(|listed| {
+        listed
+            .trim_start_matches('.')
+            .eq_ignore_ascii_case(extension)
+    })
+}
+
+/// `path` canonicalized when it exists, otherwise with `.` and `..` folded lexically.
+pub fn normalize_path(path: &Path, fs: &dyn FileSystem) -> PathBuf {
+    fs.canonicalize(path).unwrap_or_else(|_| fold_dots(path))
+}
@@ -0,0 +1,242 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// What resolution needs to know about a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub len: u64,
+    pub modified: SystemTime,
+    pub is_file: bool,
+}
+
+/// One entry of a directory listing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub is_file: bool,
+}
+
+/// File access of the resolution layer: locator resolution, manifest loading and
+/// reading targets all go through it, so tests can run against an in-memory tree.
+pub trait FileSystem: Send + Sync {
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata>;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Entries of the directory `path`, in no particular order.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>>;
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Writes `contents` to `path`, creating missing parent directories.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|metadata| metadata.is_file)
+    }
+
+    /// Whether `path` is a file or directory.
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|metadata| !metadata.is_file)
+    }
+}
+
+/// `path` with `.` and `..` components folded lexically.
+pub fn fold_dots(path: &Path) -> PathBuf {
+    let mut folded = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                folded.pop();
+            }
+            other => folded.push(other),
+        }
+    }
+    folded
+}
+
+/// The real disk.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(FileMetadata {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            is_file: metadata.is_file(),
+        })
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(path)?.flatten() {
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            entries.push(DirEntry {
+                path: entry.path(),
+                is_dir: file_type.is_dir(),
+                is_file: file_type.is_file(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        std::fs::canonicalize(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, contents)
+    }
+}
+
+#[cfg(test)]
+pub use memory::MemoryFileSystem;
+
+#[cfg(test)]
+mod memory {
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+    use std::time::{Duration, SystemTime};
+
+    use super::{fold_dots, DirEntry, FileMetadata, FileSystem};
+
+    /// Ticks of the fake clock stamping [`MemoryFileSystem`] writes. Shared by
+    /// every instance so mtime-keyed caches never confuse two writes.
+    static CLOCK: AtomicU64 = AtomicU64::new(1);
+
+    /// An in-memory tree of files. Directories exist implicitly above files, and
+    /// every write gets a later mtime than the one before it.
+    #[derive(Debug, Default)]
+    pub struct MemoryFileSystem {
+        files: Mutex<BTreeMap<PathBuf, (Vec<u8>, SystemTime)>>,
+    }
+
+    impl MemoryFileSystem {
+        pub fn write(&self, path: impl Into<PathBuf>, contents: impl Into<Vec<u8>>) {
+            let tick = CLOCK.fetch_add(1, Ordering::Relaxed);
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(tick);
+            self.files
+                .lock()
+                .unwrap()
+                .insert(path.into(), (contents.into(), modified));
+        }
+
+        pub fn remove(&self, path: &Path) {
+            self.files.lock().unwrap().remove(path);
+        }
+    }
+
+    impl FileSystem for MemoryFileSystem {
+        fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+            let files = self.files.lock().unwrap();
+            if let Some((contents, modified)) = files.get(path) {
+                return Ok(FileMetadata {
+                    len: contents.len() as u64,
+                    modified: *modified,
+                    is_file: true,
+                });
+            }
+            if files.keys().any(|file| file.starts_with(path)) {
+                return Ok(FileMetadata {
+                    len: 0,
+                    modified: SystemTime::UNIX_EPOCH,
+                    is_file: false,
+                });
+            }
+            Err(io::ErrorKind::NotFound.into())
+        }
+
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            let contents = self
+                .files
+                .lock()
+                .unwrap()
+                .get(path)
+                .map(|(contents, _)| contents.clone());
+            match contents {
+                Some(contents) => Ok(contents),
+                None if self.exists(path) => Err(io::Error::other("is a directory")),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+
+        fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+            let files = self.files.lock().unwrap();
+            // Immediate children of `path`, and whether each has files below it.
+            let mut children: BTreeMap<PathBuf, bool> = BTreeMap::new();
+            for file in files.keys() {
+                let Ok(rest) = file.strip_prefix(path) else {
+                    continue;
+                };
+                let mut components = rest.components();
+                let Some(first) = components.next() else {
+                    continue;
+                };
+                *children.entry(path.join(first)).or_default() |= components.next().is_some();
+            }
+            if children.is_empty() {
+                return Err(match files.contains_key(path) {
+                    true => io::Error::other("not a directory"),
+                    false => io::ErrorKind::NotFound.into(),
+                });
+            }
+            Ok(children
+                .into_iter()
+                .map(|(path, is_dir)| DirEntry {
+                    path,
+                    is_dir,
+                    is_file: !is_dir,
+                })
+                .collect())
+        }
+
+        /// There are no symlinks, so an existing path only has its dots folded.
+        fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+            let folded = fold_dots(path);
+            if self.exists(&folded) {
+                Ok(folded)
+            } else {
+                Err(io::ErrorKind::NotFound.into())
+            }
+        }
+
+        /// Fails like a real write when a parent of `path` is a file.
+        fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+            if path.ancestors().skip(1).any(|parent| self.is_file(parent)) {
+                return Err(io::Error::other("not a directory"));
+            }
+            MemoryFileSystem::write(self, path, contents);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, Cursor, Read};
+use std::path::{Component, Path, PathBuf};
+
+use tower_lsp::lsp_types::Url;
+
+use crate::fs::FileSystem;
+use crate::mtimecache::MtimeCache;
+
+/// Prefix of locator paths into a packaged source jar, as in
+/// `jar:file:/deps/sources.jar!/pkg/Foo.scala`.
+const JAR_PREFIX: &str = "jar:";
+
+/// Directory under the system temp directory holding extracted entries.
+const EXTRACT_DIR: &str = "firrtl-source-locator-jars";
+
+/// Extracted copies keyed by jar and entry, valid while the jar is unchanged.
+static CACHE: MtimeCache<(PathBuf, String), PathBuf> = MtimeCache::new();
+
+/// Jar file and entry name of a `jar:file:<jar>!/<entry>` locator path.
+pub fn split(path: &str) -> Option<(PathBuf, &str)> {
+    let (jar, entry) = path.strip_prefix(JAR_PREFIX)?.split_once("!/")?;
+    let jar = Url::parse(jar).ok()?.to_file_path().ok()?;
+    (!entry.is_empty()).then_some((jar, entry))
+}
+
+/// The `jar:` URL a locator into `entry` of `jar` resolves to. It names the
+/// entry without touching the jar; reading it goes through [`read_entry`].
+pub fn entry_url(jar: &Path, entry: &str) -> Option<Url> {
+    Url::parse(&format!(
+        "{JAR_PREFIX}{}!/{entry}",
+        Url::from_file_path(jar).ok()?
+    ))
+    .ok()
+}
+
+/// Jar file and entry name of a URL made by [`entry_url`].
+pub fn split_url(url: &Url) -> Option<(PathBuf, String)> {
+    if url.scheme() != "jar" {
+        return None;
+    }
+    let (jar, entry) = split(url.as_str())?;
+    Some((jar, entry.to_string()))
+}
+
+/// Contents of `entry` in the zip archive `jar`, refused when the entry is larger
+/// than `max_bytes` once decompressed.
+pub fn read_entry(
+    fs: &dyn FileSystem,
+    jar: &Path,
+    entry: &str,
+    max_bytes: u64,
+) -> io::Result<Vec<u8>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(fs.read(jar)?)).map_err(io::Error::other)?;
+    let file = archive.by_name(entry).map_err(|error| match error {
+        zip::result::ZipError::FileNotFound => io::Error::other(format!("no entry `{entry}`")),
+        other => io::Error::other(other),
+    })?;
+    let mut contents = Vec::new();
+    // Capped while reading rather than by the declared size, which the archive may misstate.
+    file.take(max_bytes.saturating_add(1))
+        .read_to_end(&mut contents)?;
+    if contents.len() as u64 > max_bytes {
+        return Err(io::Error::other(format!(
+            "larger than maxDocumentBytes ({max_bytes})"
+        )));
+    }
+    Ok(contents)
+}
+
+/// Whether `jar` is a readable archive holding `entry`.
+pub fn has_entry(fs: &dyn FileSystem, jar: &Path, entry: &str) -> bool {
+    let Ok(bytes) = fs.read(jar) else {
+        return false;
+    };
+    zip::ZipArchive::new(Cursor::new(bytes)).is_ok_and(|mut archive| archive.by_name(entry).is_ok())
+}
+
+/// A copy of `entry` extracted from `jar` into the temp directory, so editors
+/// can open it like any file. Extracted again once the jar's mtime changes or
+/// the copy was deleted.
+pub fn extracted(
+    fs: &dyn FileSystem,
+    jar: &Path,
+    entry: &str,
+    max_bytes: u64,
+) -> io::Result<PathBuf> {
+    let modified = fs.metadata(jar)?.modified;
+    let key = (jar.to_path_buf(), entry.to_string());
+    if let Some(copy) = CACHE.get(&key, modified).filter(|copy| fs.is_file(copy)) {
+        return Ok(copy.to_path_buf());
+    }
+
+    let relative = Path::new(entry);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("entry `{entry}` leaves the jar"),
+        ));
+    }
+    let mut hasher = DefaultHasher::new();
+    jar.hash(&mut hasher);
+    let copy = std::env::temp_dir()
+        .join(EXTRACT_DIR)
+        .join(format!("{:016x}", hasher.finish()))
+        .join(relative);
+
+    let contents = read_entry(fs, jar, entry, max_bytes)?;
+    fs.write(&copy, &contents)?;
+    Ok(CACHE.insert(key, modified, copy).to_path_buf())
+}
@@ -0,0 +1,33 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+use tower_lsp::lsp_types::Location;
+
+/// One resolved Go to Definition: the caret it was requested at and where it led.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Jump {
+    pub source: Location,
+    pub targets: Vec<Location>,
+}
+
+/// The most recent jumps, dropping the oldest once full.
+#[derive(Debug, Default)]
+pub struct JumpHistory {
+    /// Jumps from oldest to newest.
+    jumps: VecDeque<Jump>,
+}
+
+impl JumpHistory {
+    /// Stores `jump`, keeping at most `capacity` jumps. A zero capacity keeps nothing.
+    pub fn record(&mut self, jump: Jump, capacity: usize) {
+        self.jumps.push_back(jump);
+        while self.jumps.len() > capacity {
+            self.jumps.pop_front();
+        }
+    }
+
+    /// Stored jumps, most recent first.
+    pub fn recent(&self) -> Vec<Jump> {
+        self.jumps.iter().rev().cloned().collect()
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::{HashMap, VecDeque};
+
+use tower_lsp::lsp_types::Url;
+
+type Key = (Url, u32);
+
+/// Target source lines read from disk, keyed by resolved URL and 1-based line,
+/// evicting the least recently used entry once full.
+#[derive(Debug, Default)]
+pub struct SourceLineCache {
+    lines: HashMap<Key, String>,
+    /// Keys from least to most recently used.
+    order: VecDeque<Key>,
+}
+
+impl SourceLineCache {
+    pub fn get(&mut self, uri: &Url, line: u32) -> Option<String> {
+        let key = (uri.clone(), line);
+        let text = self.lines.get(&key)?.clone();
+        self.touch(key);
+        Some(text)
+    }
+
+    /// Stores `text`, keeping at most `capacity` lines. A zero capacity caches nothing.
+    pub fn insert(&mut self, uri: &Url, line: u32, text: String, capacity: usize) {
+        if capacity == 0 {
+            self.clear();
+            return;
+        }
+        let key = (uri.clone(), line);
+        if self.lines.insert(key.clone(), text).is_some() {
+            self.touch(key);
+        } else {
+            self.order.push_back(key);
+        }
+        while self.order.len() > capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.lines.remove(&oldest);
+            }
+        }
+    }
+
+    /// Drops every cached line whose URL satisfies `matches`.
+    pub fn evict_matching(&mut self, matches: impl Fn(&Url) -> bool) {
+        self.lines.retain(|(cached, _), _| !matches(cached));
+        self.order.retain(|(cached, _)| !matches(cached));
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.order.clear();
+    }
+
+    fn touch(&mut self, key: Key) {
+        if let Some(index) = self.order.iter().position(|cached| *cached == key) {
+            self.order.remove(index);
+        }
+        self.order.push_back(key);
+    }
+}
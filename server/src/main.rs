@@ -1,12 +1,34 @@
-use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+mod compiledb;
+mod config;
+mod fs;
+mod jarsource;
+mod jumphistory;
+mod linecache;
+mod mtimecache;
+mod sourcemap;
 
-use tokio::sync::RwLock;
-use tower_lsp::jsonrpc::Result;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::{Mutex, RwLock};
+use tower_lsp::jsonrpc::{Error, ErrorCode, Result};
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer, LspService, Server};
 
-#[derive(Clone, Debug)]
+use config::{
+    AmbiguousResolution, Config, DedupScope, GotoResponseKind, LocationSyntax, BASE_DIR_ENV,
+};
+use fs::{FileSystem, RealFileSystem};
+use jumphistory::{Jump, JumpHistory};
+use linecache::SourceLineCache;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 struct AnnotationSpan {
     full_start: usize,
     full_end: usize,
@@ -19,690 +41,8875 @@ struct Locator {
     path: String,
     line: u32,
     columns: Vec<u32>,
+    /// Explicit `(line, column)` pairs from the `path:{10:3, 12:5}` form. Empty for
+    /// the single-line form; when present, `line`/`columns` mirror the first line.
+    pairs: Vec<(u32, u32)>,
+    /// Trailing kind tag, e.g. `assert` from `A.scala:10:3 <assert>`.
+    tag: Option<String>,
+}
+
+impl Locator {
+    /// Target columns grouped by line, in order of first appearance.
+    fn line_groups(&self) -> Vec<(u32, Vec<u32>)> {
+        if self.pairs.is_empty() {
+            return vec![(self.line, self.columns.clone())];
+        }
+
+        let mut groups: Vec<(u32, Vec<u32>)> = Vec::new();
+        for &(line, column) in &self.pairs {
+            match groups.iter_mut().find(|(existing, _)| *existing == line) {
+                Some((_, columns)) => columns.push(column),
+                None => groups.push((line, vec![column])),
+            }
+        }
+        groups
+    }
 }
 
+const EXPORT_ANNOTATIONS_COMMAND: &str = "firrtl.exportAnnotations";
+const REVEAL_SIDE_BY_SIDE_COMMAND: &str = "firrtl.revealSideBySide";
+const COPY_SOURCE_LINE_COMMAND: &str = "firrtl.copySourceLine";
+const GOTO_IN_NEW_TAB_COMMAND: &str = "firrtl.gotoInNewTab";
+const LIST_SOURCE_FILES_COMMAND: &str = "firrtl.listSourceFiles";
+const CANONICALIZE_ANNOTATION_COMMAND: &str = "firrtl.canonicalizeAnnotation";
+const STATISTICS_COMMAND: &str = "firrtl.statistics";
+const OPEN_SOURCE_FILE_COMMAND: &str = "firrtl.openSourceFile";
+const VALIDATE_WORKSPACE_COMMAND: &str = "firrtl.validateWorkspace";
+const COPY_PERMALINK_COMMAND: &str = "firrtl.copyPermalink";
+const SUMMARIZE_RANGE_COMMAND: &str = "firrtl.summarizeRange";
+const RECENT_JUMPS_COMMAND: &str = "firrtl.recentJumps";
+
+/// How many files `firrtl.validateWorkspace` reads and checks at once.
+const VALIDATE_WORKSPACE_CONCURRENCY: usize = 8;
+
+const DIAGNOSTIC_SOURCE: &str = "firrtl-source-locator";
+
+/// Directories never worth descending into when scanning source roots.
+const DEFAULT_EXCLUDED_DIRS: &[&str] = &[".git", ".hg", ".svn", "target", "node_modules"];
+
+/// Extensions of generated files that may carry `@[...]` locators.
+const ANNOTATED_FILE_EXTENSIONS: &[&str] = &["fir", "firrtl", "v", "sv", "svh"];
+
 #[derive(Clone, Debug)]
 struct ParsedToken {
     byte_start: usize,
     byte_end: usize,
     locator: Locator,
     range: Range,
+    /// Byte span of the path portion; empty for inherited-path tokens.
+    path_span: ByteSpan,
+    /// Byte span of the line number; `None` for the `{line:col}` pair form.
+    line_span: Option<ByteSpan>,
+    /// Byte span and value of each column number of the single-line forms.
+    column_spans: Vec<(ByteSpan, u32)>,
 }
 
-struct Backend {
-    client: Client,
-    documents: RwLock<HashMap<Url, String>>,
+type ByteSpan = (usize, usize);
+
+impl ParsedToken {
+    /// The token narrowed to the number under `offset`: that column alone on a
+    /// column, or `first_column`, the line's first column, on the line number.
+    fn narrowed_to(&self, offset: usize, first_column: u32) -> Option<ParsedToken> {
+        let contains = |(start, end): ByteSpan| offset >= start && offset < end;
+        let column = if self.line_span.is_some_and(contains) {
+            first_column
+        } else {
+            self.column_spans
+                .iter()
+                .find(|(span, _)| contains(*span))
+                .map(|(_, column)| *column)?
+        };
+        let mut narrowed = self.clone();
+        narrowed.locator.columns = vec![column];
+        Some(narrowed)
+    }
 }
 
-impl Backend {
-    fn new(client: Client) -> Self {
-        Self {
-            client,
-            documents: RwLock::new(HashMap::new()),
+/// One exported locator: where the annotation lives and what it points at.
+#[derive(Clone, Debug, PartialEq)]
+struct AnnotationRecord {
+    source_uri: String,
+    annotation_line: u32,
+    path: String,
+    line: u32,
+    columns: Vec<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl ExportFormat {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            _ => None,
         }
     }
+}
 
-    async fn read_document(&self, uri: &Url) -> Option<String> {
-        if let Some(text) = self.documents.read().await.get(uri).cloned() {
-            return Some(text);
-        }
+/// `sourceRevision` blobs already read by one request, keyed by target path and
+/// revision; `None` records that git had nothing for the key.
+type GitBlobs = Mutex<HashMap<(PathBuf, String), Option<Arc<str>>>>;
+
+/// Lets a request notice that a newer request of the same kind superseded it,
+/// e.g. a hover for a caret position the user has already moved away from.
+#[derive(Clone, Copy)]
+struct RequestToken<'a> {
+    latest: Option<&'a AtomicU64>,
+    generation: u64,
+    /// Where the request keeps the blobs it read, so many tokens into one file run git once.
+    git_blobs: Option<&'a GitBlobs>,
+}
 
-        let path = uri.to_file_path().ok()?;
-        std::fs::read_to_string(path).ok()
+impl<'a> RequestToken<'a> {
+    /// A token for work nothing supersedes, such as an explicit command.
+    const DETACHED: RequestToken<'static> = RequestToken {
+        latest: None,
+        generation: 0,
+        git_blobs: None,
+    };
+
+    /// Starts a new request, superseding every earlier token from `latest`.
+    fn begin(latest: &'a AtomicU64) -> Self {
+        let generation = latest.fetch_add(1, Ordering::SeqCst) + 1;
+        Self {
+            latest: Some(latest),
+            generation,
+            git_blobs: None,
+        }
     }
 
-    fn resolve_target_url(&self, path: &str, source_uri: &Url) -> Option<Url> {
-        let candidate = PathBuf::from(path);
-        let resolved = if candidate.is_absolute() {
-            candidate
-        } else {
-            let source_path = source_uri.to_file_path().ok()?;
-            source_path.parent()?.join(candidate)
-        };
-        Url::from_file_path(resolved).ok()
+    /// This token, keeping the request's `sourceRevision` blobs in `git_blobs`.
+    fn with_git_blobs(self, git_blobs: &'a GitBlobs) -> Self {
+        Self {
+            git_blobs: Some(git_blobs),
+            ..self
+        }
     }
 
-    async fn read_locator_line(&self, locator: &Locator, source_uri: &Url) -> Option<String> {
-        let target_uri = self.resolve_target_url(&locator.path, source_uri)?;
-        let text = self.read_document(&target_uri).await?;
-        line_text_at(&text, locator.line).map(ToString::to_string)
+    fn is_cancelled(&self) -> bool {
+        self.latest
+            .is_some_and(|latest| latest.load(Ordering::SeqCst) != self.generation)
     }
+}
 
-    fn collect_location_links<'a>(
-        &self,
-        tokens: impl IntoIterator<Item = &'a ParsedToken>,
-        source_uri: &Url,
-    ) -> Vec<LocationLink> {
-        let mut links = Vec::new();
-        let mut seen = HashSet::new();
+/// `$/progress` carrying a batch of `textDocument/references` matches for a
+/// client-supplied `partialResultToken`.
+enum PartialReferences {}
 
-        for token in tokens {
-            if token.locator.line == 0 {
-                continue;
-            }
+#[derive(Deserialize, Serialize)]
+struct PartialReferencesParams {
+    token: ProgressToken,
+    value: Vec<Location>,
+}
 
-            let Some(url) = self.resolve_target_url(&token.locator.path, source_uri) else {
-                continue;
-            };
+impl notification::Notification for PartialReferences {
+    type Params = PartialReferencesParams;
+    const METHOD: &'static str = "$/progress";
+}
 
-            let line = token.locator.line - 1;
-            for &column in &token.locator.columns {
-                if column == 0 {
-                    continue;
-                }
+/// Receives reference matches as each document is scanned, before the request completes.
+trait PartialResultSink {
+    async fn send_partial(&self, token: &ProgressToken, locations: Vec<Location>);
+}
 
-                let col = column - 1;
-                let dedup_key = format!("{}:{line}:{col}", url);
-                if !seen.insert(dedup_key) {
-                    continue;
-                }
+impl PartialResultSink for Client {
+    async fn send_partial(&self, token: &ProgressToken, locations: Vec<Location>) {
+        self.send_notification::<PartialReferences>(PartialReferencesParams {
+            token: token.clone(),
+            value: locations,
+        })
+        .await;
+    }
+}
 
-                let target_range = Range::new(
-                    Position::new(line, col),
-                    Position::new(line, col.saturating_add(1)),
-                );
-                links.push(LocationLink {
-                    origin_selection_range: None,
-                    target_uri: url.clone(),
-                    target_range,
-                    target_selection_range: target_range,
-                });
-            }
-        }
+struct Backend {
+    client: Client,
+    documents: RwLock<HashMap<Url, String>>,
+    /// Annotation spans of each open document, kept in sync with `documents`.
+    annotation_index: RwLock<HashMap<Url, Vec<AnnotationSpan>>>,
+    /// LSP version of each open document, updated under the `documents` write
+    /// lock so a reader always sees a text and version that belong together.
+    document_versions: RwLock<HashMap<Url, i32>>,
+    config: RwLock<Config>,
+    workspace_roots: RwLock<Vec<PathBuf>>,
+    hover_generation: AtomicU64,
+    definition_generation: AtomicU64,
+    /// Target lines read from disk, dropped when the client reports the file changed.
+    source_lines: Mutex<SourceLineCache>,
+    /// Open documents whose last published diagnostics were empty.
+    clean_documents: RwLock<HashSet<Url>>,
+    /// Where targets and manifests are looked up and read.
+    fs: Arc<dyn FileSystem>,
+    /// Targets whose last read from disk was not valid UTF-8 and was decoded lossily.
+    lossy_targets: RwLock<HashSet<Url>>,
+    /// Go to Definition requests that returned links, for `firrtl.recentJumps`.
+    recent_jumps: Mutex<JumpHistory>,
+}
 
-        links
+impl Backend {
+    fn new(client: Client) -> Self {
+        Self::with_file_system(client, Arc::new(RealFileSystem))
     }
-}
 
-#[tower_lsp::async_trait]
-impl LanguageServer for Backend {
-    async fn initialize(&self, _: InitializeParams) -> Result<InitializeResult> {
-        Ok(InitializeResult {
-            capabilities: ServerCapabilities {
-                text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
-                )),
-                definition_provider: Some(OneOf::Left(true)),
-                hover_provider: Some(HoverProviderCapability::Simple(true)),
-                ..ServerCapabilities::default()
-            },
-            server_info: Some(ServerInfo {
-                name: "firrtl-source-locator".to_string(),
-                version: Some(env!("CARGO_PKG_VERSION").to_string()),
-            }),
-            ..InitializeResult::default()
-        })
+    fn with_file_system(client: Client, fs: Arc<dyn FileSystem>) -> Self {
+        Self {
+            client,
+            documents: RwLock::new(HashMap::new()),
+            annotation_index: RwLock::new(HashMap::new()),
+            document_versions: RwLock::new(HashMap::new()),
+            config: RwLock::new(Config::default()),
+            workspace_roots: RwLock::new(Vec::new()),
+            hover_generation: AtomicU64::new(0),
+            definition_generation: AtomicU64::new(0),
+            source_lines: Mutex::new(SourceLineCache::default()),
+            clean_documents: RwLock::new(HashSet::new()),
+            fs,
+            lossy_targets: RwLock::new(HashSet::new()),
+            recent_jumps: Mutex::new(JumpHistory::default()),
+        }
     }
 
-    async fn initialized(&self, _: InitializedParams) {
-        let _ = self
-            .client
-            .log_message(
-                MessageType::INFO,
-                "firrtl-source-locator ready: Go to Definition for @[...] is enabled",
+    async fn read_document(&self, uri: &Url) -> Option<String> {
+        self.read_target(uri).await.ok()
+    }
+
+    /// Text of `uri` from the open documents or disk, with the reason it could not be read.
+    /// Files that are not valid UTF-8 are decoded lossily and noted in `lossy_targets`.
+    async fn read_target(&self, uri: &Url) -> std::result::Result<String, ResolveError> {
+        if let Some(text) = self.documents.read().await.get(uri).cloned() {
+            return Ok(text);
+        }
+        if let Some((jar, entry)) = jarsource::split_url(uri) {
+            let (timeout, max_bytes) = {
+                let config = self.config.read().await;
+                if !config.allows_target(&jar, self.fs.as_ref()) {
+                    return Err(ResolveError::OutsideAllowedRoots(jar));
+                }
+                (
+                    Duration::from_millis(config.read_timeout_ms),
+                    config.max_document_bytes,
+                )
+            };
+            let (text, lossy) =
+                read_jar_entry_with_timeout(&self.fs, &jar, &entry, timeout, max_bytes).await?;
+            self.note_lossy(uri, lossy).await;
+            return Ok(text);
+        }
+
+        let path = uri
+            .to_file_path()
+            .map_err(|()| ResolveError::NotAbsolute(PathBuf::from(uri.path())))?;
+        let (timeout, max_bytes, gzip) = {
+            let config = self.config.read().await;
+            if !config.allows_target(&path, self.fs.as_ref()) {
+                return Err(ResolveError::OutsideAllowedRoots(path));
+            }
+            (
+                Duration::from_millis(config.read_timeout_ms),
+                config.max_document_bytes,
+                config.support_gzip_sources,
             )
-            .await;
+        };
+        let compressed = if gzip {
+            gzip_source(&path, self.fs.as_ref())
+        } else {
+            None
+        };
+        if let Some(compressed) = compressed {
+            return read_gzip_with_timeout(&self.fs, &compressed, timeout, max_bytes).await;
+        }
+        let metadata =
+            with_read_timeout(&self.fs, &path, timeout, |fs, path| fs.metadata(path)).await?;
+        if metadata.len > max_bytes {
+            return Err(ResolveError::ReadError(
+                path,
+                format!("larger than maxDocumentBytes ({max_bytes})"),
+            ));
+        }
+        let (text, lossy) = read_file_with_timeout(&self.fs, &path, timeout).await?;
+        self.note_lossy(uri, lossy).await;
+        Ok(text)
     }
 
-    async fn shutdown(&self) -> Result<()> {
-        Ok(())
+    /// Records in `lossy_targets` whether the last read of `uri` was decoded lossily.
+    async fn note_lossy(&self, uri: &Url, lossy: bool) {
+        let mut lossy_targets = self.lossy_targets.write().await;
+        if lossy {
+            lossy_targets.insert(uri.clone());
+        } else {
+            lossy_targets.remove(uri);
+        }
     }
 
-    async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        self.documents
-            .write()
-            .await
-            .insert(params.text_document.uri, params.text_document.text);
+    /// Logs `error` to the client without making the request wait on delivery.
+    fn log_resolve_error(&self, error: &ResolveError) {
+        self.log_detached(MessageType::LOG, format!("firrtl-source-locator: {error}"));
     }
 
-    async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        if let Some(change) = params.content_changes.into_iter().last() {
-            self.documents
-                .write()
-                .await
-                .insert(params.text_document.uri, change.text);
+    /// Sends a `window/logMessage` from a background task.
+    fn log_detached(&self, kind: MessageType, message: String) {
+        let client = self.client.clone();
+        tokio::spawn(async move { client.log_message(kind, message).await });
+    }
+
+    /// Annotation spans of `text`, from the index when `uri` is an open document.
+    async fn document_annotations(
+        &self,
+        uri: &Url,
+        text: &str,
+        config: &Config,
+    ) -> Vec<AnnotationSpan> {
+        match self.annotation_index.read().await.get(uri) {
+            Some(annotations) => annotations.clone(),
+            None => find_annotations(text, config),
         }
     }
 
-    async fn did_close(&self, params: DidCloseTextDocumentParams) {
-        self.documents
-            .write()
+    /// Whether `uri` is an open document whose index holds no annotations, so
+    /// requests on it can return before scanning the text.
+    async fn known_annotation_free(&self, uri: &Url) -> bool {
+        self.annotation_index
+            .read()
             .await
-            .remove(&params.text_document.uri);
+            .get(uri)
+            .is_some_and(Vec::is_empty)
     }
 
-    async fn goto_definition(
+    async fn annotation_at_offset(
         &self,
-        params: GotoDefinitionParams,
-    ) -> Result<Option<GotoDefinitionResponse>> {
-        let text_document_position = params.text_document_position_params;
-        let uri = text_document_position.text_document.uri;
-        let position = text_document_position.position;
-
-        let Some(text) = self.read_document(&uri).await else {
-            return Ok(None);
-        };
+        uri: &Url,
+        text: &str,
+        offset: usize,
+        config: &Config,
+    ) -> Option<AnnotationSpan> {
+        let annotations = self.document_annotations(uri, text, config).await;
+        annotation_containing(text, &annotations, offset).cloned()
+    }
 
-        let line_starts = compute_line_starts(&text);
-        let Some(offset) = position_to_offset(position, &text, &line_starts) else {
+    /// Reads one target line. `Ok(None)` means `request` has been superseded or
+    /// the target has no such line.
+    async fn read_locator_line(
+        &self,
+        path: &str,
+        line: u32,
+        source_uri: &Url,
+        request: RequestToken<'_>,
+    ) -> std::result::Result<Option<String>, ResolveError> {
+        if request.is_cancelled() {
             return Ok(None);
+        }
+        let (target_uri, revision, timeout) = {
+            let config = self.config.read().await;
+            (
+                resolve_target_url(path, source_uri, &config, self.fs.as_ref())?,
+                config.source_revision.clone(),
+                Duration::from_millis(config.read_timeout_ms),
+            )
         };
+        if let Some(revision) = revision {
+            let committed = match target_uri.to_file_path() {
+                Ok(target_path) => {
+                    git_revision_text(&target_path, &revision, timeout, request).await
+                }
+                Err(()) => None,
+            };
+            if let Some(committed) = committed {
+                return Ok(line_text_at(&committed, line).map(ToString::to_string));
+            }
+        }
+        if let Some(text) = self.documents.read().await.get(&target_uri) {
+            return Ok(line_text_at(text, line).map(ToString::to_string));
+        }
+        if let Some(cached) = self.source_lines.lock().await.get(&target_uri, line) {
+            return Ok(Some(cached));
+        }
 
-        let Some(annotation) = find_annotation_at_offset(&text, offset) else {
-            return Ok(None);
-        };
+        let text = self.read_target(&target_uri).await?;
+        let source_line = line_text_at(&text, line).map(ToString::to_string);
+        if let Some(source_line) = &source_line {
+            let capacity = self.config.read().await.source_line_cache_size;
+            self.source_lines
+                .lock()
+                .await
+                .insert(&target_uri, line, source_line.clone(), capacity);
+        }
+        Ok(source_line)
+    }
 
-        let tokens = parse_tokens_from_annotation(&text, &annotation, &line_starts);
-        if tokens.is_empty() {
-            return Ok(None);
+    /// Like [`Self::read_locator_line`], logging why the line could not be read.
+    async fn read_locator_line_logged(
+        &self,
+        path: &str,
+        line: u32,
+        source_uri: &Url,
+        request: RequestToken<'_>,
+    ) -> Option<String> {
+        match self
+            .read_locator_line(path, line, source_uri, request)
+            .await
+        {
+            Ok(source_line) => source_line,
+            Err(error) => {
+                self.log_resolve_error(&error);
+                None
+            }
         }
+    }
 
-        let links = self.collect_location_links(tokens.iter(), &uri);
+    /// Renders one fenced block (source line + column indicator) per target line.
+    /// Blank target lines render a `<blank line>` placeholder and a note instead of a lone caret;
+    /// columns past the end of the line are marked at its last column, with a note.
+    async fn locator_source_blocks(
+        &self,
+        locator: &Locator,
+        source_uri: &Url,
+        request: RequestToken<'_>,
+    ) -> Vec<String> {
+        let language = self.target_language(locator, source_uri).await;
+        let (marker, max_width, zero_based) = {
+            let config = self.config.read().await;
+            (
+                config.indicator_style.marker(),
+                config.max_source_line_width,
+                zero_based_target(&locator.path, source_uri, &config, self.fs.as_ref()),
+            )
+        };
+        let mut blocks = Vec::new();
 
-        if links.is_empty() {
-            return Ok(None);
+        for (line, columns) in locator.line_groups() {
+            if request.is_cancelled() {
+                break;
+            }
+            let read = self
+                .read_locator_line_logged(&locator.path, line, source_uri, request)
+                .await;
+            let available = read.is_some();
+            let source_line = read.unwrap_or_else(|| "<source line unavailable>".to_string());
+            if source_line.trim().is_empty() {
+                blocks.push(format!(
+                    "```{language}\n<blank line>\n```\n_Line {line} of the target is blank._"
+                ));
+                continue;
+            }
+            // The indicator works on 1-based columns.
+            let columns: Vec<u32> = if zero_based {
+                columns.iter().map(|column| column + 1).collect()
+            } else {
+                columns
+            };
+            let (columns, past_end) = if available {
+                clamp_columns(&source_line, &columns)
+            } else {
+                (columns, Vec::new())
+            };
+            let line_width = source_line.chars().count();
+            let (source_line, columns) = match max_width {
+                Some(width) => truncate_source_line(&source_line, &columns, width),
+                None => (source_line, columns),
+            };
+            let column_line = build_column_indicator_line(&source_line, &columns, marker);
+            let mut block = format!("```{language}\n{source_line}\n{column_line}\n```");
+            for column in past_end {
+                block.push_str(&format!(
+                    "\n_Column {column} is past the end of line {line} ({line_width} columns); \
+                     marked at the last column._"
+                ));
+            }
+            blocks.push(block);
         }
 
-        Ok(Some(GotoDefinitionResponse::Link(links)))
+        if self.lossy_target(locator, source_uri).await {
+            if let Some(last) = blocks.last_mut() {
+                last.push_str(
+                    "\n_The target is not valid UTF-8; undecodable bytes are shown as `\u{FFFD}`._",
+                );
+            }
+        }
+        blocks
     }
 
-    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
-        let text_document_position = params.text_document_position_params;
-
-        let uri = text_document_position.text_document.uri;
-        let position = text_document_position.position;
-
-        let Some(text) = self.read_document(&uri).await else {
-            return Ok(None);
+    /// Whether the locator's target was decoded lossily when last read from disk.
+    async fn lossy_target(&self, locator: &Locator, source_uri: &Url) -> bool {
+        let target_uri = {
+            let config = self.config.read().await;
+            resolve_target_url(&locator.path, source_uri, &config, self.fs.as_ref())
         };
+        match target_uri {
+            Ok(target_uri) => self.lossy_targets.read().await.contains(&target_uri),
+            Err(_) => false,
+        }
+    }
 
-        let line_starts = compute_line_starts(&text);
-        let Some(offset) = position_to_offset(position, &text, &line_starts) else {
-            return Ok(None);
+    /// Fence language for the locator's target: from its extension, or from a
+    /// shebang or modeline in its first lines when the extension is unknown.
+    async fn target_language(&self, locator: &Locator, source_uri: &Url) -> &'static str {
+        let language = markdown_language_from_path(&locator.path);
+        if language != "text" {
+            return language;
+        }
+        let target_uri = {
+            let config = self.config.read().await;
+            resolve_target_url(&locator.path, source_uri, &config, self.fs.as_ref()).ok()
         };
-
-        let Some(annotation) = find_annotation_at_offset(&text, offset) else {
-            return Ok(None);
+        let Some(target_uri) = target_uri else {
+            return language;
         };
+        match self.read_document(&target_uri).await {
+            Some(text) => markdown_language_from_header(&text).unwrap_or(language),
+            None => language,
+        }
+    }
 
-        let tokens = parse_tokens_from_annotation(&text, &annotation, &line_starts);
-        let (summary_start, summary_end) =
-            summary_hover_byte_range(&text, &annotation, &line_starts);
-        if offset >= summary_start && offset < summary_end {
-            let mut blocks = Vec::new();
-
-            for token in &tokens {
-                let source_line = self
-                    .read_locator_line(&token.locator, &uri)
-                    .await
-                    .unwrap_or_else(|| "<source line unavailable>".to_string());
-                let language = markdown_language_from_path(&token.locator.path);
-                let column_line = build_column_indicator_line(&source_line, &token.locator.columns);
-                blocks.push(format!("```{language}\n{source_line}\n{column_line}\n```"));
-            }
+    /// For a locator into a FIRRTL file, the locators of the annotations on its
+    /// target lines with their source blocks. Only one level is followed, and
+    /// locators leading back to `source_uri` or the FIRRTL file itself are skipped.
+    async fn followed_firrtl_blocks(
+        &self,
+        locator: &Locator,
+        source_uri: &Url,
+        config: &Config,
+        request: RequestToken<'_>,
+    ) -> Vec<(Locator, Vec<String>)> {
+        if markdown_language_from_path(&locator.path) != "firrtl" {
+            return Vec::new();
+        }
+        let Ok(target_uri) =
+            resolve_target_url(&locator.path, source_uri, config, self.fs.as_ref())
+        else {
+            return Vec::new();
+        };
+        if target_uri == *source_uri {
+            return Vec::new();
+        }
+        let Some(text) = self.read_document(&target_uri).await else {
+            return Vec::new();
+        };
 
-            if blocks.is_empty() {
-                return Ok(None);
+        let line_starts = compute_line_starts(&text);
+        let annotations = find_annotations(&text, config);
+        let mut followed = Vec::new();
+        for (line, _) in locator.line_groups() {
+            let Some(&line_start) = line_starts.get((line as usize).wrapping_sub(1)) else {
+                continue;
+            };
+            let line_end = line_starts
+                .get(line as usize)
+                .copied()
+                .unwrap_or(text.len());
+            for annotation in annotations
+                .iter()
+                .filter(|annotation| (line_start..line_end).contains(&annotation.full_start))
+            {
+                for token in parse_tokens_from_annotation(&text, annotation, &line_starts, config) {
+                    let leads_back = resolve_target_url(
+                        &token.locator.path,
+                        &target_uri,
+                        config,
+                        self.fs.as_ref(),
+                    )
+                    .is_ok_and(|url| url == *source_uri || url == target_uri);
+                    if leads_back || request.is_cancelled() {
+                        continue;
+                    }
+                    let blocks = self
+                        .locator_source_blocks(&token.locator, &target_uri, request)
+                        .await;
+                    followed.push((token.locator, blocks));
+                }
             }
+        }
+        followed
+    }
 
-            let summary_range = Range::new(
-                offset_to_position(summary_start, &text, &line_starts),
-                offset_to_position(summary_end, &text, &line_starts),
+    /// Hover listing the source blocks of every token, shown over `range`.
+    async fn summary_hover(
+        &self,
+        tokens: &[ParsedToken],
+        source_uri: &Url,
+        range: Range,
+        request: RequestToken<'_>,
+    ) -> Option<Hover> {
+        let mut blocks = Vec::new();
+        for token in tokens {
+            blocks.extend(
+                self.locator_source_blocks(&token.locator, source_uri, request)
+                    .await,
             );
-
-            return Ok(Some(Hover {
-                contents: HoverContents::Markup(MarkupContent {
-                    kind: MarkupKind::Markdown,
-                    value: blocks.join("\n"),
-                }),
-                range: Some(summary_range),
-            }));
         }
 
-        let Some(token) = tokens
-            .iter()
-            .find(|token| offset >= token.byte_start && offset < token.byte_end)
-        else {
-            return Ok(None);
-        };
-
-        let source_line = self
-            .read_locator_line(&token.locator, &uri)
-            .await
-            .unwrap_or_else(|| "<source line unavailable>".to_string());
-        let column_line = build_column_indicator_line(&source_line, &token.locator.columns);
-        let language = markdown_language_from_path(&token.locator.path);
-        let value = format!(
-            "```{language}\n{source_line}\n{column_line}\n```\n{}",
-            format_locator(&token.locator)
-        );
+        if blocks.is_empty() || request.is_cancelled() {
+            return None;
+        }
 
-        Ok(Some(Hover {
+        let mut value = blocks.join("\n");
+        let config = self.config.read().await;
+        if config.explanatory_hover {
+            value = format!("_{}_\n\n{value}", explain_annotation(tokens, &config));
+        }
+        Some(Hover {
             contents: HoverContents::Markup(MarkupContent {
                 kind: MarkupKind::Markdown,
                 value,
             }),
-            range: Some(token.range),
-        }))
+            range: Some(range),
+        })
     }
-}
 
-fn compute_line_starts(text: &str) -> Vec<usize> {
-    let mut starts = vec![0];
-    for (idx, byte) in text.bytes().enumerate() {
-        if byte == b'\n' {
-            starts.push(idx + 1);
-        }
+    /// The definition enclosing the locator's first target line, like `def bar`.
+    async fn locator_enclosing_symbol(
+        &self,
+        locator: &Locator,
+        source_uri: &Url,
+    ) -> Option<String> {
+        let target_uri = {
+            let config = self.config.read().await;
+            resolve_target_url(&locator.path, source_uri, &config, self.fs.as_ref()).ok()?
+        };
+        let text = self.read_document(&target_uri).await?;
+        enclosing_symbol(&text, locator.line, &locator.path)
     }
-    starts
-}
 
-fn position_to_offset(position: Position, text: &str, line_starts: &[usize]) -> Option<usize> {
-    let line = position.line as usize;
-    if line >= line_starts.len() {
-        return None;
+    /// Diagnostics for `text`: unterminated `@[` openers, plus locator lines
+    /// beyond the resolved target's line count when `diagnoseLineOverflow` is on.
+    async fn compute_diagnostics(&self, uri: &Url, text: &str) -> Vec<Diagnostic> {
+        let config = self.config.read().await.clone();
+        if !config.allows_document(uri.path()) {
+            return Vec::new();
+        }
+
+        let annotations = self.document_annotations(uri, text, &config).await;
+        let line_starts = compute_line_starts(text);
+        let mut diagnostics: Vec<Diagnostic> =
+            find_unterminated_annotations(text, &annotations, &config)
+                .iter()
+                .map(|unterminated| {
+                    unterminated_diagnostic(text, &line_starts, unterminated, &config)
+                })
+                .collect();
+        for annotation in &annotations {
+            diagnostics.extend(
+                find_empty_tokens(text, annotation)
+                    .into_iter()
+                    .map(|span| empty_token_diagnostic(text, &line_starts, span)),
+            );
+            diagnostics.extend(
+                find_orphan_tokens(text, annotation, &line_starts, &config)
+                    .into_iter()
+                    .map(|span| orphan_token_diagnostic(text, &line_starts, span)),
+            );
+        }
+        if config.diagnose_line_overflow {
+            diagnostics.extend(
+                self.line_overflow_diagnostics(uri, text, &annotations, &config)
+                    .await,
+            );
+        }
+        diagnostics
     }
 
-    let line_start = line_starts[line];
-    let line_end = if line + 1 < line_starts.len() {
-        line_starts[line + 1]
-    } else {
-        text.len()
-    };
-    let line_text = &text[line_start..line_end];
+    async fn line_overflow_diagnostics(
+        &self,
+        uri: &Url,
+        text: &str,
+        annotations: &[AnnotationSpan],
+        config: &Config,
+    ) -> Vec<Diagnostic> {
+        let line_starts = compute_line_starts(text);
+        let mut line_counts: HashMap<Url, Option<u32>> = HashMap::new();
+        let mut diagnostics = Vec::new();
+        for annotation in annotations {
+            for token in parse_tokens_from_annotation(text, annotation, &line_starts, config) {
+                let Ok(target_uri) =
+                    resolve_target_url(&token.locator.path, uri, config, self.fs.as_ref())
+                else {
+                    continue;
+                };
+                let line_count = match line_counts.get(&target_uri) {
+                    Some(count) => *count,
+                    None => {
+                        let count = self
+                            .read_document(&target_uri)
+                            .await
+                            .map(|target| line_count(&target));
+                        line_counts.insert(target_uri, count);
+                        count
+                    }
+                };
+                let Some(line_count) = line_count else {
+                    continue;
+                };
 
-    let mut remaining_utf16 = position.character as usize;
-    for (idx, ch) in line_text.char_indices() {
-        let width = ch.len_utf16();
-        if remaining_utf16 == 0 {
-            return Some(line_start + idx);
+                let Some(line) = token
+                    .locator
+                    .line_groups()
+                    .into_iter()
+                    .map(|(line, _)| line)
+                    .find(|&line| line > line_count)
+                else {
+                    continue;
+                };
+                diagnostics.push(Diagnostic {
+                    range: token.range,
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    source: Some(DIAGNOSTIC_SOURCE.to_string()),
+                    message: format!(
+                        "line {line} is past the end of `{}` ({line_count} lines)",
+                        token.locator.path
+                    ),
+                    ..Diagnostic::default()
+                });
+            }
         }
-        if remaining_utf16 < width {
-            return Some(line_start + idx);
+        diagnostics
+    }
+
+    /// Publishes the diagnostics of `text`; an empty set is sent only once until
+    /// the document has diagnostics again.
+    async fn publish_diagnostics(&self, uri: Url, text: &str) {
+        // Unterminated openers are not indexed, so only skip the scan when there is no opener at all.
+        let opener = self.config.read().await.annotation_opener();
+        let diagnostics = if self.known_annotation_free(&uri).await
+            && !text.contains(&opener)
+            && !text.contains(MLIR_LOC_OPENER)
+        {
+            Vec::new()
+        } else {
+            self.compute_diagnostics(&uri, text).await
+        };
+        {
+            let mut clean_documents = self.clean_documents.write().await;
+            if diagnostics.is_empty() {
+                if !clean_documents.insert(uri.clone()) {
+                    return;
+                }
+            } else {
+                clean_documents.remove(&uri);
+            }
         }
-        remaining_utf16 -= width;
+        self.client
+            .publish_diagnostics(uri, diagnostics, None)
+            .await;
     }
 
-    Some(line_end)
-}
+    /// Every file below the workspace roots, walked off the async workers since
+    /// a large tree or slow mount would otherwise stall them.
+    async fn workspace_files(&self, config: &Config) -> Vec<PathBuf> {
+        let roots = self.workspace_roots.read().await.clone();
+        let (fs, exclude_dirs) = (Arc::clone(&self.fs), config.exclude_dirs.clone());
+        tokio::task::spawn_blocking(move || walk_source_roots(&roots, &exclude_dirs, fs.as_ref()))
+            .await
+            .unwrap_or_default()
+    }
 
-fn offset_to_position(offset: usize, text: &str, line_starts: &[usize]) -> Position {
-    let clamped = offset.min(text.len());
-    let line = match line_starts.binary_search(&clamped) {
-        Ok(index) => index,
-        Err(0) => 0,
-        Err(index) => index - 1,
-    };
+    /// Open documents, plus (with `include_workspace`) every annotated file under
+    /// the workspace roots that is not open, sorted by URI.
+    async fn annotated_documents(
+        &self,
+        include_workspace: bool,
+        config: &Config,
+    ) -> BTreeMap<Url, String> {
+        let mut documents: BTreeMap<Url, String> = self
+            .documents
+            .read()
+            .await
+            .iter()
+            .map(|(uri, text)| (uri.clone(), text.clone()))
+            .collect();
+        if include_workspace {
+            for path in self.workspace_files(config).await {
+                if !has_annotated_extension(&path) {
+                    continue;
+                }
+                let Ok(uri) = Url::from_file_path(&path) else {
+                    continue;
+                };
+                if documents.contains_key(&uri) {
+                    continue;
+                }
+                if let Ok(Some(text)) = read_workspace_file(&self.fs, &path, config).await {
+                    documents.insert(uri, text);
+                }
+            }
+        }
+        documents
+    }
 
-    let line_start = line_starts[line];
-    let utf16_col = text[line_start..clamped]
-        .chars()
-        .map(|ch| ch.len_utf16() as u32)
-        .sum();
+    /// Every locator token, in open documents and the workspace, pointing at the
+    /// line under the caret. With a `partialResultToken`, each document's matches
+    /// go to `sink` as soon as it is scanned and the final result is empty.
+    async fn find_references(
+        &self,
+        params: ReferenceParams,
+        sink: &impl PartialResultSink,
+    ) -> Result<Option<Vec<Location>>> {
+        let target = params.text_document_position.text_document.uri;
+        let line = params.text_document_position.position.line + 1;
+        let partial_result_token = params.partial_result_params.partial_result_token;
+        let config = self.config.read().await.clone();
 
-    Position::new(line as u32, utf16_col)
-}
+        let mut locations = Vec::new();
+        for (uri, text) in self.annotated_documents(true, &config).await {
+            let matches = locator_references(&text, &uri, &target, line, &config, self.fs.as_ref());
+            if matches.is_empty() {
+                continue;
+            }
+            match &partial_result_token {
+                Some(token) => sink.send_partial(token, matches).await,
+                None => locations.extend(matches),
+            }
+        }
+        Ok(Some(locations))
+    }
 
-fn find_annotations(text: &str) -> Vec<AnnotationSpan> {
-    let mut spans = Vec::new();
-    let mut cursor = 0;
+    async fn export_annotations(&self, arguments: &[Value]) -> Result<Option<Value>> {
+        let format = arguments
+            .first()
+            .and_then(Value::as_str)
+            .and_then(ExportFormat::parse)
+            .ok_or_else(|| {
+                Error::invalid_params("expected format `csv` or `json` as argument 1")
+            })?;
+        let output_path = arguments
+            .get(1)
+            .and_then(Value::as_str)
+            .ok_or_else(|| Error::invalid_params("expected output path as argument 2"))?
+            .to_string();
 
-    while let Some(relative_start) = text[cursor..].find("@[") {
-        let full_start = cursor + relative_start;
-        let inner_start = full_start + 2;
+        let include_workspace = arguments.get(2).and_then(Value::as_bool).unwrap_or(false);
+        let config = self.config.read().await.clone();
 
-        let Some(relative_end) = text[inner_start..].find(']') else {
-            break;
+        let mut records = Vec::new();
+        for (uri, text) in self.annotated_documents(include_workspace, &config).await {
+            records.extend(collect_annotation_records(&uri, &text, &config));
+        }
+
+        let serialized = match format {
+            ExportFormat::Csv => records_to_csv(&records),
+            ExportFormat::Json => records_to_json(&records),
         };
+        let (fs, path) = (Arc::clone(&self.fs), PathBuf::from(&output_path));
+        tokio::task::spawn_blocking(move || fs.write(&path, serialized.as_bytes()))
+            .await
+            .unwrap_or_else(|error| Err(std::io::Error::other(error)))
+            .map_err(|err| Error {
+                code: ErrorCode::InternalError,
+                message: format!("failed to write `{output_path}`: {err}").into(),
+                data: None,
+            })?;
 
-        let inner_end = inner_start + relative_end;
-        let full_end = inner_end + 1;
+        Ok(Some(
+            json!({ "path": output_path, "records": records.len() }),
+        ))
+    }
 
-        spans.push(AnnotationSpan {
-            full_start,
-            full_end,
-            inner_start,
-            inner_end,
-        });
+    async fn reveal_side_by_side(&self, arguments: &[Value]) -> Result<Option<Value>> {
+        let (uri, position) = uri_position_arguments(arguments)?;
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
 
-        cursor = full_end;
+        let config = self.config.read().await.clone();
+        let annotations = self.document_annotations(&uri, &text, &config).await;
+        let target_lines = self
+            .link_target_lines(&text, &annotations, &uri, position, &config)
+            .await;
+        Ok(side_by_side_payload(
+            &text,
+            &uri,
+            position,
+            &config,
+            &target_lines,
+            self.fs.as_ref(),
+        ))
     }
 
-    spans
-}
+    async fn goto_in_new_tab(&self, arguments: &[Value]) -> Result<Option<Value>> {
+        let (uri, position) = uri_position_arguments(arguments)?;
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
 
-fn find_annotation_at_offset(text: &str, offset: usize) -> Option<AnnotationSpan> {
-    find_annotations(text)
-        .into_iter()
-        .find(|span| offset >= span.full_start && offset < span.full_end)
-}
+        let config = self.config.read().await.clone();
+        let annotations = self.document_annotations(&uri, &text, &config).await;
+        let target_lines = self
+            .link_target_lines(&text, &annotations, &uri, position, &config)
+            .await;
+        Ok(new_tab_payload(
+            &text,
+            &uri,
+            position,
+            &config,
+            &target_lines,
+            self.fs.as_ref(),
+        ))
+    }
 
-fn split_locator_tokens(inner: &str) -> Vec<(usize, usize)> {
-    let mut result = Vec::new();
-    let mut start = 0;
-    let mut brace_depth = 0usize;
+    /// Target lines of every token the links at `position` can point into.
+    async fn link_target_lines(
+        &self,
+        text: &str,
+        annotations: &[AnnotationSpan],
+        uri: &Url,
+        position: Position,
+        config: &Config,
+    ) -> TargetLines {
+        let tokens = link_candidate_tokens(text, annotations, position, config);
+        TargetLines::load(&tokens, uri, config, &self.fs).await
+    }
 
-    for (idx, ch) in inner.char_indices() {
-        match ch {
-            '{' => brace_depth += 1,
-            '}' => {
-                brace_depth = brace_depth.saturating_sub(1);
+    /// `firrtl.listSourceFiles`: every distinct file the document's locators
+    /// resolve to, sorted, with how many tokens reference each.
+    async fn list_source_files(&self, arguments: &[Value]) -> Result<Option<Value>> {
+        let uri = uri_argument(arguments)?;
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
+
+        let config = self.config.read().await.clone();
+        let files: Vec<Value> = source_file_counts(&text, &uri, &config, self.fs.as_ref())
+            .into_iter()
+            .map(|(file, count)| json!({ "uri": file, "count": count }))
+            .collect();
+        Ok(Some(json!({ "files": files })))
+    }
+
+    /// `firrtl.statistics`: annotation density of the document: annotation and
+    /// token counts, distinct target files, and the share of tokens whose target
+    /// exists. Each target file is read at most once.
+    async fn statistics(&self, arguments: &[Value]) -> Result<Option<Value>> {
+        let uri = uri_argument(arguments)?;
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
+
+        let config = self.config.read().await.clone();
+        let line_starts = compute_line_starts(&text);
+        let annotations = find_annotations(&text, &config);
+        let mut readable: HashMap<Url, bool> = HashMap::new();
+        let (mut tokens, mut resolved) = (0usize, 0usize);
+        for annotation in &annotations {
+            for token in parse_tokens_from_annotation(&text, annotation, &line_starts, &config) {
+                tokens += 1;
+                let Ok(target) =
+                    resolve_target_url(&token.locator.path, &uri, &config, self.fs.as_ref())
+                else {
+                    continue;
+                };
+                let exists = match readable.get(&target) {
+                    Some(exists) => *exists,
+                    None => {
+                        let exists = self.read_target(&target).await.is_ok();
+                        readable.insert(target, exists);
+                        exists
+                    }
+                };
+                if exists {
+                    resolved += 1;
+                }
             }
-            ',' if brace_depth == 0 => {
-                result.push((start, idx));
-                start = idx + ch.len_utf8();
+        }
+
+        let resolved_percent = if tokens == 0 {
+            0.0
+        } else {
+            resolved as f64 * 100.0 / tokens as f64
+        };
+        Ok(Some(json!({
+            "annotations": annotations.len(),
+            "tokens": tokens,
+            "sourceFiles": readable.len(),
+            "resolvedTokens": resolved,
+            "resolvedPercent": resolved_percent,
+        })))
+    }
+
+    /// `firrtl.openSourceFile`: the file and line (at column 0) of the token under
+    /// the caret, available even when none of its columns yields a link.
+    async fn open_source_file(&self, arguments: &[Value]) -> Result<Option<Value>> {
+        let (uri, position) = uri_position_arguments(arguments)?;
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
+
+        let config = self.config.read().await.clone();
+        Ok(
+            source_file_location(&text, &uri, position, &config, self.fs.as_ref())
+                .and_then(|location| serde_json::to_value(location).ok()),
+        )
+    }
+
+    /// `firrtl.copyPermalink`: a GitHub permalink to the target line of the token
+    /// under the caret, pinned to the commit checked out where the target lives.
+    /// Targets that are untracked or whose `origin` is not on GitHub get their
+    /// plain file path instead.
+    async fn copy_permalink(&self, arguments: &[Value]) -> Result<Option<Value>> {
+        let (uri, position) = uri_position_arguments(arguments)?;
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
+
+        let (location, timeout) = {
+            let config = self.config.read().await;
+            (
+                source_file_location(&text, &uri, position, &config, self.fs.as_ref()),
+                Duration::from_millis(config.read_timeout_ms),
+            )
+        };
+        let Some(location) = location else {
+            return Ok(None);
+        };
+        let Ok(path) = location.uri.to_file_path() else {
+            return Ok(Some(json!(location.uri.as_str())));
+        };
+        let line = location.range.start.line + 1;
+        let permalink = git_permalink(&path, line, timeout)
+            .await
+            .unwrap_or_else(|| path.display().to_string());
+        Ok(Some(json!(permalink)))
+    }
+
+    /// `firrtl.summarizeRange`: one Markdown summary of every annotation overlapping
+    /// the range, each headed by its line and locators and followed by the same
+    /// source blocks as its `// @[` hover.
+    async fn summarize_range(&self, arguments: &[Value]) -> Result<Option<Value>> {
+        let uri = uri_argument(arguments)?;
+        let range = arguments
+            .get(1)
+            .cloned()
+            .and_then(|range| serde_json::from_value::<Range>(range).ok())
+            .ok_or_else(|| Error::invalid_params("expected `{ start, end }` as argument 2"))?;
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
+
+        let config = self.config.read().await.clone();
+        let line_starts = compute_line_starts(&text);
+        let (Some(start), Some(end)) = (
+            position_to_offset(range.start, &text, &line_starts),
+            position_to_offset(range.end, &text, &line_starts),
+        ) else {
+            return Ok(None);
+        };
+        // An empty range selects the annotation under it.
+        let end = end.max(start + 1);
+
+        let git_blobs = GitBlobs::default();
+        let request = RequestToken::DETACHED.with_git_blobs(&git_blobs);
+        let mut sections = Vec::new();
+        for annotation in self.document_annotations(&uri, &text, &config).await {
+            if annotation.full_start >= end || annotation.full_end <= start {
+                continue;
             }
-            _ => {}
+            let tokens = parse_tokens_from_annotation(&text, &annotation, &line_starts, &config);
+            let annotation_range = Range::new(
+                offset_to_position(annotation.full_start, &text, &line_starts),
+                offset_to_position(annotation.full_end, &text, &line_starts),
+            );
+            let locators: Vec<String> = tokens
+                .iter()
+                .map(|token| format!("`{}`", display_locator(&token.locator, &config)))
+                .collect();
+            let mut section = format!(
+                "**Line {}**: {}",
+                annotation_range.start.line + 1,
+                locators.join(", ")
+            );
+            let summary = self
+                .summary_hover(&tokens, &uri, annotation_range, request)
+                .await;
+            if let Some(Hover {
+                contents: HoverContents::Markup(markup),
+                ..
+            }) = summary
+            {
+                section.push_str(&format!("\n\n{}", markup.value));
+            }
+            sections.push(section);
         }
+
+        if sections.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(json!(sections.join("\n\n---\n\n"))))
     }
 
-    if start <= inner.len() {
-        result.push((start, inner.len()));
+    /// `firrtl.validateWorkspace`: checks every `.fir`/`.firrtl` file under the
+    /// workspace roots, returning totals and the files with locators whose target
+    /// is missing. Files are checked concurrently, each target looked up once.
+    async fn validate_workspace(&self) -> Result<Option<Value>> {
+        let config = Arc::new(self.config.read().await.clone());
+        let open_documents = self.documents.read().await.clone();
+        let targets: TargetExistence = Arc::default();
+        let permits = Arc::new(tokio::sync::Semaphore::new(VALIDATE_WORKSPACE_CONCURRENCY));
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for path in self.workspace_files(&config).await {
+            if markdown_language_from_path(&path.to_string_lossy()) != "firrtl" {
+                continue;
+            }
+            let Ok(uri) = Url::from_file_path(&path) else {
+                continue;
+            };
+            let open_text = open_documents.get(&uri).cloned();
+            let (config, targets, fs) = (config.clone(), targets.clone(), Arc::clone(&self.fs));
+            let permit = Arc::clone(&permits).acquire_owned().await;
+            tasks.spawn(async move {
+                let _permit = permit;
+                let text = match open_text {
+                    Some(text) => text,
+                    None => match read_workspace_file(&fs, &path, &config).await {
+                        Ok(Some(text)) => text,
+                        // Oversized files are skipped, as in exports.
+                        Ok(None) => return None,
+                        Err(err) => return Some((uri, Err(err.to_string()))),
+                    },
+                };
+                let checked = tokio::task::spawn_blocking({
+                    let uri = uri.clone();
+                    move || unresolved_locators(&text, &uri, &config, &targets, fs.as_ref())
+                })
+                .await
+                .map_err(|err| err.to_string());
+                Some((uri, checked))
+            });
+        }
+
+        let mut results = Vec::new();
+        while let Some(joined) = tasks.join_next().await {
+            if let Ok(Some(result)) = joined {
+                results.push(result);
+            }
+        }
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let (mut tokens, mut unresolved_tokens) = (0, 0);
+        let (mut unresolved_files, mut unreadable) = (Vec::new(), Vec::new());
+        for (uri, checked) in &results {
+            match checked {
+                Ok((count, unresolved)) => {
+                    tokens += count;
+                    unresolved_tokens += unresolved.len();
+                    if !unresolved.is_empty() {
+                        unresolved_files.push(json!({ "uri": uri, "unresolved": unresolved }));
+                    }
+                }
+                Err(err) => unreadable.push(json!({ "uri": uri, "error": err })),
+            }
+        }
+        Ok(Some(json!({
+            "files": results.len(),
+            "tokens": tokens,
+            "unresolvedTokens": unresolved_tokens,
+            "filesWithUnresolved": unresolved_files,
+            "unreadable": unreadable,
+        })))
     }
 
-    result
-}
+    /// `firrtl.canonicalizeAnnotation`: a `WorkspaceEdit` rewriting the annotation
+    /// under the caret into canonical form, or `null` when it already is.
+    async fn canonicalize_annotation(&self, arguments: &[Value]) -> Result<Option<Value>> {
+        let (uri, position) = uri_position_arguments(arguments)?;
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
 
-fn parse_columns(columns_text: &str) -> Option<Vec<u32>> {
-    let trimmed = columns_text.trim();
-    if trimmed.is_empty() {
-        return None;
+        let config = self.config.read().await.clone();
+        let annotations = self.document_annotations(&uri, &text, &config).await;
+        let Some(edit) = canonical_annotation_edit(&text, &annotations, position, &config) else {
+            return Ok(None);
+        };
+        let workspace_edit = WorkspaceEdit {
+            changes: Some(HashMap::from([(uri, vec![edit])])),
+            ..WorkspaceEdit::default()
+        };
+        Ok(serde_json::to_value(workspace_edit).ok())
     }
 
-    if trimmed.starts_with('{') && trimmed.ends_with('}') {
-        let inner = &trimmed[1..trimmed.len() - 1];
-        let columns: Vec<u32> = inner
-            .split(',')
-            .filter_map(|part| part.trim().parse::<u32>().ok())
-            .collect();
-        if columns.is_empty() {
-            None
+    /// `firrtl.copySourceLine`: the trimmed source line of the token under the
+    /// caret, or every mapped line (newline-joined) on the `// @[` trigger.
+    async fn copy_source_line(&self, arguments: &[Value]) -> Result<Option<Value>> {
+        let (uri, position) = uri_position_arguments(arguments)?;
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
+
+        let config = self.config.read().await.clone();
+        let line_starts = compute_line_starts(&text);
+        let Some(offset) = position_to_offset(position, &text, &line_starts) else {
+            return Ok(None);
+        };
+        let Some(annotation) = self
+            .annotation_at_offset(&uri, &text, offset, &config)
+            .await
+        else {
+            return Ok(None);
+        };
+
+        let tokens = parse_tokens_from_annotation(&text, &annotation, &line_starts, &config);
+        let (summary_start, summary_end) =
+            summary_hover_byte_range(&text, &annotation, &line_starts);
+        let selected: Vec<&ParsedToken> = if offset >= summary_start && offset < summary_end {
+            tokens.iter().collect()
         } else {
-            Some(columns)
+            tokens
+                .iter()
+                .filter(|token| offset >= token.byte_start && offset < token.byte_end)
+                .collect()
+        };
+        if selected.is_empty() {
+            return Ok(None);
         }
-    } else {
-        trimmed.parse::<u32>().ok().map(|column| vec![column])
+
+        let git_blobs = GitBlobs::default();
+        let request = RequestToken::DETACHED.with_git_blobs(&git_blobs);
+        let mut lines = Vec::new();
+        for token in selected {
+            for (line, _) in token.locator.line_groups() {
+                let source_line = match self
+                    .read_locator_line_logged(&token.locator.path, line, &uri, request)
+                    .await
+                {
+                    Some(source_line) => source_line.trim().to_string(),
+                    None => format!("<source line unavailable: {}:{line}>", token.locator.path),
+                };
+                lines.push(source_line);
+            }
+        }
+        Ok(Some(Value::String(lines.join("\n"))))
     }
-}
 
-fn parse_locator_token(token_text: &str, last_path: Option<&str>) -> Option<(Locator, bool)> {
-    let trimmed = token_text.trim();
-    if trimmed.is_empty() {
-        return None;
+    /// LSP version of the open document `uri`, bumped by every applied `didChange`.
+    async fn document_version(&self, uri: &Url) -> Option<i32> {
+        self.document_versions.read().await.get(uri).copied()
     }
 
-    let last_colon = trimmed.rfind(':')?;
-    let columns_text = &trimmed[last_colon + 1..];
-    let before_columns = &trimmed[..last_colon];
+    /// Hover for `params` against whatever text is current; `hover` drops the
+    /// result when the document changed meanwhile, since its ranges would be stale.
+    async fn compute_hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let text_document_position = params.text_document_position_params;
 
-    let line_colon = before_columns.rfind(':')?;
-    let path_text = &before_columns[..line_colon];
-    let line_text = &before_columns[line_colon + 1..];
+        let uri = text_document_position.text_document.uri;
+        let position = text_document_position.position;
 
-    let line = line_text.parse::<u32>().ok()?;
-    let columns = parse_columns(columns_text)?;
+        let git_blobs = GitBlobs::default();
+        let request = RequestToken::begin(&self.hover_generation).with_git_blobs(&git_blobs);
+        let config = self.config.read().await.clone();
+        if !config.allows_document(uri.path()) || self.known_annotation_free(&uri).await {
+            return Ok(None);
+        }
 
-    let (path, used_inherited_path) = if path_text.is_empty() {
-        (last_path?.to_string(), true)
-    } else {
-        (path_text.to_string(), false)
-    };
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
 
-    Some((
-        Locator {
-            path,
-            line,
-            columns,
-        },
-        used_inherited_path,
-    ))
-}
+        let line_starts = compute_line_starts(&text);
+        let Some(offset) = position_to_offset(position, &text, &line_starts) else {
+            return Ok(None);
+        };
 
-fn parse_tokens_from_annotation(
-    text: &str,
-    annotation: &AnnotationSpan,
-    line_starts: &[usize],
-) -> Vec<ParsedToken> {
-    let inner = &text[annotation.inner_start..annotation.inner_end];
-    let mut parsed = Vec::new();
-    let mut last_path: Option<String> = None;
+        if config.merge_same_line_annotations {
+            let annotations = self.document_annotations(&uri, &text, &config).await;
+            if let Some((line_annotations, (start, end))) =
+                merged_line_annotations(&text, &annotations, offset, &line_starts)
+            {
+                let tokens: Vec<ParsedToken> = line_annotations
+                    .iter()
+                    .flat_map(|annotation| {
+                        parse_tokens_from_annotation(&text, annotation, &line_starts, &config)
+                    })
+                    .collect();
+                let range = Range::new(
+                    offset_to_position(start, &text, &line_starts),
+                    offset_to_position(end, &text, &line_starts),
+                );
+                return Ok(self.summary_hover(&tokens, &uri, range, request).await);
+            }
+        }
+
+        let Some(annotation) = self
+            .annotation_at_offset(&uri, &text, offset, &config)
+            .await
+        else {
+            return Ok(None);
+        };
+
+        let tokens = parse_tokens_from_annotation(&text, &annotation, &line_starts, &config);
+        let (summary_start, summary_end) =
+            summary_hover_byte_range(&text, &annotation, &line_starts);
+        if offset >= summary_start && offset < summary_end {
+            let summary_range = Range::new(
+                offset_to_position(summary_start, &text, &line_starts),
+                offset_to_position(summary_end, &text, &line_starts),
+            );
+            return Ok(self
+                .summary_hover(&tokens, &uri, summary_range, request)
+                .await);
+        }
+
+        let Some(index) = tokens
+            .iter()
+            .position(|token| offset >= token.byte_start && offset < token.byte_end)
+        else {
+            return Ok(None);
+        };
+        let token = &tokens[index];
+
+        let (path_start, path_end) = token.path_span;
+        if offset >= path_start && offset < path_end {
+            if let Some(value) =
+                unresolved_path_markdown(&token.locator.path, &uri, &config, self.fs.as_ref())
+            {
+                return Ok(Some(Hover {
+                    contents: HoverContents::Markup(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value,
+                    }),
+                    range: Some(Range::new(
+                        offset_to_position(path_start, &text, &line_starts),
+                        offset_to_position(path_end, &text, &line_starts),
+                    )),
+                }));
+            }
+        }
+
+        let blocks = self
+            .locator_source_blocks(&token.locator, &uri, request)
+            .await;
+        if request.is_cancelled() {
+            return Ok(None);
+        }
+        let mut value = format!(
+            "{}\n{}",
+            blocks.join("\n"),
+            display_locator(&token.locator, &config)
+        );
+        if config.show_generated_line {
+            let annotation_line =
+                offset_to_position(annotation.full_start, &text, &line_starts).line;
+            if let Some(generated) = line_text_at(&text, annotation_line + 1) {
+                let language = markdown_language_from_path(uri.path());
+                value = format!("```{language}\n{}\n```\n{value}", generated.trim());
+            }
+        }
+        if let Some(tag) = &token.locator.tag {
+            value.push_str(&format!(" `<{tag}>`"));
+        }
+        if config.show_enclosing_symbol {
+            if let Some(symbol) = self.locator_enclosing_symbol(&token.locator, &uri).await {
+                value.push_str(&format!("\nin `{symbol}`"));
+            }
+        }
+        if config.show_chain_context {
+            // Neighbouring tokens are the adjacent frames of the inlining chain.
+            if let Some(previous) = index.checked_sub(1).map(|previous| &tokens[previous]) {
+                value.push_str(&format!(
+                    "\nfrom `{}`",
+                    display_locator(&previous.locator, &config)
+                ));
+            }
+            if let Some(next) = tokens.get(index + 1) {
+                value.push_str(&format!(
+                    "\ninto `{}`",
+                    display_locator(&next.locator, &config)
+                ));
+            }
+        }
+        if config.show_reference_count {
+            let annotations = self.document_annotations(&uri, &text, &config).await;
+            let count = same_location_count(
+                &token.locator,
+                &text,
+                &annotations,
+                &line_starts,
+                &uri,
+                &config,
+                self.fs.as_ref(),
+            );
+            value.push_str(&format!("\nreferenced {count}× in this file"));
+        }
+        if config.follow_firrtl_targets {
+            for (locator, blocks) in self
+                .followed_firrtl_blocks(&token.locator, &uri, &config, request)
+                .await
+            {
+                value.push_str(&format!(
+                    "\n\nFollowed into `{}`:\n{}",
+                    display_locator(&locator, &config),
+                    blocks.join("\n")
+                ));
+            }
+            if request.is_cancelled() {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(token.range),
+        }))
+    }
+
+    /// Points links into source jars at copies of their entries extracted to the
+    /// temp directory, since editors cannot open `jar:` URLs. A link whose entry
+    /// cannot be extracted keeps its `jar:` URL.
+    async fn extract_jar_targets(&self, links: &mut [LocationLink], config: &Config) {
+        let timeout = Duration::from_millis(config.read_timeout_ms);
+        let max_bytes = config.max_document_bytes;
+        for link in links {
+            let Some((jar, entry)) = jarsource::split_url(&link.target_uri) else {
+                continue;
+            };
+            let copy = with_read_timeout(&self.fs, &jar, timeout, move |fs, jar| {
+                jarsource::extracted(fs, jar, &entry, max_bytes)
+            })
+            .await;
+            match copy.map(Url::from_file_path) {
+                Ok(Ok(url)) => link.target_uri = url,
+                Ok(Err(())) => {}
+                Err(error) => self.log_resolve_error(&error),
+            }
+        }
+    }
+
+    /// Definition links for `params`; dropped like `compute_hover` results when
+    /// the document changed while they were computed.
+    async fn compute_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>> {
+        let text_document_position = params.text_document_position_params;
+        let uri = text_document_position.text_document.uri;
+        let position = text_document_position.position;
+
+        let request = RequestToken::begin(&self.definition_generation);
+        if self.known_annotation_free(&uri).await {
+            return Ok(None);
+        }
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
+
+        let config = self.config.read().await.clone();
+        if !config.allows_document(uri.path()) || request.is_cancelled() {
+            return Ok(None);
+        }
+        let annotations = self.document_annotations(&uri, &text, &config).await;
+        let target_lines = self
+            .link_target_lines(&text, &annotations, &uri, position, &config)
+            .await;
+        if request.is_cancelled() {
+            return Ok(None);
+        }
+        let mut links = definition_links(
+            &text,
+            &annotations,
+            &uri,
+            position,
+            &config,
+            &target_lines,
+            self.fs.as_ref(),
+        );
+        if links.is_empty() || request.is_cancelled() {
+            return Ok(None);
+        }
+
+        if config.ambiguous_resolution == AmbiguousResolution::Prompt
+            && has_ambiguous_targets(&links)
+        {
+            let _ = self
+                .client
+                .show_message(
+                    MessageType::INFO,
+                    "Locator matches files under several source roots; pick the intended one.",
+                )
+                .await;
+        }
+
+        // A token under the caret keeps every link it points at; only summaries are capped.
+        if let Some(max) = config.max_goto_links.filter(|max| {
+            links.len() > *max && !caret_on_token(&text, &annotations, position, &config)
+        }) {
+            self.log_detached(
+                MessageType::INFO,
+                format!(
+                    "firrtl-source-locator: returned the first {max} of {} definition links (maxGotoLinks)",
+                    links.len()
+                ),
+            );
+            links.truncate(max);
+        }
+        self.extract_jar_targets(&mut links, &config).await;
+
+        Ok(Some(match config.goto_response_kind {
+            GotoResponseKind::Link => GotoDefinitionResponse::Link(links),
+            // A location keeps only where the caret lands, like the link's selection range.
+            GotoResponseKind::Location => GotoDefinitionResponse::Array(
+                links
+                    .into_iter()
+                    .map(|link| Location::new(link.target_uri, link.target_selection_range))
+                    .collect(),
+            ),
+        }))
+    }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for Backend {
+    async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
+        *self.workspace_roots.write().await = workspace_roots_from_params(&params);
+        let (mut config, mut warnings) =
+            Config::from_initialization_options(params.initialization_options);
+        config.apply_base_dir_env(std::env::var_os(BASE_DIR_ENV).map(PathBuf::from));
+        let workspace_roots = self.workspace_roots.read().await.clone();
+        config.anchor_source_roots(&workspace_roots);
+        warnings.extend(config.validate_base_dir(self.fs.as_ref()));
+        for warning in warnings {
+            self.client
+                .show_message(
+                    MessageType::WARNING,
+                    format!("firrtl-source-locator: {warning}"),
+                )
+                .await;
+        }
+        *self.config.write().await = config;
+
+        Ok(InitializeResult {
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                    TextDocumentSyncKind::INCREMENTAL,
+                )),
+                definition_provider: Some(OneOf::Left(true)),
+                code_action_provider: Some(CodeActionProviderCapability::Options(
+                    CodeActionOptions {
+                        code_action_kinds: Some(vec![
+                            CodeActionKind::QUICKFIX,
+                            CodeActionKind::REFACTOR_REWRITE,
+                        ]),
+                        ..CodeActionOptions::default()
+                    },
+                )),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                references_provider: Some(OneOf::Left(true)),
+                rename_provider: Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: WorkDoneProgressOptions::default(),
+                })),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        EXPORT_ANNOTATIONS_COMMAND.to_string(),
+                        REVEAL_SIDE_BY_SIDE_COMMAND.to_string(),
+                        COPY_SOURCE_LINE_COMMAND.to_string(),
+                        GOTO_IN_NEW_TAB_COMMAND.to_string(),
+                        LIST_SOURCE_FILES_COMMAND.to_string(),
+                        CANONICALIZE_ANNOTATION_COMMAND.to_string(),
+                        STATISTICS_COMMAND.to_string(),
+                        OPEN_SOURCE_FILE_COMMAND.to_string(),
+                        VALIDATE_WORKSPACE_COMMAND.to_string(),
+                        COPY_PERMALINK_COMMAND.to_string(),
+                        SUMMARIZE_RANGE_COMMAND.to_string(),
+                        RECENT_JUMPS_COMMAND.to_string(),
+                    ],
+                    ..ExecuteCommandOptions::default()
+                }),
+                ..ServerCapabilities::default()
+            },
+            server_info: Some(ServerInfo {
+                name: "firrtl-source-locator".to_string(),
+                version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            }),
+        })
+    }
+
+    async fn initialized(&self, _: InitializedParams) {
+        let _ = self
+            .client
+            .log_message(
+                MessageType::INFO,
+                "firrtl-source-locator ready: Go to Definition for @[...] is enabled",
+            )
+            .await;
+
+        // File change notifications keep the source line cache fresh.
+        let watchers = DidChangeWatchedFilesRegistrationOptions {
+            watchers: vec![FileSystemWatcher {
+                glob_pattern: GlobPattern::String("**/*".to_string()),
+                kind: None,
+            }],
+        };
+        let _ = self
+            .client
+            .register_capability(vec![Registration {
+                id: "firrtl-source-locator-watched-files".to_string(),
+                method: "workspace/didChangeWatchedFiles".to_string(),
+                register_options: serde_json::to_value(watchers).ok(),
+            }])
+            .await;
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let uri = params.text_document.uri;
+        let text = params.text_document.text;
+        let config = self.config.read().await.clone();
+        self.annotation_index
+            .write()
+            .await
+            .insert(uri.clone(), find_annotations(&text, &config));
+        let mut documents = self.documents.write().await;
+        self.document_versions
+            .write()
+            .await
+            .insert(uri.clone(), params.text_document.version);
+        documents.insert(uri.clone(), text.clone());
+        drop(documents);
+        self.publish_diagnostics(uri, &text).await;
+    }
+
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        let uri = params.text_document.uri;
+        let version = params.text_document.version;
+        if params.content_changes.is_empty() {
+            // Nothing to apply; keep the stored text and its diagnostics as they are.
+            self.log_detached(
+                MessageType::WARNING,
+                format!("firrtl-source-locator: didChange for {uri} carried no content changes"),
+            );
+            return;
+        }
+        let config = self.config.read().await.clone();
+        let text = {
+            let mut documents = self.documents.write().await;
+            let mut versions = self.document_versions.write().await;
+            let mut index = self.annotation_index.write().await;
+            let Some(text) = documents.get_mut(&uri) else {
+                return;
+            };
+            if versions
+                .get(&uri)
+                .is_some_and(|current| *current >= version)
+            {
+                // Out of order: applying it would edit text it was not computed against.
+                self.log_detached(
+                    MessageType::WARNING,
+                    format!("firrtl-source-locator: ignored stale didChange {version} for {uri}"),
+                );
+                return;
+            }
+            versions.insert(uri.clone(), version);
+            let annotations = index.entry(uri.clone()).or_default();
+            for change in params.content_changes {
+                match apply_content_change(text, &change) {
+                    Some(edit) => {
+                        *annotations = reindex_annotations(text, annotations, edit, &config)
+                    }
+                    None => *annotations = find_annotations(text, &config),
+                }
+            }
+            text.clone()
+        };
+        self.publish_diagnostics(uri, &text).await;
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        let mut source_lines = self.source_lines.lock().await;
+        for change in params.changes {
+            // Lines of entries inside a changed jar are stale as well.
+            source_lines.evict_matching(|cached| {
+                *cached == change.uri
+                    || jarsource::split_url(cached).is_some_and(|(jar, _)| {
+                        Url::from_file_path(jar).is_ok_and(|jar| jar == change.uri)
+                    })
+            });
+        }
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        self.clean_documents
+            .write()
+            .await
+            .remove(&params.text_document.uri);
+        self.annotation_index
+            .write()
+            .await
+            .remove(&params.text_document.uri);
+        self.document_versions
+            .write()
+            .await
+            .remove(&params.text_document.uri);
+        self.documents
+            .write()
+            .await
+            .remove(&params.text_document.uri);
+    }
+
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>> {
+        let uri = params
+            .text_document_position_params
+            .text_document
+            .uri
+            .clone();
+        let position = params.text_document_position_params.position;
+        let version = self.document_version(&uri).await;
+        let links = self.compute_definition(params).await?;
+        if self.document_version(&uri).await != version {
+            return Ok(None);
+        }
+
+        let targets: Vec<Location> = match &links {
+            Some(GotoDefinitionResponse::Link(links)) => links
+                .iter()
+                .map(|link| Location::new(link.target_uri.clone(), link.target_selection_range))
+                .collect(),
+            Some(GotoDefinitionResponse::Array(locations)) => locations.clone(),
+            Some(GotoDefinitionResponse::Scalar(location)) => vec![location.clone()],
+            None => Vec::new(),
+        };
+        if !targets.is_empty() {
+            let capacity = self.config.read().await.recent_jumps_limit;
+            let jump = Jump {
+                source: Location::new(uri, Range::new(position, position)),
+                targets,
+            };
+            self.recent_jumps.lock().await.record(jump, capacity);
+        }
+        Ok(links)
+    }
+
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let uri = params
+            .text_document_position_params
+            .text_document
+            .uri
+            .clone();
+        let version = self.document_version(&uri).await;
+        let hover = self.compute_hover(params).await?;
+        if self.document_version(&uri).await != version {
+            return Ok(None);
+        }
+        Ok(hover)
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let uri = params.text_document.uri;
+        let config = self.config.read().await.clone();
+        if !config.allows_document(uri.path()) {
+            return Ok(None);
+        }
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
+
+        let annotations = self.document_annotations(&uri, &text, &config).await;
+        let mut actions =
+            malformed_annotation_fixes(&text, &annotations, &uri, params.range, &config);
+        if let Some(edit) =
+            canonical_annotation_edit(&text, &annotations, params.range.start, &config)
+        {
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: "Canonicalize locator annotation".to_string(),
+                kind: Some(CodeActionKind::REFACTOR_REWRITE),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(HashMap::from([(uri.clone(), vec![edit])])),
+                    ..WorkspaceEdit::default()
+                }),
+                ..CodeAction::default()
+            }));
+        }
+        Ok((!actions.is_empty()).then_some(actions))
+    }
+
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        let Some(text) = self.read_document(&params.text_document.uri).await else {
+            return Ok(None);
+        };
+
+        let uri = &params.text_document.uri;
+        let config = self.config.read().await.clone();
+        let annotations = self.document_annotations(uri, &text, &config).await;
+        Ok(
+            prepare_rename_range(&text, &annotations, params.position, &config)
+                .map(PrepareRenameResponse::Range),
+        )
+    }
+
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        self.find_references(params, &self.client).await
+    }
+
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        let text_document_position = params.text_document_position;
+        let uri = text_document_position.text_document.uri;
+
+        let Some(text) = self.read_document(&uri).await else {
+            return Ok(None);
+        };
+
+        let config = self.config.read().await.clone();
+        let annotations = self.document_annotations(&uri, &text, &config).await;
+        let edits = rename_path_edits(
+            &text,
+            &annotations,
+            &uri,
+            text_document_position.position,
+            &params.new_name,
+            &config,
+            self.fs.as_ref(),
+        );
+        if edits.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(WorkspaceEdit {
+            changes: Some(HashMap::from([(uri, edits)])),
+            ..WorkspaceEdit::default()
+        }))
+    }
+
+    async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<Value>> {
+        match params.command.as_str() {
+            EXPORT_ANNOTATIONS_COMMAND => self.export_annotations(&params.arguments).await,
+            REVEAL_SIDE_BY_SIDE_COMMAND => self.reveal_side_by_side(&params.arguments).await,
+            COPY_SOURCE_LINE_COMMAND => self.copy_source_line(&params.arguments).await,
+            GOTO_IN_NEW_TAB_COMMAND => self.goto_in_new_tab(&params.arguments).await,
+            LIST_SOURCE_FILES_COMMAND => self.list_source_files(&params.arguments).await,
+            CANONICALIZE_ANNOTATION_COMMAND => {
+                self.canonicalize_annotation(&params.arguments).await
+            }
+            STATISTICS_COMMAND => self.statistics(&params.arguments).await,
+            OPEN_SOURCE_FILE_COMMAND => self.open_source_file(&params.arguments).await,
+            VALIDATE_WORKSPACE_COMMAND => self.validate_workspace().await,
+            COPY_PERMALINK_COMMAND => self.copy_permalink(&params.arguments).await,
+            SUMMARIZE_RANGE_COMMAND => self.summarize_range(&params.arguments).await,
+            RECENT_JUMPS_COMMAND => Ok(Some(json!(self.recent_jumps.lock().await.recent()))),
+            other => Err(Error::invalid_params(format!("unknown command `{other}`"))),
+        }
+    }
+}
+
+/// Reads `path` off the runtime's worker threads and returns its text, plus whether
+/// it had to be decoded lossily because it is not valid UTF-8. Gives up after
+/// `timeout`, so a slow network mount degrades to "<source line unavailable>"
+/// instead of stalling.
+async fn read_file_with_timeout(
+    fs: &Arc<dyn FileSystem>,
+    path: &Path,
+    timeout: Duration,
+) -> std::result::Result<(String, bool), ResolveError> {
+    let bytes = with_read_timeout(fs, path, timeout, |fs, path| fs.read(path)).await?;
+    Ok(decode_lossy(bytes))
+}
+
+/// Text of `entry` in the source jar `jar`, read like [`read_file_with_timeout`]
+/// and refused when the entry holds more than `max_bytes`.
+async fn read_jar_entry_with_timeout(
+    fs: &Arc<dyn FileSystem>,
+    jar: &Path,
+    entry: &str,
+    timeout: Duration,
+    max_bytes: u64,
+) -> std::result::Result<(String, bool), ResolveError> {
+    let entry = entry.to_string();
+    let bytes = with_read_timeout(fs, jar, timeout, move |fs, jar| {
+        jarsource::read_entry(fs, jar, &entry, max_bytes)
+    })
+    .await?;
+    Ok(decode_lossy(bytes))
+}
+
+/// `bytes` as text, plus whether invalid UTF-8 had to be replaced.
+fn decode_lossy(bytes: Vec<u8>) -> (String, bool) {
+    match String::from_utf8(bytes) {
+        Ok(text) => (text, false),
+        Err(error) => (String::from_utf8_lossy(error.as_bytes()).into_owned(), true),
+    }
+}
+
+/// Text of the unopened workspace file `path`, read within `readTimeoutMs`.
+/// `None` when it is larger than `maxDocumentBytes`, so it is skipped like an
+/// oversized target rather than read in full.
+async fn read_workspace_file(
+    fs: &Arc<dyn FileSystem>,
+    path: &Path,
+    config: &Config,
+) -> std::result::Result<Option<String>, ResolveError> {
+    let timeout = Duration::from_millis(config.read_timeout_ms);
+    let metadata = with_read_timeout(fs, path, timeout, |fs, path| fs.metadata(path)).await?;
+    if metadata.len > config.max_document_bytes {
+        return Ok(None);
+    }
+    with_read_timeout(fs, path, timeout, |fs, path| fs.read_to_string(path))
+        .await
+        .map(Some)
+}
+
+/// Runs the blocking `read` of `path` off the async workers, giving up after `timeout`.
+async fn with_read_timeout<T, F>(
+    fs: &Arc<dyn FileSystem>,
+    path: &Path,
+    timeout: Duration,
+    read: F,
+) -> std::result::Result<T, ResolveError>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn FileSystem, &Path) -> std::io::Result<T> + Send + 'static,
+{
+    let (fs, owned_path) = (Arc::clone(fs), path.to_path_buf());
+    let task = tokio::task::spawn_blocking(move || read(fs.as_ref(), &owned_path));
+    tokio::time::timeout(timeout, task)
+        .await
+        .map_err(|_| ResolveError::timed_out(path, timeout))?
+        .map_err(|error| ResolveError::ReadError(path.to_path_buf(), error.to_string()))?
+        .map_err(|error| ResolveError::from_io(path, &error))
+}
+
+/// The gzip file to read for `path`: `path` itself when it ends in `.gz`, else
+/// its `.gz` sibling when `path` is missing and the sibling exists.
+fn gzip_source(path: &Path, fs: &dyn FileSystem) -> Option<PathBuf> {
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        return Some(path.to_path_buf());
+    }
+    if fs.exists(path) {
+        return None;
+    }
+    let mut name = path.file_name()?.to_os_string();
+    name.push(".gz");
+    let sibling = path.with_file_name(name);
+    fs.is_file(&sibling).then_some(sibling)
+}
+
+/// Decompressed text of the gzip file `path`, refusing more than `max_bytes`
+/// of output.
+async fn read_gzip_with_timeout(
+    fs: &Arc<dyn FileSystem>,
+    path: &Path,
+    timeout: Duration,
+    max_bytes: u64,
+) -> std::result::Result<String, ResolveError> {
+    use std::io::Read;
+
+    let compressed = with_read_timeout(fs, path, timeout, |fs, path| fs.read(path)).await?;
+    let mut text = String::new();
+    flate2::read::GzDecoder::new(compressed.as_slice())
+        .take(max_bytes.saturating_add(1))
+        .read_to_string(&mut text)
+        .map_err(|error| ResolveError::ReadError(path.to_path_buf(), error.to_string()))?;
+    if text.len() as u64 > max_bytes {
+        return Err(ResolveError::ReadError(
+            path.to_path_buf(),
+            format!("decompresses to more than maxDocumentBytes ({max_bytes})"),
+        ));
+    }
+    Ok(text)
+}
+
+/// Trimmed standard output of `git -C <directory> <args>`, or `None` when git fails.
+async fn git_stdout(directory: &Path, args: &[&str], timeout: Duration) -> Option<String> {
+    let output = tokio::process::Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(timeout, output).await.ok()?.ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    output
+        .status
+        .success()
+        .then(|| stdout.trim().to_string())
+        .filter(|stdout| !stdout.is_empty())
+}
+
+/// Permalink to `line` of the tracked file `path`, from its checkout's `HEAD`
+/// and `origin` remote.
+async fn git_permalink(path: &Path, line: u32, timeout: Duration) -> Option<String> {
+    let directory = path.parent()?;
+    let file_name = path.file_name()?.to_str()?;
+    let relative_path = git_stdout(
+        directory,
+        &["ls-files", "--full-name", "--", file_name],
+        timeout,
+    )
+    .await?;
+    let sha = git_stdout(directory, &["rev-parse", "HEAD"], timeout).await?;
+    let remote = git_stdout(directory, &["remote", "get-url", "origin"], timeout).await?;
+    github_permalink(&remote, &sha, &relative_path, line)
+}
+
+/// `https://github.com/<owner>/<repo>/blob/<sha>/<path>#L<line>` for a GitHub
+/// `remote` in HTTPS or SSH form; `None` for any other host.
+fn github_permalink(remote: &str, sha: &str, relative_path: &str, line: u32) -> Option<String> {
+    let repository = [
+        "https://github.com/",
+        "http://github.com/",
+        "git@github.com:",
+        "ssh://git@github.com/",
+    ]
+    .iter()
+    .find_map(|prefix| remote.strip_prefix(prefix))?
+    .trim_end_matches('/');
+    let repository = repository.strip_suffix(".git").unwrap_or(repository);
+    let segments: Vec<&str> = repository.split('/').collect();
+    if segments.len() != 2 || segments.iter().any(|segment| segment.is_empty()) {
+        return None;
+    }
+
+    let mut url = Url::parse("https://github.com/").ok()?;
+    url.path_segments_mut()
+        .ok()?
+        .pop_if_empty()
+        .extend(segments)
+        .push("blob")
+        .push(sha)
+        .extend(relative_path.split(['/', '\\']));
+    url.set_fragment(Some(&format!("L{line}")));
+    Some(url.to_string())
+}
+
+/// Contents of `path` at git `revision` (`git show <rev>:./<file>` run in the
+/// file's directory), or `None` when git fails, e.g. because the file is not
+/// tracked at that revision.
+async fn read_git_revision(path: &Path, revision: &str, timeout: Duration) -> Option<Vec<u8>> {
+    if revision.is_empty() || revision.starts_with('-') {
+        return None;
+    }
+    let directory = path.parent()?;
+    let file_name = path.file_name()?.to_str()?;
+    let output = tokio::process::Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .arg("show")
+        .arg(format!("{revision}:./{file_name}"))
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(timeout, output).await.ok()?.ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+/// `git show` output, which is the raw blob contents, decoded for line lookups.
+fn git_blob_text(stdout: &[u8]) -> Arc<str> {
+    Arc::from(String::from_utf8_lossy(stdout))
+}
+
+/// [`read_git_revision`] as text, run at most once per path and revision while
+/// `request` carries a blob cache.
+async fn git_revision_text(
+    path: &Path,
+    revision: &str,
+    timeout: Duration,
+    request: RequestToken<'_>,
+) -> Option<Arc<str>> {
+    let Some(git_blobs) = request.git_blobs else {
+        let stdout = read_git_revision(path, revision, timeout).await?;
+        return Some(git_blob_text(&stdout));
+    };
+    // Held across the read so concurrent lookups of one blob wait for it instead of spawning git.
+    let mut blobs = git_blobs.lock().await;
+    let key = (path.to_path_buf(), revision.to_string());
+    if let Some(text) = blobs.get(&key) {
+        return text.clone();
+    }
+    let text = read_git_revision(path, revision, timeout)
+        .await
+        .map(|stdout| git_blob_text(&stdout));
+    blobs.insert(key, text.clone());
+    text
+}
+
+/// Why a locator could not be resolved to target text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ResolveError {
+    /// The resolved candidate is not an absolute path, so it has no `file://` URL.
+    NotAbsolute(PathBuf),
+    /// The annotated document has no directory to resolve relative paths against,
+    /// and no `sourceRoots` were configured.
+    SourceHasNoParent(Url),
+    /// The resolved target does not exist.
+    FileNotFound(PathBuf),
+    /// The target exists but could not be read: an I/O error, a timeout, or the size limit.
+    ReadError(PathBuf, String),
+    /// The target lies outside every `allowedResolveRoots` entry.
+    OutsideAllowedRoots(PathBuf),
+}
+
+impl ResolveError {
+    fn from_io(path: &Path, error: &std::io::Error) -> Self {
+        if error.kind() == std::io::ErrorKind::NotFound {
+            Self::FileNotFound(path.to_path_buf())
+        } else {
+            Self::ReadError(path.to_path_buf(), error.to_string())
+        }
+    }
+
+    fn timed_out(path: &Path, timeout: Duration) -> Self {
+        Self::ReadError(
+            path.to_path_buf(),
+            format!("timed out after {} ms", timeout.as_millis()),
+        )
+    }
+}
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotAbsolute(path) => write!(f, "`{}` is not an absolute path", path.display()),
+            Self::SourceHasNoParent(uri) => {
+                write!(
+                    f,
+                    "`{uri}` has no directory to resolve relative paths against"
+                )
+            }
+            Self::FileNotFound(path) => write!(f, "`{}` does not exist", path.display()),
+            Self::ReadError(path, reason) => {
+                write!(f, "could not read `{}`: {reason}", path.display())
+            }
+            Self::OutsideAllowedRoots(path) => {
+                write!(f, "`{}` is outside allowedResolveRoots", path.display())
+            }
+        }
+    }
+}
+
+/// Resolves a locator path: absolute paths are kept, relative ones are joined onto `base_dir`.
+fn resolve_target_path(path: &str, base_dir: &Path) -> PathBuf {
+    let candidate = PathBuf::from(path);
+    if candidate.is_absolute() {
+        candidate
+    } else {
+        base_dir.join(candidate)
+    }
+}
+
+/// Files a locator path may refer to, in the order tried: the jar of a `jar:`
+/// locator, else the first that applies of the path itself when absolute, its
+/// `.sourcemap` entry, the `moduleIndex` entry of a bare module name and its
+/// `rootAliases` root, else the path joined onto `baseDir`, the annotated file's
+/// `compileCommands` directories, its `resolveFromProjectRoot` project root, its
+/// own directory and each `sourceRoots` entry.
+fn resolution_candidates(
+    path: &str,
+    source_uri: &Url,
+    config: &Config,
+    fs: &dyn FileSystem,
+) -> Vec<PathBuf> {
+    if let Some((jar, _)) = jar_locator(path, config) {
+        return vec![jar];
+    }
+    if Path::new(path).is_absolute() || unc_parts(path).is_some() {
+        return vec![PathBuf::from(path)];
+    }
+    if let Some(target) = source_uri
+        .to_file_path()
+        .ok()
+        .and_then(|source_path| sourcemap::lookup(fs, &source_path, path))
+    {
+        return vec![target];
+    }
+    if let Some(target) = config
+        .module_index
+        .as_deref()
+        .filter(|_| is_module_name(path))
+        .and_then(|index| sourcemap::lookup_module(fs, index, path))
+    {
+        return vec![target];
+    }
+    if let Some((root, rest)) = config.match_root_alias(path) {
+        return vec![root.join(rest)];
+    }
+
+    let mut candidates = Vec::new();
+    if let Some(base_dir) = &config.base_dir {
+        candidates.push(resolve_target_path(path, base_dir));
+    }
+    if let (Some(database), Ok(source_path)) = (&config.compile_commands, source_uri.to_file_path())
+    {
+        for directory in compiledb::directories_for(fs, database, &source_path) {
+            candidates.push(resolve_target_path(path, &directory));
+        }
+    }
+    if config.resolve_from_project_root {
+        if let Some(root) = source_uri
+            .to_file_path()
+            .ok()
+            .and_then(|source_path| project_root(&source_path, &config.project_root_markers, fs))
+        {
+            candidates.push(resolve_target_path(path, &root));
+        }
+    }
+    if let Some(source_dir) = source_uri
+        .to_file_path()
+        .ok()
+        .and_then(|source_path| source_path.parent().map(Path::to_path_buf))
+    {
+        candidates.push(resolve_target_path(path, &source_dir));
+    }
+    for root in &config.source_roots {
+        candidates.push(resolve_target_path(path, root));
+    }
+    candidates
+}
+
+/// Jar and entry of a `jar:` locator path when `supportJarSources` is on.
+fn jar_locator<'a>(path: &'a str, config: &Config) -> Option<(PathBuf, &'a str)> {
+    if config.support_jar_sources {
+        jarsource::split(path)
+    } else {
+        None
+    }
+}
+
+/// Nearest ancestor directory of `source_path` containing one of `markers`.
+fn project_root(source_path: &Path, markers: &[String], fs: &dyn FileSystem) -> Option<PathBuf> {
+    source_path
+        .ancestors()
+        .skip(1)
+        .find(|dir| markers.iter().any(|marker| fs.exists(&dir.join(marker))))
+        .map(Path::to_path_buf)
+}
+
+/// Host, share and remaining components of a Windows UNC path like
+/// `\\server\share\dir\Foo.scala`, recognized on every platform.
+fn unc_parts(path: &str) -> Option<(&str, &str, Vec<&str>)> {
+    let rest = path.strip_prefix(r"\\")?;
+    if rest.starts_with(['?', '.']) {
+        // `\\?\` and `\\.\` are device paths, not network shares.
+        return None;
+    }
+    let mut components = rest.split('\\');
+    let host = components.next().filter(|host| !host.is_empty())?;
+    let share = components.next().filter(|share| !share.is_empty())?;
+    Some((host, share, components.collect()))
+}
+
+/// `file://` URL of a resolved candidate. UNC paths become `file://server/share/...`
+/// even where the platform's path rules do not know them.
+fn candidate_url(path: &Path) -> Option<Url> {
+    if let Some((host, share, components)) = path.to_str().and_then(unc_parts) {
+        let mut url = Url::parse(&format!("file://{host}/")).ok()?;
+        url.path_segments_mut()
+            .ok()?
+            .pop_if_empty()
+            .push(share)
+            .extend(components);
+        return Some(url);
+    }
+    Url::from_file_path(path).ok()
+}
+
+/// Whether a locator path looks like a module name rather than a file path.
+fn is_module_name(path: &str) -> bool {
+    !path.contains(['/', '\\']) && Path::new(path).extension().is_none()
+}
+
+/// Hover text for the path part of a token whose path exists under none of its
+/// resolution candidates: the raw path and every candidate that was tried.
+fn unresolved_path_markdown(
+    path: &str,
+    source_uri: &Url,
+    config: &Config,
+    fs: &dyn FileSystem,
+) -> Option<String> {
+    let candidates = resolution_candidates(path, source_uri, config, fs);
+    if candidates.iter().any(|candidate| fs.is_file(candidate)) {
+        return None;
+    }
+
+    let mut value = format!("**Unresolved path** `{path}`\n\nTried:");
+    for candidate in &candidates {
+        value.push_str(&format!("\n- `{}` (missing)", candidate.display()));
+    }
+    if candidates.is_empty() {
+        value.push_str("\n- no candidate locations");
+    }
+    Some(value)
+}
+
+/// Resolves a locator path to target URLs.
+///
+/// Only candidates that exist on disk are considered; when none does, the first
+/// candidate is still returned so navigation behaves like a plain relative join.
+/// With several existing candidates, `ambiguousResolution` decides whether only
+/// the first (`first`) or all of them (`all`, `prompt`) are returned. A `jar:`
+/// locator resolves to the `jar:` URL of its entry, without opening the jar.
+fn resolve_target_urls(
+    path: &str,
+    source_uri: &Url,
+    config: &Config,
+    fs: &dyn FileSystem,
+) -> Vec<Url> {
+    if let Some((jar, entry)) = jar_locator(path, config) {
+        return jarsource::entry_url(&jar, entry)
+            .filter(|_| config.allows_target(&jar, fs))
+            .into_iter()
+            .collect();
+    }
+    let candidates: Vec<PathBuf> = resolution_candidates(path, source_uri, config, fs)
+        .into_iter()
+        .filter(|candidate| config.allows_target(candidate, fs))
+        .collect();
+    let mut existing: Vec<&PathBuf> = candidates.iter().filter(|path| fs.is_file(path)).collect();
+    existing.dedup();
+
+    let selected: Vec<&PathBuf> = match (existing.len(), config.ambiguous_resolution) {
+        (0, _) => candidates.iter().take(1).collect(),
+        (_, AmbiguousResolution::First) => existing.into_iter().take(1).collect(),
+        (_, AmbiguousResolution::All | AmbiguousResolution::Prompt) => existing,
+    };
+
+    selected
+        .into_iter()
+        .filter_map(|path| candidate_url(path))
+        .collect()
+}
+
+fn resolve_target_url(
+    path: &str,
+    source_uri: &Url,
+    config: &Config,
+    fs: &dyn FileSystem,
+) -> std::result::Result<Url, ResolveError> {
+    if let Some((jar, entry)) = jar_locator(path, config) {
+        if !config.allows_target(&jar, fs) {
+            return Err(ResolveError::OutsideAllowedRoots(jar));
+        }
+        return jarsource::entry_url(&jar, entry).ok_or(ResolveError::NotAbsolute(jar));
+    }
+    if let Some(url) = resolve_target_urls(path, source_uri, config, fs)
+        .into_iter()
+        .next()
+    {
+        return Ok(url);
+    }
+    let candidates = resolution_candidates(path, source_uri, config, fs);
+    let allowed: Vec<&PathBuf> = candidates
+        .iter()
+        .filter(|candidate| config.allows_target(candidate, fs))
+        .collect();
+    // The candidate `resolve_target_urls` picked and could not turn into a URL.
+    let failed = allowed
+        .iter()
+        .find(|candidate| fs.is_file(candidate))
+        .or(allowed.first());
+    match (candidates.first(), failed) {
+        (None, _) => Err(ResolveError::SourceHasNoParent(source_uri.clone())),
+        (Some(first), None) => Err(ResolveError::OutsideAllowedRoots(first.clone())),
+        (Some(_), Some(failed)) => Err(ResolveError::NotAbsolute(failed.to_path_buf())),
+    }
+}
+
+fn collect_location_links<'a>(
+    tokens: impl IntoIterator<Item = &'a ParsedToken>,
+    source_uri: &Url,
+    config: &Config,
+    target_lines: &TargetLines,
+    fs: &dyn FileSystem,
+) -> Vec<LocationLink> {
+    let mut links = Vec::new();
+    let mut seen = HashSet::new();
+
+    for token in tokens {
+        for url in resolve_target_urls(&token.locator.path, source_uri, config, fs) {
+            for (line, columns) in token.locator.line_groups() {
+                if line == 0 {
+                    continue;
+                }
+
+                let line = line - 1;
+                let zero_based = config.zero_based_columns(url.path());
+                for column in columns {
+                    let mut col = match (zero_based, column) {
+                        (true, column) => column,
+                        (false, 0) => continue,
+                        (false, column) => column - 1,
+                    };
+                    let line_text = target_lines.get(&url, line);
+                    if let Some(text) = line_text {
+                        // Past the end of the line: land on its last character instead.
+                        let length = text.encode_utf16().count() as u32;
+                        col = col.min(length.saturating_sub(1));
+                    }
+                    let (start, end) = match line_text {
+                        Some(text) if config.select_target_identifier => {
+                            identifier_span(text, col).unwrap_or((col, col.saturating_add(1)))
+                        }
+                        _ => (col, col.saturating_add(1)),
+                    };
+                    let dedup_key = format!("{}:{line}:{start}", url);
+                    if !seen.insert(dedup_key) {
+                        continue;
+                    }
+
+                    let target_range =
+                        Range::new(Position::new(line, start), Position::new(line, end));
+                    let target_selection_range = if config.select_target_on_jump {
+                        target_range
+                    } else {
+                        Range::new(target_range.start, target_range.start)
+                    };
+                    links.push(LocationLink {
+                        origin_selection_range: None,
+                        target_uri: url.clone(),
+                        target_range,
+                        target_selection_range,
+                    });
+                }
+            }
+        }
+    }
+
+    if !config.preserve_link_order {
+        links.sort_by(|a, b| {
+            let (a_start, b_start) = (a.target_range.start, b.target_range.start);
+            a.target_uri
+                .as_str()
+                .cmp(b.target_uri.as_str())
+                .then((a_start.line, a_start.character).cmp(&(b_start.line, b_start.character)))
+        });
+    }
+    links
+}
+
+/// Texts of the target files links may point into, read before the links are
+/// built so building them never blocks on disk. Files that could not be read
+/// (or exceed `maxDocumentBytes`) report no lines.
+#[derive(Default)]
+struct TargetLines {
+    files: HashMap<Url, String>,
+}
+
+impl TargetLines {
+    /// Reads every target of `tokens` once, off the async workers and within `readTimeoutMs`.
+    async fn load(
+        tokens: &[ParsedToken],
+        source_uri: &Url,
+        config: &Config,
+        fs: &Arc<dyn FileSystem>,
+    ) -> Self {
+        let timeout = Duration::from_millis(config.read_timeout_ms);
+        let mut attempted = HashSet::new();
+        let mut files = HashMap::new();
+        for token in tokens {
+            for url in resolve_target_urls(&token.locator.path, source_uri, config, fs.as_ref()) {
+                if !attempted.insert(url.clone()) {
+                    continue;
+                }
+                if let Some((jar, entry)) = jarsource::split_url(&url) {
+                    let read = read_jar_entry_with_timeout(
+                        fs,
+                        &jar,
+                        &entry,
+                        timeout,
+                        config.max_document_bytes,
+                    );
+                    if let Ok((text, _)) = read.await {
+                        files.insert(url, text);
+                    }
+                    continue;
+                }
+                let Ok(path) = url.to_file_path() else {
+                    continue;
+                };
+                let Ok(metadata) =
+                    with_read_timeout(fs, &path, timeout, |fs, path| fs.metadata(path)).await
+                else {
+                    continue;
+                };
+                if metadata.len > config.max_document_bytes {
+                    continue;
+                }
+                if let Ok((text, _)) = read_file_with_timeout(fs, &path, timeout).await {
+                    files.insert(url, text);
+                }
+            }
+        }
+        Self { files }
+    }
+
+    /// The 0-based `line` of `url`, if the file was read and has that line.
+    fn get(&self, url: &Url, line: u32) -> Option<&str> {
+        line_text_at(self.files.get(url)?, line.checked_add(1)?)
+    }
+}
+
+/// UTF-16 span of the identifier (letters, digits, `_`, `$`) covering UTF-16
+/// column `column` of `line`, starting at its first character even when
+/// `column` points into the middle. `None` off an identifier.
+fn identifier_span(line: &str, column: u32) -> Option<(u32, u32)> {
+    let is_identifier = |ch: char| ch.is_alphanumeric() || ch == '_' || ch == '$';
+    let mut chars = Vec::new();
+    let mut offset = 0;
+    for ch in line.chars() {
+        chars.push((offset, ch));
+        offset += ch.len_utf16() as u32;
+    }
+    let index = chars
+        .iter()
+        .position(|&(start, ch)| start <= column && column < start + ch.len_utf16() as u32)?;
+    if !is_identifier(chars[index].1) {
+        return None;
+    }
+    let first = chars[..index]
+        .iter()
+        .rposition(|&(_, ch)| !is_identifier(ch))
+        .map_or(0, |before| before + 1);
+    let end = chars[index..]
+        .iter()
+        .find(|&&(_, ch)| !is_identifier(ch))
+        .map_or(offset, |&(start, _)| start);
+    Some((chars[first].0, end))
+}
+
+/// Whether columns of locators with `path` count from 0 under `zeroBasedColumnsFor`,
+/// judged by the resolved target when there is one.
+fn zero_based_target(path: &str, source_uri: &Url, config: &Config, fs: &dyn FileSystem) -> bool {
+    match resolve_target_url(path, source_uri, config, fs) {
+        Ok(url) => config.zero_based_columns(url.path()),
+        Err(_) => config.zero_based_columns(path),
+    }
+}
+
+/// Whether some mapped position resolved into more than one file.
+fn has_ambiguous_targets(links: &[LocationLink]) -> bool {
+    let mut targets: HashMap<(u32, u32), &Url> = HashMap::new();
+    links.iter().any(|link| {
+        let start = link.target_range.start;
+        let previous = targets.insert((start.line, start.character), &link.target_uri);
+        previous.is_some_and(|previous| *previous != link.target_uri)
+    })
+}
+
+/// Links for Go to Definition at `position`: every mapped target of the annotation there.
+///
+/// With `gotoDedupScope: "file"`, invoking it on the `// @[` trigger collects the
+/// unique destinations of every annotation in the document instead.
+fn definition_links(
+    text: &str,
+    annotations: &[AnnotationSpan],
+    uri: &Url,
+    position: Position,
+    config: &Config,
+    target_lines: &TargetLines,
+    fs: &dyn FileSystem,
+) -> Vec<LocationLink> {
+    let line_starts = compute_line_starts(text);
+    let Some(offset) = position_to_offset(position, text, &line_starts) else {
+        return Vec::new();
+    };
+    let Some(annotation) = annotation_containing(text, annotations, offset) else {
+        return Vec::new();
+    };
+
+    let (summary_start, summary_end) = summary_hover_byte_range(text, annotation, &line_starts);
+    let in_summary = offset >= summary_start && offset < summary_end;
+    if in_summary && config.goto_dedup_scope == DedupScope::File {
+        // One token list for the whole file, so `collect_location_links` dedups
+        // destinations across annotations instead of within each one.
+        let tokens: Vec<ParsedToken> = annotations
+            .iter()
+            .flat_map(|annotation| {
+                parse_tokens_from_annotation(text, annotation, &line_starts, config)
+            })
+            .collect();
+        return collect_location_links(tokens.iter(), uri, config, target_lines, fs);
+    }
+
+    let tokens = parse_tokens_from_annotation(text, annotation, &line_starts, config);
+    if let Some(narrowed) = tokens.iter().find_map(|token| {
+        let first_column = u32::from(!zero_based_target(&token.locator.path, uri, config, fs));
+        token.narrowed_to(offset, first_column)
+    }) {
+        return collect_location_links([&narrowed], uri, config, target_lines, fs);
+    }
+    collect_location_links(tokens.iter(), uri, config, target_lines, fs)
+}
+
+/// Whether `position` is inside one locator token rather than on an annotation's
+/// summary trigger or between its tokens.
+fn caret_on_token(
+    text: &str,
+    annotations: &[AnnotationSpan],
+    position: Position,
+    config: &Config,
+) -> bool {
+    let line_starts = compute_line_starts(text);
+    let Some(offset) = position_to_offset(position, text, &line_starts) else {
+        return false;
+    };
+    annotation_containing(text, annotations, offset).is_some_and(|annotation| {
+        parse_tokens_from_annotation(text, annotation, &line_starts, config)
+            .iter()
+            .any(|token| offset >= token.byte_start && offset < token.byte_end)
+    })
+}
+
+/// Tokens whose targets the links at `position` can point into: those of the
+/// annotation there, or of every annotation on a `gotoDedupScope: "file"` trigger.
+fn link_candidate_tokens(
+    text: &str,
+    annotations: &[AnnotationSpan],
+    position: Position,
+    config: &Config,
+) -> Vec<ParsedToken> {
+    let line_starts = compute_line_starts(text);
+    let Some(offset) = position_to_offset(position, text, &line_starts) else {
+        return Vec::new();
+    };
+    let Some(annotation) = annotation_containing(text, annotations, offset) else {
+        return Vec::new();
+    };
+    let (summary_start, summary_end) = summary_hover_byte_range(text, annotation, &line_starts);
+    if offset >= summary_start
+        && offset < summary_end
+        && config.goto_dedup_scope == DedupScope::File
+    {
+        return annotations
+            .iter()
+            .flat_map(|annotation| {
+                parse_tokens_from_annotation(text, annotation, &line_starts, config)
+            })
+            .collect();
+    }
+    parse_tokens_from_annotation(text, annotation, &line_starts, config)
+}
+
+/// The token whose explicit path portion contains `offset`.
+fn path_token_at_offset(tokens: &[ParsedToken], offset: usize) -> Option<&ParsedToken> {
+    tokens.iter().find(|token| {
+        let (start, end) = token.path_span;
+        offset >= start && offset < end
+    })
+}
+
+/// Range of the path under `position` for `prepareRename`; `None` on the line or
+/// column portions, on inherited-path tokens and outside annotations.
+fn prepare_rename_range(
+    text: &str,
+    annotations: &[AnnotationSpan],
+    position: Position,
+    config: &Config,
+) -> Option<Range> {
+    let line_starts = compute_line_starts(text);
+    let offset = position_to_offset(position, text, &line_starts)?;
+    let annotation = annotation_containing(text, annotations, offset)?;
+    let tokens = parse_tokens_from_annotation(text, annotation, &line_starts, config);
+    let (start, end) = path_token_at_offset(&tokens, offset)?.path_span;
+    Some(Range::new(
+        offset_to_position(start, text, &line_starts),
+        offset_to_position(end, text, &line_starts),
+    ))
+}
+
+/// Edits renaming the locator path under `position` in every token of the document
+/// that resolves to the same file.
+///
+/// Only explicit paths are rewritten: inherited-path tokens (`:13:4`) follow the
+/// explicit token they inherit from, so they pick up the new path automatically.
+fn rename_path_edits(
+    text: &str,
+    annotations: &[AnnotationSpan],
+    uri: &Url,
+    position: Position,
+    new_name: &str,
+    config: &Config,
+    fs: &dyn FileSystem,
+) -> Vec<TextEdit> {
+    let line_starts = compute_line_starts(text);
+    let Some(offset) = position_to_offset(position, text, &line_starts) else {
+        return Vec::new();
+    };
+    let Some(annotation) = annotation_containing(text, annotations, offset) else {
+        return Vec::new();
+    };
+
+    let tokens = parse_tokens_from_annotation(text, annotation, &line_starts, config);
+    let Some(target_token) = path_token_at_offset(&tokens, offset) else {
+        return Vec::new();
+    };
+    let Ok(target_url) = resolve_target_url(&target_token.locator.path, uri, config, fs) else {
+        return Vec::new();
+    };
+
+    let replacement = quote_path_if_needed(new_name);
+    let mut edits = Vec::new();
+    for annotation in annotations {
+        for token in parse_tokens_from_annotation(text, annotation, &line_starts, config) {
+            let (start, end) = token.path_span;
+            if start == end
+                || resolve_target_url(&token.locator.path, uri, config, fs).as_ref()
+                    != Ok(&target_url)
+            {
+                continue;
+            }
+            edits.push(TextEdit::new(
+                Range::new(
+                    offset_to_position(start, text, &line_starts),
+                    offset_to_position(end, text, &line_starts),
+                ),
+                replacement.clone(),
+            ));
+        }
+    }
+
+    edits
+}
+
+/// Parses `[uri, position]` command arguments.
+fn uri_argument(arguments: &[Value]) -> Result<Url> {
+    arguments
+        .first()
+        .and_then(Value::as_str)
+        .and_then(|uri| Url::parse(uri).ok())
+        .ok_or_else(|| Error::invalid_params("expected document URI as argument 1"))
+}
+
+fn uri_position_arguments(arguments: &[Value]) -> Result<(Url, Position)> {
+    let uri = uri_argument(arguments)?;
+    let position = arguments
+        .get(1)
+        .cloned()
+        .and_then(|position| serde_json::from_value::<Position>(position).ok())
+        .ok_or_else(|| Error::invalid_params("expected `{ line, character }` as argument 2"))?;
+    Ok((uri, position))
+}
+
+/// Number of tokens in `annotations` pointing at the same target file, lines and
+/// columns as `locator`, itself included.
+fn same_location_count(
+    locator: &Locator,
+    text: &str,
+    annotations: &[AnnotationSpan],
+    line_starts: &LineIndex,
+    source_uri: &Url,
+    config: &Config,
+    fs: &dyn FileSystem,
+) -> usize {
+    let target =
+        |path: &str| resolve_target_url(path, source_uri, config, fs).map_err(|_| path.to_string());
+    let wanted = (target(&locator.path), locator.line_groups());
+    annotations
+        .iter()
+        .flat_map(|annotation| parse_tokens_from_annotation(text, annotation, line_starts, config))
+        .filter(|token| (target(&token.locator.path), token.locator.line_groups()) == wanted)
+        .count()
+}
+
+/// Whether each target file exists, shared by the tasks of one workspace validation.
+type TargetExistence = Arc<std::sync::Mutex<HashMap<Url, bool>>>;
+
+/// Token count of `text` and the formatted locators among them whose target
+/// cannot be resolved or does not exist.
+fn unresolved_locators(
+    text: &str,
+    uri: &Url,
+    config: &Config,
+    targets: &TargetExistence,
+    fs: &dyn FileSystem,
+) -> (usize, Vec<String>) {
+    let line_starts = compute_line_starts(text);
+    let mut count = 0;
+    let mut unresolved = Vec::new();
+    for annotation in find_annotations(text, config) {
+        for token in parse_tokens_from_annotation(text, &annotation, &line_starts, config) {
+            count += 1;
+            let exists = match resolve_target_url(&token.locator.path, uri, config, fs) {
+                Ok(target) => target_exists(&target, targets, fs),
+                Err(_) => false,
+            };
+            if !exists {
+                unresolved.push(format_locator(&token.locator));
+            }
+        }
+    }
+    (count, unresolved)
+}
+
+fn target_exists(target: &Url, targets: &TargetExistence, fs: &dyn FileSystem) -> bool {
+    let cached = targets
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(target)
+        .copied();
+    if let Some(exists) = cached {
+        return exists;
+    }
+    let exists = match (target.to_file_path(), jarsource::split_url(target)) {
+        (Ok(path), _) => fs.is_file(&path),
+        (Err(()), Some((jar, entry))) => jarsource::has_entry(fs, &jar, &entry),
+        (Err(()), None) => false,
+    };
+    targets
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(target.clone(), exists);
+    exists
+}
+
+/// Start of the first target line of the token under the caret (outside any
+/// token, of the annotation's first token), ignoring its columns.
+fn source_file_location(
+    text: &str,
+    uri: &Url,
+    position: Position,
+    config: &Config,
+    fs: &dyn FileSystem,
+) -> Option<Location> {
+    let line_starts = compute_line_starts(text);
+    let offset = position_to_offset(position, text, &line_starts)?;
+    let annotation = find_annotation_at_offset(text, offset, config)?;
+    let tokens = parse_tokens_from_annotation(text, &annotation, &line_starts, config);
+    let token = tokens
+        .iter()
+        .find(|token| offset >= token.byte_start && offset < token.byte_end)
+        .or(tokens.first())?;
+
+    let line = token
+        .locator
+        .line_groups()
+        .into_iter()
+        .map(|(line, _)| line)
+        .find(|line| *line > 0)?;
+    let target = resolve_target_url(&token.locator.path, uri, config, fs).ok()?;
+    let start = Position::new(line - 1, 0);
+    Some(Location::new(target, Range::new(start, start)))
+}
+
+/// The generated-code range under the caret and its first target. Outside any
+/// token the whole annotation is used.
+fn caret_target(
+    text: &str,
+    uri: &Url,
+    position: Position,
+    config: &Config,
+    target_lines: &TargetLines,
+    fs: &dyn FileSystem,
+) -> Option<(Range, LocationLink)> {
+    let line_starts = compute_line_starts(text);
+    let offset = position_to_offset(position, text, &line_starts)?;
+    let annotation = find_annotation_at_offset(text, offset, config)?;
+    let tokens = parse_tokens_from_annotation(text, &annotation, &line_starts, config);
+
+    let token = tokens
+        .iter()
+        .find(|token| offset >= token.byte_start && offset < token.byte_end);
+    let (source_range, target) = match token {
+        Some(token) => (
+            token.range,
+            collect_location_links(std::iter::once(token), uri, config, target_lines, fs)
+                .into_iter()
+                .next()?,
+        ),
+        None => (
+            Range::new(
+                offset_to_position(annotation.full_start, text, &line_starts),
+                offset_to_position(annotation.full_end, text, &line_starts),
+            ),
+            collect_location_links(tokens.iter(), uri, config, target_lines, fs)
+                .into_iter()
+                .next()?,
+        ),
+    };
+    Some((source_range, target))
+}
+
+/// Distinct resolved target files of every token in `text`, sorted by URI, with
+/// the number of tokens referencing each.
+fn source_file_counts(
+    text: &str,
+    uri: &Url,
+    config: &Config,
+    fs: &dyn FileSystem,
+) -> Vec<(Url, usize)> {
+    let line_starts = compute_line_starts(text);
+    let mut counts: BTreeMap<Url, usize> = BTreeMap::new();
+    for annotation in find_annotations(text, config) {
+        for token in parse_tokens_from_annotation(text, &annotation, &line_starts, config) {
+            for target in resolve_target_urls(&token.locator.path, uri, config, fs) {
+                *counts.entry(target).or_default() += 1;
+            }
+        }
+    }
+    counts.into_iter().collect()
+}
+
+/// Ranges of the tokens in `text` (the document `uri`) whose locator resolves to
+/// `target` and maps to its 1-based `line`.
+fn locator_references(
+    text: &str,
+    uri: &Url,
+    target: &Url,
+    line: u32,
+    config: &Config,
+    fs: &dyn FileSystem,
+) -> Vec<Location> {
+    let line_starts = compute_line_starts(text);
+    let mut locations = Vec::new();
+    for annotation in find_annotations(text, config) {
+        for token in parse_tokens_from_annotation(text, &annotation, &line_starts, config) {
+            let locator = &token.locator;
+            let on_line = if locator.pairs.is_empty() {
+                locator.line == line
+            } else {
+                locator
+                    .pairs
+                    .iter()
+                    .any(|(pair_line, _)| *pair_line == line)
+            };
+            if on_line
+                && resolve_target_urls(&locator.path, uri, config, fs)
+                    .iter()
+                    .any(|resolved| resolved == target)
+            {
+                locations.push(Location::new(uri.clone(), token.range));
+            }
+        }
+    }
+    locations
+}
+
+/// Payload for `firrtl.revealSideBySide`: the generated-code location under the
+/// caret and its first target.
+fn side_by_side_payload(
+    text: &str,
+    uri: &Url,
+    position: Position,
+    config: &Config,
+    target_lines: &TargetLines,
+    fs: &dyn FileSystem,
+) -> Option<Value> {
+    let (source_range, target) = caret_target(text, uri, position, config, target_lines, fs)?;
+    Some(json!({
+        "source": Location::new(uri.clone(), source_range),
+        "target": Location::new(target.target_uri, target.target_selection_range),
+    }))
+}
+
+/// Payload for `firrtl.gotoInNewTab`: the first target under the caret plus a
+/// `newTab` hint asking the client to open it in a new tab instead of reusing one.
+fn new_tab_payload(
+    text: &str,
+    uri: &Url,
+    position: Position,
+    config: &Config,
+    target_lines: &TargetLines,
+    fs: &dyn FileSystem,
+) -> Option<Value> {
+    let (_, target) = caret_target(text, uri, position, config, target_lines, fs)?;
+    Some(json!({
+        "location": Location::new(target.target_uri, target.target_selection_range),
+        "newTab": true,
+    }))
+}
+
+/// Resolution status of one locator token, independent of any LSP session.
+#[derive(Clone, Debug)]
+struct TokenReport {
+    locator: Locator,
+    resolved_path: PathBuf,
+    exists: bool,
+}
+
+#[derive(Clone, Debug)]
+struct AnnotationReport {
+    /// 1-based line of the `@[` in the analyzed text.
+    line: u32,
+    tokens: Vec<TokenReport>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct AnalysisReport {
+    annotations: Vec<AnnotationReport>,
+}
+
+/// Parses every annotation in `text`, the contents of `source_uri`, and resolves
+/// each token the way Go to Definition would, checking the targets through `fs`.
+fn analyze_text(
+    text: &str,
+    source_uri: &Url,
+    config: &Config,
+    fs: &dyn FileSystem,
+) -> AnalysisReport {
+    let line_starts = compute_line_starts(text);
+    let mut report = AnalysisReport::default();
+
+    for annotation in find_annotations(text, config) {
+        let line = offset_to_position(annotation.full_start, text, &line_starts).line + 1;
+        let tokens = parse_tokens_from_annotation(text, &annotation, &line_starts, config)
+            .into_iter()
+            .map(|token| {
+                let (resolved_path, exists) =
+                    match resolve_target_url(&token.locator.path, source_uri, config, fs) {
+                        Ok(target) => match target.to_file_path() {
+                            Ok(path) => {
+                                let exists = fs.is_file(&path);
+                                (path, exists)
+                            }
+                            Err(()) => match jarsource::split_url(&target) {
+                                Some((jar, entry)) => (
+                                    PathBuf::from(target.as_str()),
+                                    jarsource::has_entry(fs, &jar, &entry),
+                                ),
+                                None => (PathBuf::from(target.path()), false),
+                            },
+                        },
+                        Err(
+                            ResolveError::NotAbsolute(path)
+                            | ResolveError::FileNotFound(path)
+                            | ResolveError::ReadError(path, _)
+                            | ResolveError::OutsideAllowedRoots(path),
+                        ) => (path, false),
+                        Err(ResolveError::SourceHasNoParent(_)) => {
+                            (PathBuf::from(&token.locator.path), false)
+                        }
+                    };
+                TokenReport {
+                    exists,
+                    resolved_path,
+                    locator: token.locator,
+                }
+            })
+            .collect();
+        report.annotations.push(AnnotationReport { line, tokens });
+    }
+
+    report
+}
+
+fn workspace_roots_from_params(params: &InitializeParams) -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = params
+        .workspace_folders
+        .iter()
+        .flatten()
+        .filter_map(|folder| folder.uri.to_file_path().ok())
+        .collect();
+
+    #[allow(deprecated)]
+    if roots.is_empty() {
+        if let Some(root) = params
+            .root_uri
+            .as_ref()
+            .and_then(|uri| uri.to_file_path().ok())
+        {
+            roots.push(root);
+        }
+    }
+
+    roots
+}
+
+fn has_annotated_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            ANNOTATED_FILE_EXTENSIONS
+                .iter()
+                .any(|known| extension.eq_ignore_ascii_case(known))
+        })
+}
+
+fn is_excluded_dir(dir: &Path, root: &Path, exclude_dirs: &[String]) -> bool {
+    let Some(name) = dir.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    if DEFAULT_EXCLUDED_DIRS.contains(&name) {
+        return true;
+    }
+
+    let relative = dir.strip_prefix(root).unwrap_or(dir);
+    exclude_dirs.iter().any(|excluded| {
+        let excluded = excluded.trim_end_matches('/');
+        excluded == name || Path::new(excluded) == relative
+    })
+}
+
+/// Lists every file below `roots`, skipping VCS/build directories and `exclude_dirs`.
+///
+/// Entries in `exclude_dirs` match either a directory name anywhere in the tree
+/// or a path relative to the root being walked. Symlinked directories are not
+/// followed. Output is sorted per directory so results are deterministic.
+fn walk_source_roots(
+    roots: &[PathBuf],
+    exclude_dirs: &[String],
+    fs: &dyn FileSystem,
+) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+
+    for root in roots {
+        let mut pending = vec![root.clone()];
+        while let Some(dir) = pending.pop() {
+            let Ok(mut entries) = fs.read_dir(&dir) else {
+                continue;
+            };
+            entries.sort_by(|a, b| a.path.file_name().cmp(&b.path.file_name()));
+
+            let mut subdirs = Vec::new();
+            for entry in entries {
+                if entry.is_dir {
+                    if !is_excluded_dir(&entry.path, root, exclude_dirs) {
+                        subdirs.push(entry.path);
+                    }
+                } else if entry.is_file {
+                    files.push(entry.path);
+                }
+            }
+            pending.extend(subdirs.into_iter().rev());
+        }
+    }
+
+    files
+}
+
+/// Bytes between UTF-16 checkpoints in a [`LineIndex`]; bounds the scan behind
+/// each `offset_to_position` call, however long the line.
+const UTF16_CHECKPOINT_BYTES: usize = 1024;
+
+/// Line starts of a document plus periodic `(byte offset, UTF-16 offset)`
+/// checkpoints, so UTF-16 columns on multi-megabyte single-line files do not
+/// rescan the line from its start. Derefs to the line start offsets.
+struct LineIndex {
+    starts: Vec<usize>,
+    utf16_checkpoints: Vec<(usize, u32)>,
+}
+
+impl Deref for LineIndex {
+    type Target = [usize];
+
+    fn deref(&self) -> &[usize] {
+        &self.starts
+    }
+}
+
+impl LineIndex {
+    /// The last checkpoint at or before `offset`.
+    fn checkpoint_for(&self, offset: usize) -> (usize, u32) {
+        let index = match self
+            .utf16_checkpoints
+            .binary_search_by_key(&offset, |&(byte, _)| byte)
+        {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        };
+        self.utf16_checkpoints[index]
+    }
+
+    /// UTF-16 code units from the start of the document to `offset`, which must
+    /// be a char boundary.
+    fn utf16_offset(&self, text: &str, offset: usize) -> u32 {
+        let (byte, utf16) = self.checkpoint_for(offset);
+        utf16
+            + text[byte..offset]
+                .chars()
+                .map(|ch| ch.len_utf16() as u32)
+                .sum::<u32>()
+    }
+}
+
+/// Byte offsets where each line starts. `\n`, `\r\n` and a lone `\r` (classic
+/// Mac line endings) all terminate a line.
+fn compute_line_starts(text: &str) -> LineIndex {
+    let bytes = text.as_bytes();
+    let mut starts = vec![0];
+    let mut utf16_checkpoints = vec![(0, 0)];
+    let mut next_checkpoint = UTF16_CHECKPOINT_BYTES;
+    let mut utf16: u32 = 0;
+    for (idx, &byte) in bytes.iter().enumerate() {
+        let is_char_start = byte & 0xC0 != 0x80;
+        if is_char_start && idx >= next_checkpoint {
+            utf16_checkpoints.push((idx, utf16));
+            next_checkpoint = idx + UTF16_CHECKPOINT_BYTES;
+        }
+        // Four-byte sequences are the only ones needing a surrogate pair.
+        utf16 += match byte {
+            _ if !is_char_start => 0,
+            0xF0.. => 2,
+            _ => 1,
+        };
+
+        let is_break = byte == b'\n' || (byte == b'\r' && bytes.get(idx + 1) != Some(&b'\n'));
+        if is_break {
+            starts.push(idx + 1);
+        }
+    }
+    LineIndex {
+        starts,
+        utf16_checkpoints,
+    }
+}
+
+/// Converts an LSP position into a byte offset.
+///
+/// A `character` at or beyond the line's UTF-16 length (editors send this for
+/// end-of-line clicks) clamps to the start of the last character of the line's
+/// content, never to the line terminator, so a click after a trailing `]`
+/// still lands inside the annotation.
+fn position_to_offset(position: Position, text: &str, line_starts: &[usize]) -> Option<usize> {
+    let line = position.line as usize;
+    if line >= line_starts.len() {
+        return None;
+    }
+
+    let line_start = line_starts[line];
+    let line_end = if line + 1 < line_starts.len() {
+        line_starts[line + 1]
+    } else {
+        text.len()
+    };
+    let line_text = text[line_start..line_end].trim_end_matches(['\n', '\r']);
+
+    let mut remaining_utf16 = position.character as usize;
+    let mut last_char_start = 0;
+    for (idx, ch) in line_text.char_indices() {
+        let width = ch.len_utf16();
+        if remaining_utf16 < width {
+            return Some(line_start + idx);
+        }
+        remaining_utf16 -= width;
+        last_char_start = idx;
+    }
+
+    Some(line_start + last_char_start)
+}
+
+/// Number of lines in `text`; a trailing line terminator does not start a new line.
+fn line_count(text: &str) -> u32 {
+    let starts = compute_line_starts(text);
+    let trailing_break = text.ends_with(['\n', '\r']);
+    (starts.len() - usize::from(trailing_break)) as u32
+}
+
+fn offset_to_position(offset: usize, text: &str, line_starts: &LineIndex) -> Position {
+    let clamped = offset.min(text.len());
+    let line = match line_starts.binary_search(&clamped) {
+        Ok(index) => index,
+        Err(0) => 0,
+        Err(index) => index - 1,
+    };
+
+    let line_start = line_starts[line];
+    let utf16_col =
+        line_starts.utf16_offset(text, clamped) - line_starts.utf16_offset(text, line_start);
+
+    Position::new(line as u32, utf16_col)
+}
+
+/// Finds the `]` closing an annotation, ignoring brackets inside `"..."` quoted
+/// segments. A backslash escapes the next character inside quotes. A quote still
+/// open at the end of a line is treated as a stray literal and the first `]` wins,
+/// so one unbalanced `"` cannot swallow the following annotations.
+fn find_closing_bracket(inner: &str) -> Option<usize> {
+    find_closing_delimiter(inner, ']')
+}
+
+/// [`find_closing_bracket`] for an arbitrary closing character, e.g. the `)` of `loc(...)`.
+fn find_closing_delimiter(inner: &str, close: char) -> Option<usize> {
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for (idx, ch) in inner.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match ch {
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '\n' if in_quotes => return inner.find(close),
+            _ if ch == close && !in_quotes => return Some(idx),
+            _ => {}
+        }
+    }
+
+    if in_quotes {
+        inner.find(close)
+    } else {
+        None
+    }
+}
+
+fn find_annotations(text: &str, config: &Config) -> Vec<AnnotationSpan> {
+    let mut spans = Vec::new();
+    let mut cursor = 0;
+
+    while let Some(span) = next_annotation(text, cursor, config) {
+        cursor = span.full_end;
+        spans.push(span);
+    }
+
+    spans
+}
+
+/// Opener of an MLIR file location, `loc("A.scala":10:3)`; the inner text
+/// starts at the quoted path.
+const MLIR_LOC_OPENER: &str = "loc(";
+
+/// Offset of the first opener in `text` and whether it is a `loc("` (as opposed
+/// to the bracket `opener`, `None` to skip those). A `loc("` that is the tail
+/// of a longer identifier such as `alloc("` does not count.
+fn find_next_opener(text: &str, opener: Option<&str>, mlir: bool) -> Option<(usize, bool)> {
+    let sigil = opener
+        .and_then(|opener| opener.chars().next())
+        .unwrap_or('l');
+    let mut from = 0;
+    while let Some(relative) = text[from..].find([sigil, 'l']) {
+        let index = from + relative;
+        let rest = &text[index..];
+        if opener.is_some_and(|opener| rest.starts_with(opener)) {
+            return Some((index, false));
+        }
+        if mlir
+            && rest.starts_with("loc(\"")
+            && !text[..index]
+                .chars()
+                .next_back()
+                .is_some_and(|ch| ch.is_alphanumeric() || ch == '_')
+        {
+            return Some((index, true));
+        }
+        from = index + rest.chars().next().map_or(1, char::len_utf8);
+    }
+    None
+}
+
+/// The first annotation starting at or after byte `cursor`: an `@[...]` block,
+/// a `loc("...")` location when `locationSyntax` is `all`, a bare
+/// `"path:line:col"` string when `bareLocatorStrings` is on, or a fullwidth
+/// `＠［...］` block when `unicodeBrackets` is on.
+fn next_annotation(text: &str, cursor: usize, config: &Config) -> Option<AnnotationSpan> {
+    let ascii = next_ascii_annotation(text, cursor, config);
+    if !config.unicode_brackets {
+        return ascii;
+    }
+    // A fullwidth block can only come first when its opener precedes the ASCII one.
+    let search_end = ascii.as_ref().map_or(text.len(), |span| span.full_start);
+    next_fullwidth_annotation(text, cursor, search_end, config).or(ascii)
+}
+
+/// Fullwidth opener and closer recognized with `unicodeBrackets`.
+const FULLWIDTH_OPENER: &str = "＠［";
+const FULLWIDTH_CLOSER: char = '］';
+
+/// The first `＠［...］` block whose opener lies in `cursor..search_end`; byte
+/// offsets account for the multibyte brackets.
+fn next_fullwidth_annotation(
+    text: &str,
+    mut cursor: usize,
+    search_end: usize,
+    config: &Config,
+) -> Option<AnnotationSpan> {
+    while let Some(relative) = text[cursor..search_end].find(FULLWIDTH_OPENER) {
+        let full_start = cursor + relative;
+        let inner_start = full_start + FULLWIDTH_OPENER.len();
+        cursor = inner_start;
+        if is_escaped_opener(text, full_start) {
+            continue;
+        }
+        if config.annotation_require_comment_prefix {
+            let line_start = text[..full_start]
+                .rfind(['\n', '\r'])
+                .map_or(0, |index| index + 1);
+            if comment_prefix_start(text, full_start, line_start).is_none() {
+                continue;
+            }
+        }
+        let relative_end = find_closing_delimiter(&text[inner_start..], FULLWIDTH_CLOSER)?;
+        let inner_end = inner_start + relative_end;
+        if text[inner_start..inner_end].contains(FULLWIDTH_OPENER) {
+            continue;
+        }
+        return Some(AnnotationSpan {
+            full_start,
+            full_end: inner_end + FULLWIDTH_CLOSER.len_utf8(),
+            inner_start,
+            inner_end,
+        });
+    }
+    None
+}
+
+/// [`next_annotation`] without the fullwidth brackets.
+fn next_ascii_annotation(text: &str, cursor: usize, config: &Config) -> Option<AnnotationSpan> {
+    if !config.bare_locator_strings {
+        return next_delimited_annotation(text, cursor, config);
+    }
+    let Some(bare) = next_bare_locator_string(text, cursor) else {
+        return next_delimited_annotation(text, cursor, config);
+    };
+    // Only look for a delimited annotation when an opener precedes the string,
+    // so files full of bare strings are still scanned once.
+    let before = &text[cursor..bare.full_start];
+    if !before.contains(&config.annotation_opener()) && !before.contains(MLIR_LOC_OPENER) {
+        return Some(bare);
+    }
+    match next_delimited_annotation(text, cursor, config) {
+        Some(delimited) if delimited.full_start < bare.full_start => Some(delimited),
+        _ => Some(bare),
+    }
+}
+
+/// The first double-quoted string at or after `cursor` that is exactly one
+/// `path:line:col` or `path:line:{col,...}` locator, as in JSON attribute files.
+fn next_bare_locator_string(text: &str, mut cursor: usize) -> Option<AnnotationSpan> {
+    while let Some(relative) = text[cursor..].find('"') {
+        let open = cursor + relative;
+        let inner_start = open + 1;
+        let close = inner_start + text[inner_start..].find(['"', '\n'])?;
+        if text[close..].starts_with('\n') {
+            cursor = close + 1;
+            continue;
+        }
+        if is_bare_locator(&text[inner_start..close]) {
+            return Some(AnnotationSpan {
+                full_start: open,
+                full_end: close + 1,
+                inner_start,
+                inner_end: close,
+            });
+        }
+        cursor = close + 1;
+    }
+    None
+}
+
+/// Strict shape check for bare locator strings: a path whose file name has an
+/// extension and no delimiter characters, a decimal line, and decimal columns.
+fn is_bare_locator(candidate: &str) -> bool {
+    let Some((rest, columns)) = candidate.rsplit_once(':') else {
+        return false;
+    };
+    let Some((path, line)) = rest.rsplit_once(':') else {
+        return false;
+    };
+    let digits = |text: &str| !text.is_empty() && text.bytes().all(|byte| byte.is_ascii_digit());
+    let columns_ok = match columns
+        .strip_prefix('{')
+        .and_then(|set| set.strip_suffix('}'))
+    {
+        Some(set) => set.split(',').all(|column| digits(column.trim())),
+        None => digits(columns),
+    };
+    let file_name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    let has_extension = file_name.rsplit_once('.').is_some_and(|(stem, extension)| {
+        !stem.is_empty()
+            && !extension.is_empty()
+            && extension.bytes().all(|b| b.is_ascii_alphanumeric())
+    });
+    columns_ok
+        && digits(line)
+        && has_extension
+        && !path.contains(|ch: char| ch.is_whitespace() || "\"'[](){},:@".contains(ch))
+}
+
+fn next_delimited_annotation(
+    text: &str,
+    mut cursor: usize,
+    config: &Config,
+) -> Option<AnnotationSpan> {
+    let opener = config.annotation_opener();
+    // Once an opener finds no closer at all, later openers of that kind cannot either.
+    let mut brackets_left = true;
+    let mut parens_left = config.location_syntax == LocationSyntax::All;
+    loop {
+        let (relative_start, is_mlir) = if parens_left {
+            find_next_opener(&text[cursor..], brackets_left.then_some(&opener), true)?
+        } else if brackets_left {
+            (text[cursor..].find(&opener)?, false)
+        } else {
+            return None;
+        };
+        let full_start = cursor + relative_start;
+
+        if is_mlir {
+            let inner_start = full_start + MLIR_LOC_OPENER.len();
+            let Some(relative_end) = find_closing_delimiter(&text[inner_start..], ')') else {
+                parens_left = false;
+                continue;
+            };
+            let inner_end = inner_start + relative_end;
+            return Some(AnnotationSpan {
+                full_start,
+                full_end: inner_end + 1,
+                inner_start,
+                inner_end,
+            });
+        }
+
+        let inner_start = full_start + opener.len();
+        if is_escaped_opener(text, full_start) {
+            cursor = inner_start;
+            continue;
+        }
+        if config.annotation_require_comment_prefix {
+            let line_start = text[..full_start]
+                .rfind(['\n', '\r'])
+                .map_or(0, |index| index + 1);
+            if comment_prefix_start(text, full_start, line_start).is_none() {
+                cursor = inner_start;
+                continue;
+            }
+        }
+
+        let Some(relative_end) = find_closing_bracket(&text[inner_start..]) else {
+            brackets_left = false;
+            continue;
+        };
+        let inner_end = inner_start + relative_end;
+        // Another opener before the `]` means this one was never closed; let the
+        // later annotation keep its bracket.
+        if text[inner_start..inner_end]
+            .match_indices(&opener)
+            .any(|(index, _)| !is_escaped_opener(text, inner_start + index))
+        {
+            cursor = inner_start;
+            continue;
+        }
+        return Some(AnnotationSpan {
+            full_start,
+            full_end: inner_end + 1,
+            inner_start,
+            inner_end,
+        });
+    }
+}
+
+/// An `@[` that no `]` closes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct UnterminatedAnnotation {
+    start: usize,
+    /// Where the missing `]` most likely belongs: after the last locator text
+    /// before the end of the line or the next `@[`.
+    fix_offset: usize,
+}
+
+/// Whether the `@[` at `start` is written `\@[`, as literal text rather than an annotation.
+fn is_escaped_opener(text: &str, start: usize) -> bool {
+    text[..start].ends_with('\\')
+}
+
+/// Openers in `text` that are not the start of one of `annotations`.
+fn find_unterminated_annotations(
+    text: &str,
+    annotations: &[AnnotationSpan],
+    config: &Config,
+) -> Vec<UnterminatedAnnotation> {
+    let opener = config.annotation_opener();
+    let mut unterminated = Vec::new();
+    let mut spans = annotations.iter().peekable();
+    for (start, _) in text.match_indices(&opener) {
+        while spans.peek().is_some_and(|span| span.full_end <= start) {
+            spans.next();
+        }
+        if spans.peek().is_some_and(|span| span.full_start <= start)
+            || is_escaped_opener(text, start)
+        {
+            continue;
+        }
+
+        let line_start = text[..start]
+            .rfind(['\n', '\r'])
+            .map_or(0, |index| index + 1);
+        if config.annotation_require_comment_prefix
+            && comment_prefix_start(text, start, line_start).is_none()
+        {
+            continue;
+        }
+
+        let inner_start = start + opener.len();
+        let rest = &text[inner_start..];
+        let limit = [rest.find(['\n', '\r']), rest.find(&opener)]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(rest.len());
+        let locator_text = rest[..limit].trim_end();
+        let locator_text = locator_text
+            .strip_suffix("//")
+            .map_or(locator_text, str::trim_end);
+        unterminated.push(UnterminatedAnnotation {
+            start,
+            fix_offset: inner_start + locator_text.len(),
+        });
+    }
+    unterminated
+}
+
+fn unterminated_diagnostic(
+    text: &str,
+    line_starts: &LineIndex,
+    unterminated: &UnterminatedAnnotation,
+    config: &Config,
+) -> Diagnostic {
+    Diagnostic {
+        range: Range::new(
+            offset_to_position(unterminated.start, text, line_starts),
+            offset_to_position(unterminated.fix_offset, text, line_starts),
+        ),
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some(DIAGNOSTIC_SOURCE.to_string()),
+        message: format!(
+            "unterminated locator: `{}` has no closing `]`",
+            config.annotation_opener()
+        ),
+        ..Diagnostic::default()
+    }
+}
+
+/// Quick fixes for malformed annotations overlapping `range`: currently
+/// inserting the missing `]` of an unterminated `@[`.
+fn malformed_annotation_fixes(
+    text: &str,
+    annotations: &[AnnotationSpan],
+    uri: &Url,
+    range: Range,
+    config: &Config,
+) -> Vec<CodeActionOrCommand> {
+    let line_starts = compute_line_starts(text);
+    find_unterminated_annotations(text, annotations, config)
+        .iter()
+        .map(|unterminated| unterminated_diagnostic(text, &line_starts, unterminated, config))
+        .filter(|diagnostic| {
+            diagnostic.range.start <= range.end && range.start <= diagnostic.range.end
+        })
+        .map(|diagnostic| {
+            let insert_at = diagnostic.range.end;
+            CodeActionOrCommand::CodeAction(CodeAction {
+                title: "Insert missing `]`".to_string(),
+                kind: Some(CodeActionKind::QUICKFIX),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(HashMap::from([(
+                        uri.clone(),
+                        vec![TextEdit::new(
+                            Range::new(insert_at, insert_at),
+                            "]".to_string(),
+                        )],
+                    )])),
+                    ..WorkspaceEdit::default()
+                }),
+                diagnostics: Some(vec![diagnostic]),
+                is_preferred: Some(true),
+                ..CodeAction::default()
+            })
+        })
+        .collect()
+}
+
+/// Edit replacing the inner text of the annotation at `position` with its
+/// canonical form: each token as [`format_locator`] prints it, joined by `, `,
+/// with inherited paths kept inherited. `None` when the text is already
+/// canonical or some token does not parse, since rewriting would drop it.
+fn canonical_annotation_edit(
+    text: &str,
+    annotations: &[AnnotationSpan],
+    position: Position,
+    config: &Config,
+) -> Option<TextEdit> {
+    let line_starts = compute_line_starts(text);
+    let offset = position_to_offset(position, text, &line_starts)?;
+    let annotation = annotation_containing(text, annotations, offset)?;
+    let inner = &text[annotation.inner_start..annotation.inner_end];
+    let tokens = parse_tokens_from_annotation(text, annotation, &line_starts, config);
+    if tokens.is_empty() || tokens.len() != tokenize_locators(inner).tokens.len() {
+        return None;
+    }
+
+    let canonical = tokens
+        .iter()
+        .map(|token| {
+            let quoted_path = quote_path_if_needed(&token.locator.path);
+            let formatted = format_locator(&token.locator);
+            let rest = &formatted[token.locator.path.len()..];
+            let path = if token.path_span.0 == token.path_span.1 {
+                ""
+            } else {
+                quoted_path.as_str()
+            };
+            match &token.locator.tag {
+                Some(tag) => format!("{path}{rest} <{tag}>"),
+                None => format!("{path}{rest}"),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    if canonical == inner {
+        return None;
+    }
+    Some(TextEdit::new(
+        Range::new(
+            offset_to_position(annotation.inner_start, text, &line_starts),
+            offset_to_position(annotation.inner_end, text, &line_starts),
+        ),
+        canonical,
+    ))
+}
+
+/// Byte span of one applied edit: where it starts, where the replaced text
+/// ended before the edit, and where the inserted text ends after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct EditSpan {
+    start: usize,
+    old_end: usize,
+    new_end: usize,
+}
+
+/// Applies one `didChange` event to `text`. Returns the edited span for ranged
+/// (incremental) changes and `None` when the whole document was replaced.
+fn apply_content_change(
+    text: &mut String,
+    change: &TextDocumentContentChangeEvent,
+) -> Option<EditSpan> {
+    let Some(range) = change.range else {
+        text.clone_from(&change.text);
+        return None;
+    };
+
+    let line_starts = compute_line_starts(text);
+    let start = edit_offset(range.start, text, &line_starts);
+    let old_end = edit_offset(range.end, text, &line_starts).max(start);
+    text.replace_range(start..old_end, &change.text);
+    Some(EditSpan {
+        start,
+        old_end,
+        new_end: start + change.text.len(),
+    })
+}
+
+/// Converts an edit position into a byte offset. Unlike `position_to_offset`,
+/// a position past the end of a line lands after its last character (so text can
+/// be appended), and a line past the end lands at the end of the document.
+fn edit_offset(position: Position, text: &str, line_starts: &[usize]) -> usize {
+    let line = position.line as usize;
+    let Some(&line_start) = line_starts.get(line) else {
+        return text.len();
+    };
+    let line_end = line_starts.get(line + 1).copied().unwrap_or(text.len());
+    let line_text = text[line_start..line_end].trim_end_matches(['\n', '\r']);
+
+    let mut remaining_utf16 = position.character as usize;
+    for (idx, ch) in line_text.char_indices() {
+        if remaining_utf16 < ch.len_utf16() {
+            return line_start + idx;
+        }
+        remaining_utf16 -= ch.len_utf16();
+    }
+    line_start + line_text.len()
+}
+
+/// Updates the annotation index of a document after `edit` without rescanning
+/// all of it.
+///
+/// Annotations ending before the edit are kept as they are. Scanning resumes
+/// after the last of them and stops as soon as it meets an annotation past the
+/// edit that matches an old one shifted by the edit delta; from there on the
+/// scan would repeat, so the remaining old annotations are shifted instead.
+fn reindex_annotations(
+    text: &str,
+    annotations: &[AnnotationSpan],
+    edit: EditSpan,
+    config: &Config,
+) -> Vec<AnnotationSpan> {
+    let shift = |span: &AnnotationSpan| AnnotationSpan {
+        full_start: span.full_start - edit.old_end + edit.new_end,
+        full_end: span.full_end - edit.old_end + edit.new_end,
+        inner_start: span.inner_start - edit.old_end + edit.new_end,
+        inner_end: span.inner_end - edit.old_end + edit.new_end,
+    };
+
+    let kept = annotations.partition_point(|span| span.full_end <= edit.start);
+    let later = annotations.partition_point(|span| span.full_start < edit.old_end);
+    let mut reindexed = annotations[..kept].to_vec();
+    let mut old_later = annotations[later..].iter().peekable();
+    let mut cursor = reindexed.last().map_or(0, |span| span.full_end);
+
+    while let Some(span) = next_annotation(text, cursor, config) {
+        if span.full_start >= edit.new_end {
+            while old_later
+                .peek()
+                .is_some_and(|old| shift(old).full_start < span.full_start)
+            {
+                old_later.next();
+            }
+            if old_later.peek().is_some_and(|old| shift(old) == span) {
+                reindexed.extend(old_later.map(shift));
+                return reindexed;
+            }
+        }
+        cursor = span.full_end;
+        reindexed.push(span);
+    }
+
+    reindexed
+}
+
+fn find_annotation_at_offset(text: &str, offset: usize, config: &Config) -> Option<AnnotationSpan> {
+    annotation_containing(text, &find_annotations(text, config), offset).cloned()
+}
+
+/// The span among `annotations` (those of `text`) that contains `offset`.
+fn annotation_containing<'a>(
+    text: &str,
+    annotations: &'a [AnnotationSpan],
+    offset: usize,
+) -> Option<&'a AnnotationSpan> {
+    annotations
+        .iter()
+        .find(|span| annotation_contains(text, span, offset))
+}
+
+/// Whether `offset` is inside `span` or on the `//` / `info =` prefix its
+/// summary hover covers.
+fn annotation_contains(text: &str, span: &AnnotationSpan, offset: usize) -> bool {
+    if offset >= span.full_end {
+        return false;
+    }
+    if offset >= span.full_start {
+        return true;
+    }
+    let line_start = text[..span.full_start]
+        .rfind(['\n', '\r'])
+        .map_or(0, |index| index + 1);
+    offset >= line_start
+        && summary_prefix_start(text, span.full_start, line_start)
+            .is_some_and(|start| offset >= start)
+}
+
+/// Splits the inner text of an annotation on top-level commas: not inside `{}`,
+/// not inside a quoted path, and not escaped as `\,`.
+fn split_locator_tokens(inner: &str) -> Vec<(usize, usize)> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut brace_depth = 0usize;
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for (idx, ch) in inner.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match ch {
+            '\\' if in_quotes || inner[idx + 1..].starts_with(',') => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            _ if in_quotes => {}
+            '{' => brace_depth += 1,
+            '}' => {
+                brace_depth = brace_depth.saturating_sub(1);
+            }
+            ',' if brace_depth == 0 => {
+                result.push((start, idx));
+                start = idx + ch.len_utf8();
+            }
+            _ => {}
+        }
+    }
+
+    if start <= inner.len() {
+        result.push((start, inner.len()));
+    }
+
+    result
+}
+
+/// Comma-separated slots of an annotation's inner text, split into trimmed
+/// token ranges and the ranges of slots holding nothing but whitespace.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct TokenSlots {
+    tokens: Vec<(usize, usize)>,
+    empty: Vec<(usize, usize)>,
+}
+
+fn tokenize_locators(inner: &str) -> TokenSlots {
+    let mut slots = TokenSlots::default();
+    for (raw_start, raw_end) in split_locator_tokens(inner) {
+        let raw = &inner[raw_start..raw_end];
+        let leading = raw.len() - raw.trim_start().len();
+        let trailing = raw.len() - raw.trim_end().len();
+        if leading + trailing >= raw.len() {
+            slots.empty.push((raw_start, raw_end));
+        } else {
+            slots.tokens.push((raw_start + leading, raw_end - trailing));
+        }
+    }
+    slots
+}
+
+/// Byte ranges of empty slots between separators, like the middle of
+/// `A:1:2,,B:3:4`. A wholly empty `@[]` has no separators and reports nothing.
+fn find_empty_tokens(text: &str, annotation: &AnnotationSpan) -> Vec<(usize, usize)> {
+    let inner = &text[annotation.inner_start..annotation.inner_end];
+    let slots = tokenize_locators(inner);
+    if slots.tokens.len() + slots.empty.len() < 2 {
+        return Vec::new();
+    }
+    slots
+        .empty
+        .into_iter()
+        .map(|(start, end)| (annotation.inner_start + start, annotation.inner_start + end))
+        .collect()
+}
+
+fn empty_token_diagnostic(text: &str, line_starts: &LineIndex, span: (usize, usize)) -> Diagnostic {
+    Diagnostic {
+        range: Range::new(
+            offset_to_position(span.0, text, line_starts),
+            offset_to_position(span.1, text, line_starts),
+        ),
+        severity: Some(DiagnosticSeverity::WARNING),
+        source: Some(DIAGNOSTIC_SOURCE.to_string()),
+        message: "empty locator token between `,` separators".to_string(),
+        ..Diagnostic::default()
+    }
+}
+
+/// Byte ranges of leading tokens like `:10:3` that leave out the path before any
+/// token has named one. Parsing drops them, since there is nothing to inherit.
+fn find_orphan_tokens(
+    text: &str,
+    annotation: &AnnotationSpan,
+    line_starts: &LineIndex,
+    config: &Config,
+) -> Vec<(usize, usize)> {
+    let inner = &text[annotation.inner_start..annotation.inner_end];
+    let base_line = annotation_base_line(text, annotation, line_starts, config);
+    let mut orphans = Vec::new();
+    for (start, end) in tokenize_locators(inner).tokens {
+        let raw = &inner[start..end];
+        if parse_locator_token(raw, None, base_line).is_some() {
+            break;
+        }
+        if parse_locator_token(raw, Some(""), base_line).is_some_and(|(_, inherited)| inherited) {
+            orphans.push((annotation.inner_start + start, annotation.inner_start + end));
+        }
+    }
+    orphans
+}
+
+fn orphan_token_diagnostic(
+    text: &str,
+    line_starts: &LineIndex,
+    span: (usize, usize),
+) -> Diagnostic {
+    Diagnostic {
+        range: Range::new(
+            offset_to_position(span.0, text, line_starts),
+            offset_to_position(span.1, text, line_starts),
+        ),
+        severity: Some(DiagnosticSeverity::WARNING),
+        source: Some(DIAGNOSTIC_SOURCE.to_string()),
+        message: "first locator has no path to inherit".to_string(),
+        ..Diagnostic::default()
+    }
+}
+
+/// Parses digits in `radix`, allowing `_` separators after the first digit like
+/// Rust numeric literals (`1_234`). Signs are rejected.
+fn parse_number(digits: &str, radix: u32) -> Option<u32> {
+    if !digits.chars().next()?.is_digit(radix) {
+        return None;
+    }
+    u32::from_str_radix(&digits.replace('_', ""), radix).ok()
+}
+
+/// Parses one column number: decimal, or hex/octal/binary with a `0x`/`0o`/`0b` prefix.
+fn parse_column_number(column_text: &str) -> Option<u32> {
+    let column_text = column_text.trim();
+    let prefixed = |prefix: &str| {
+        column_text
+            .get(..2)
+            .filter(|head| head.eq_ignore_ascii_case(prefix))
+            .map(|_| &column_text[2..])
+    };
+    if let Some(digits) = prefixed("0x") {
+        parse_number(digits, 16)
+    } else if let Some(digits) = prefixed("0o") {
+        parse_number(digits, 8)
+    } else if let Some(digits) = prefixed("0b") {
+        parse_number(digits, 2)
+    } else {
+        parse_number(column_text, 10)
+    }
+}
+
+fn parse_columns(columns_text: &str) -> Option<Vec<u32>> {
+    let trimmed = columns_text.trim();
+    if trimmed.is_empty() {
+        return None;
+    }
+
+    if trimmed.starts_with('{') && trimmed.ends_with('}') {
+        let inner = &trimmed[1..trimmed.len() - 1];
+        let columns: Vec<u32> = inner.split(',').filter_map(parse_column_number).collect();
+        if columns.is_empty() {
+            None
+        } else {
+            Some(columns)
+        }
+    } else {
+        parse_column_number(trimmed).map(|column| vec![column])
+    }
+}
+
+/// Parses a locator line number.
+///
+/// Besides absolute lines, `+N`/`-N` are offsets from `base_line` (the
+/// `relativeLineBase` option, or else the annotation's own 1-based line).
+/// An offset reaching line 0 or below is rejected.
+fn parse_line(line_text: &str, base_line: u32) -> Option<u32> {
+    let line_text = line_text.trim();
+    if let Some(offset) = line_text.strip_prefix('+') {
+        base_line.checked_add(parse_number(offset, 10)?)
+    } else if let Some(offset) = line_text.strip_prefix('-') {
+        base_line
+            .checked_sub(parse_number(offset, 10)?)
+            .filter(|line| *line > 0)
+    } else {
+        parse_number(line_text, 10)
+    }
+}
+
+/// Parses the `{10:3, 12:5}` pair-set form into `(line, column)` pairs.
+fn parse_line_column_pairs(pairs_text: &str, base_line: u32) -> Option<Vec<(u32, u32)>> {
+    let inner = pairs_text.trim().strip_prefix('{')?.strip_suffix('}')?;
+    let pairs = inner
+        .split(',')
+        .map(|pair| {
+            let (line, column) = pair.trim().split_once(':')?;
+            Some((parse_line(line, base_line)?, parse_column_number(column)?))
+        })
+        .collect::<Option<Vec<(u32, u32)>>>()?;
+    if pairs.is_empty() {
+        None
+    } else {
+        Some(pairs)
+    }
+}
+
+/// Strips the quotes of a `"..."` path, so paths may contain `]`, `:` or spaces.
+fn unquote_path(path_text: &str) -> &str {
+    path_text
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .unwrap_or(path_text)
+}
+
+fn resolve_token_path(path_text: &str, last_path: Option<&str>) -> Option<(String, bool)> {
+    if path_text.is_empty() {
+        Some((last_path?.to_string(), true))
+    } else {
+        let unquoted = unquote_path(path_text);
+        if unquoted.len() == path_text.len() {
+            Some((path_text.replace("\\,", ","), false))
+        } else {
+            // Quoted paths escape their own quotes, as `quote_path_if_needed` writes them.
+            Some((unquoted.replace("\\\"", "\""), false))
+        }
+    }
+}
+
+/// The raw (possibly quoted, possibly empty) path portion at the start of a token.
+fn locator_path_text(token_text: &str) -> Option<&str> {
+    let (trimmed, _) = split_locator_tag(token_text);
+    if let Some(brace) = trimmed.rfind('{').filter(|_| trimmed.ends_with('}')) {
+        if trimmed[brace..].contains(':') {
+            return trimmed[..brace].strip_suffix(':');
+        }
+    }
+
+    let (line_colon, _) = locator_field_colons(trimmed)?;
+    Some(&trimmed[..line_colon])
+}
+
+/// Offsets of the colons opening the line and the column field of a single-line
+/// token (tag already split off). Pairs of adjacent colons are tried from the
+/// right, and one is only taken when the text after it is column-shaped and the
+/// text between is line-shaped, so colons of the path (a `host:8080` or `C:`)
+/// are never read as fields.
+fn locator_field_colons(token_text: &str) -> Option<(usize, usize)> {
+    let colons: Vec<usize> = token_text
+        .match_indices(':')
+        .map(|(index, _)| index)
+        .collect();
+    colons
+        .windows(2)
+        .rev()
+        .map(|pair| (pair[0], pair[1]))
+        .find(|&(line_colon, last_colon)| {
+            is_line_shaped(&token_text[line_colon + 1..last_colon])
+                && parse_columns(&token_text[last_colon + 1..]).is_some()
+        })
+}
+
+/// Whether `text` has the shape of a line field: digits with an optional `+`/`-`.
+fn is_line_shaped(text: &str) -> bool {
+    let text = text.trim();
+    parse_number(text.strip_prefix(['+', '-']).unwrap_or(text), 10).is_some()
+}
+
+/// Spans, relative to `token_text`, of the line number and of each column number
+/// of a single-line token; `None` for the `{line:col}` pair form.
+fn locator_number_spans(token_text: &str) -> Option<(ByteSpan, Vec<ByteSpan>)> {
+    let (trimmed, _) = split_locator_tag(token_text);
+    if let Some(brace) = trimmed.rfind('{').filter(|_| trimmed.ends_with('}')) {
+        if trimmed[brace..].contains(':') {
+            return None;
+        }
+    }
+
+    let (line_colon, last_colon) = locator_field_colons(trimmed)?;
+    let columns_start = last_colon + 1;
+    let columns_text = &trimmed[columns_start..];
+    let (list_start, list) = match columns_text
+        .strip_prefix('{')
+        .and_then(|rest| rest.strip_suffix('}'))
+    {
+        Some(list) => (columns_start + 1, list),
+        None => (columns_start, columns_text),
+    };
+
+    let mut column_spans = Vec::new();
+    let mut part_start = list_start;
+    for part in list.split(',') {
+        let leading = part.len() - part.trim_start().len();
+        let number = part.trim();
+        if !number.is_empty() {
+            let start = part_start + leading;
+            column_spans.push((start, start + number.len()));
+        }
+        part_start += part.len() + 1;
+    }
+    Some(((line_colon + 1, last_colon), column_spans))
+}
+
+/// Quotes a replacement path when it contains characters that delimit locators.
+fn quote_path_if_needed(path: &str) -> String {
+    if path.contains([',', ']', '{', '}', '"']) || path.contains(char::is_whitespace) {
+        format!("\"{}\"", path.replace('"', "\\\""))
+    } else {
+        path.to_string()
+    }
+}
+
+/// Splits a trailing `<kind>` tag off a locator token, as in `A.scala:10:3 <assert>`.
+fn split_locator_tag(token_text: &str) -> (&str, Option<String>) {
+    let Some(open) = token_text
+        .strip_suffix('>')
+        .and_then(|rest| rest.rfind('<'))
+    else {
+        return (token_text, None);
+    };
+    let tag = token_text[open + 1..token_text.len() - 1].trim();
+    if tag.is_empty() {
+        return (token_text, None);
+    }
+    (token_text[..open].trim_end(), Some(tag.to_string()))
+}
+
+fn parse_locator_token(
+    token_text: &str,
+    last_path: Option<&str>,
+    base_line: u32,
+) -> Option<(Locator, bool)> {
+    let (trimmed, tag) = split_locator_tag(token_text.trim());
+    if trimmed.is_empty() {
+        return None;
+    }
+
+    if let Some(brace) = trimmed.rfind('{').filter(|_| trimmed.ends_with('}')) {
+        let set_text = &trimmed[brace..];
+        if set_text.contains(':') {
+            let path_text = trimmed[..brace].strip_suffix(':')?;
+            let pairs = parse_line_column_pairs(set_text, base_line)?;
+            let (path, used_inherited_path) = resolve_token_path(path_text, last_path)?;
+            let line = pairs[0].0;
+            let columns = pairs
+                .iter()
+                .filter(|(pair_line, _)| *pair_line == line)
+                .map(|(_, column)| *column)
+                .collect();
+            return Some((
+                Locator {
+                    path,
+                    line,
+                    columns,
+                    pairs,
+                    tag,
+                },
+                used_inherited_path,
+            ));
+        }
+    }
+
+    let (line_colon, last_colon) = locator_field_colons(trimmed)?;
+    let path_text = &trimmed[..line_colon];
+    let line = parse_line(&trimmed[line_colon + 1..last_colon], base_line)?;
+    let columns = parse_columns(&trimmed[last_colon + 1..])?;
+
+    let (path, used_inherited_path) = resolve_token_path(path_text, last_path)?;
+
+    Some((
+        Locator {
+            path,
+            line,
+            columns,
+            pairs: Vec::new(),
+            tag,
+        },
+        used_inherited_path,
+    ))
+}
+
+/// Line that relative `+N`/`-N` lines count from: `relativeLineBase`, or else the
+/// 1-based line the annotation sits on.
+fn annotation_base_line(
+    text: &str,
+    annotation: &AnnotationSpan,
+    line_starts: &LineIndex,
+    config: &Config,
+) -> u32 {
+    config
+        .relative_line_base
+        .unwrap_or_else(|| offset_to_position(annotation.full_start, text, line_starts).line + 1)
+}
+
+fn parse_tokens_from_annotation(
+    text: &str,
+    annotation: &AnnotationSpan,
+    line_starts: &LineIndex,
+    config: &Config,
+) -> Vec<ParsedToken> {
+    let inner = &text[annotation.inner_start..annotation.inner_end];
+    let mut parsed = Vec::new();
+    let mut last_path: Option<String> = None;
+    let mut last_token_text: Option<&str> = None;
+    let base_line = annotation_base_line(text, annotation, line_starts, config);
+
+    for (token_start, token_end) in tokenize_locators(inner).tokens {
+        let raw = &inner[token_start..token_end];
+        if config.collapse_duplicate_tokens && last_token_text == Some(raw) {
+            continue;
+        }
+        let token_text = raw.to_string();
+
+        let Some((locator, used_inherited_path)) =
+            parse_locator_token(&token_text, last_path.as_deref(), base_line)
+        else {
+            continue;
+        };
+
+        if !used_inherited_path {
+            last_path = Some(locator.path.clone());
+        }
+        last_token_text = Some(raw);
+
+        let byte_start = annotation.inner_start + token_start;
+        let byte_end = annotation.inner_start + token_end;
+        let path_len = locator_path_text(&token_text).map_or(0, str::len);
+        let absolute = |(start, end): ByteSpan| (byte_start + start, byte_start + end);
+        let (line_span, column_spans) = match locator_number_spans(&token_text) {
+            Some((line_span, column_spans)) => (
+                Some(absolute(line_span)),
+                column_spans
+                    .into_iter()
+                    .filter_map(|span| {
+                        let column = parse_column_number(&token_text[span.0..span.1])?;
+                        Some((absolute(span), column))
+                    })
+                    .collect(),
+            ),
+            None => (None, Vec::new()),
+        };
+
+        parsed.push(ParsedToken {
+            byte_start,
+            byte_end,
+            range: Range::new(
+                offset_to_position(byte_start, text, line_starts),
+                offset_to_position(byte_end, text, line_starts),
+            ),
+            locator,
+            path_span: (byte_start, byte_start + path_len),
+            line_span,
+            column_spans,
+        });
+    }
+
+    parsed
+}
+
+/// `format_locator` for display, with `stripDisplayPrefix` removed from the path.
+fn display_locator(locator: &Locator, config: &Config) -> String {
+    format_locator(&Locator {
+        path: display_path(&locator.path, config).to_string(),
+        ..locator.clone()
+    })
+}
+
+fn display_path<'a>(path: &'a str, config: &Config) -> &'a str {
+    config
+        .strip_display_prefix
+        .as_deref()
+        .and_then(|prefix| path.strip_prefix(prefix))
+        .unwrap_or(path)
+}
+
+/// Plain-language description of what the annotation's tokens map to, e.g.
+/// "This annotation links generated code to 2 source columns on line 10 of A.scala."
+fn explain_annotation(tokens: &[ParsedToken], config: &Config) -> String {
+    let parts: Vec<String> = tokens
+        .iter()
+        .flat_map(|token| {
+            let path = display_path(&token.locator.path, config);
+            token
+                .locator
+                .line_groups()
+                .into_iter()
+                .map(move |(line, columns)| {
+                    let noun = if columns.len() == 1 {
+                        "column"
+                    } else {
+                        "columns"
+                    };
+                    format!("{} source {noun} on line {line} of {path}", columns.len())
+                })
+        })
+        .collect();
+    let joined = match parts.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} and {last}", rest.join(", ")),
+        None => "nothing".to_string(),
+    };
+    format!("This annotation links generated code to {joined}.")
+}
+
+fn format_locator(locator: &Locator) -> String {
+    if !locator.pairs.is_empty() {
+        let pairs = locator
+            .pairs
+            .iter()
+            .map(|(line, column)| format!("{line}:{column}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("{}:{{{pairs}}}", locator.path)
+    } else if locator.columns.len() == 1 {
+        format!("{}:{}:{}", locator.path, locator.line, locator.columns[0])
+    } else {
+        let columns = locator
+            .columns
+            .iter()
+            .map(|column| column.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("{}:{}:{{{columns}}}", locator.path, locator.line)
+    }
+}
+
+fn line_text_at(text: &str, one_based_line: u32) -> Option<&str> {
+    let line_index = usize::try_from(one_based_line.checked_sub(1)?).ok()?;
+    let line_starts = compute_line_starts(text);
+    let start = *line_starts.get(line_index)?;
+    let end = line_starts
+        .get(line_index + 1)
+        .copied()
+        .unwrap_or(text.len());
+    Some(text[start..end].trim_end_matches(['\n', '\r']))
+}
+
+/// Cuts a line longer than `max_width` characters down to a window of that many
+/// centered on the marked columns, with `…` where text was dropped, and shifts
+/// `columns` to match. Columns falling outside the window are dropped.
+fn truncate_source_line(
+    source_line: &str,
+    columns: &[u32],
+    max_width: usize,
+) -> (String, Vec<u32>) {
+    let chars: Vec<char> = source_line.chars().collect();
+    if max_width == 0 || chars.len() <= max_width {
+        return (source_line.to_string(), columns.to_vec());
+    }
+
+    let marked: Vec<usize> = columns
+        .iter()
+        .filter(|column| **column > 0)
+        .map(|column| (*column - 1) as usize)
+        .collect();
+    let center = match (marked.iter().min(), marked.iter().max()) {
+        (Some(first), Some(last)) => (first + last) / 2,
+        _ => 0,
+    };
+    let start = center
+        .saturating_sub(max_width / 2)
+        .min(chars.len() - max_width);
+    let end = start + max_width;
+
+    let leading = usize::from(start > 0);
+    let mut window = String::new();
+    if start > 0 {
+        window.push('…');
+    }
+    window.extend(&chars[start..end]);
+    if end < chars.len() {
+        window.push('…');
+    }
+    let shifted = marked
+        .into_iter()
+        .filter(|index| (start..end).contains(index))
+        .map(|index| (index - start + leading + 1) as u32)
+        .collect();
+    (window, shifted)
+}
+
+/// Moves 1-based `columns` past the end of `source_line` onto its last column,
+/// returning the moved columns' original values alongside.
+fn clamp_columns(source_line: &str, columns: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let width = source_line.chars().count() as u32;
+    let mut past_end = Vec::new();
+    let clamped = columns
+        .iter()
+        .map(|&column| {
+            if width > 0 && column > width {
+                past_end.push(column);
+                width
+            } else {
+                column
+            }
+        })
+        .collect();
+    (clamped, past_end)
+}
+
+fn build_column_indicator_line(source_line: &str, columns: &[u32], marker: char) -> String {
+    let mut indicators: Vec<char> = source_line
+        .chars()
+        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    let mut has_valid_column = false;
+    for &column in columns {
+        if column == 0 {
+            continue;
+        }
+        has_valid_column = true;
+        let index = (column - 1) as usize;
+        if index >= indicators.len() {
+            indicators.resize(index + 1, ' ');
+        }
+        indicators[index] = marker;
+    }
+
+    if !has_valid_column {
+        return marker.to_string();
+    }
+
+    if let Some(last) = indicators.iter().rposition(|ch| *ch == marker) {
+        indicators.truncate(last + 1);
+    }
+
+    indicators.into_iter().collect()
+}
+
+fn markdown_language_from_path(path: &str) -> &'static str {
+    let lower = path.to_ascii_lowercase();
+    if lower.ends_with(".scala") {
+        "scala"
+    } else if lower.ends_with(".fir") || lower.ends_with(".firrtl") {
+        "firrtl"
+    } else if lower.ends_with(".rs") {
+        "rust"
+    } else if lower.ends_with(".py") {
+        "python"
+    } else if lower.ends_with(".sv") || lower.ends_with(".svh") || lower.ends_with(".v") {
+        "verilog"
+    } else {
+        "text"
+    }
+}
+
+/// Fence language named by a shebang (`#!/usr/bin/env python3`) or an Emacs
+/// (`-*- mode: firrtl -*-`) or Vim (`vim: set ft=scala:`) modeline in the first two lines.
+fn markdown_language_from_header(text: &str) -> Option<&'static str> {
+    text.lines().take(2).find_map(|line| {
+        let name = if let Some(interpreter) = line.strip_prefix("#!") {
+            let mut words = interpreter.split_whitespace();
+            let program = words.next()?;
+            let program = if program.ends_with("/env") {
+                words.find(|word| !word.starts_with('-'))?
+            } else {
+                program
+            };
+            program.rsplit('/').next()?
+        } else if let Some(start) = line.find("-*-") {
+            let rest = &line[start + 3..];
+            let body = &rest[..rest.find("-*-")?];
+            body.split(';')
+                .find_map(|field| {
+                    let (key, value) = field.split_once(':')?;
+                    key.trim()
+                        .eq_ignore_ascii_case("mode")
+                        .then(|| value.trim())
+                })
+                .unwrap_or_else(|| body.trim())
+        } else {
+            let start = line.find("vim:").or_else(|| line.find("vi:"))?;
+            line[start..].split([' ', ':']).find_map(|field| {
+                field
+                    .strip_prefix("ft=")
+                    .or_else(|| field.strip_prefix("filetype="))
+            })?
+        };
+        markdown_language_from_name(name)
+    })
+}
+
+fn markdown_language_from_name(name: &str) -> Option<&'static str> {
+    let name = name.to_ascii_lowercase();
+    let name = name.trim_end_matches(|ch: char| ch.is_ascii_digit() || ch == '.');
+    match name {
+        "scala" | "amm" => Some("scala"),
+        "firrtl" | "fir" => Some("firrtl"),
+        "rust" => Some("rust"),
+        "python" => Some("python"),
+        "verilog" | "systemverilog" => Some("verilog"),
+        _ => None,
+    }
+}
+
+/// Keywords that open a definition worth naming in hovers, per target language.
+fn definition_keywords(path: &str) -> &'static [&'static str] {
+    match markdown_language_from_path(path) {
+        "scala" => &["class", "object", "trait", "enum", "def"],
+        "firrtl" => &["circuit", "module", "extmodule", "intmodule"],
+        "rust" => &["fn", "struct", "enum", "trait", "impl", "mod"],
+        "python" => &["def", "class"],
+        "verilog" => &[
+            "module",
+            "interface",
+            "package",
+            "class",
+            "function",
+            "task",
+        ],
+        _ => &[],
+    }
+}
+
+/// Words that may precede a definition keyword or its name without being either.
+const DEFINITION_MODIFIERS: &[&str] = &[
+    "private",
+    "protected",
+    "public",
+    "pub",
+    "override",
+    "final",
+    "abstract",
+    "sealed",
+    "implicit",
+    "lazy",
+    "case",
+    "async",
+    "unsafe",
+    "static",
+    "automatic",
+    "virtual",
+];
+
+/// Parses a line like `  override def bar(x: Int) = {` into `def bar`.
+fn definition_on_line(line: &str, keywords: &[&str]) -> Option<String> {
+    let is_ident = |ch: char| ch.is_alphanumeric() || ch == '_' || ch == '$';
+    let mut words = line
+        .split(|ch: char| !is_ident(ch))
+        .filter(|word| !word.is_empty());
+    let mut keyword = words.next()?;
+    while DEFINITION_MODIFIERS.contains(&keyword) {
+        keyword = words.next()?;
+    }
+    if !keywords.contains(&keyword) {
+        return None;
+    }
+    let name = words.find(|word| !DEFINITION_MODIFIERS.contains(word))?;
+    Some(format!("{keyword} {name}"))
+}
+
+/// Scans upward from 1-based `line` for the nearest definition enclosing it:
+/// the line itself when it is one, otherwise the closest definition above that
+/// is indented less than the line.
+fn enclosing_symbol(text: &str, line: u32, path: &str) -> Option<String> {
+    let keywords = definition_keywords(path);
+    if keywords.is_empty() {
+        return None;
+    }
+    let indent = |line: &str| line.len() - line.trim_start().len();
+    let line_starts = compute_line_starts(text);
+    let line_text = |line: u32| {
+        let index = (line as usize).checked_sub(1)?;
+        let start = *line_starts.get(index)?;
+        let end = line_starts.get(index + 1).copied().unwrap_or(text.len());
+        Some(text[start..end].trim_end_matches(['\n', '\r']))
+    };
+
+    let target_line = line_text(line)?;
+    if let Some(symbol) = definition_on_line(target_line, keywords) {
+        return Some(symbol);
+    }
+    let mut threshold = if target_line.trim().is_empty() {
+        usize::MAX
+    } else {
+        indent(target_line)
+    };
+    for above in (1..line).rev() {
+        let candidate = line_text(above)?;
+        if candidate.trim().is_empty() || indent(candidate) >= threshold {
+            continue;
+        }
+        if let Some(symbol) = definition_on_line(candidate, keywords) {
+            return Some(symbol);
+        }
+        threshold = indent(candidate);
+    }
+    None
+}
+
+fn collect_annotation_records(uri: &Url, text: &str, config: &Config) -> Vec<AnnotationRecord> {
+    let line_starts = compute_line_starts(text);
+    let mut records = Vec::new();
+
+    for annotation in find_annotations(text, config) {
+        let annotation_line =
+            offset_to_position(annotation.full_start, text, &line_starts).line + 1;
+        for token in parse_tokens_from_annotation(text, &annotation, &line_starts, config) {
+            for (line, columns) in token.locator.line_groups() {
+                records.push(AnnotationRecord {
+                    source_uri: uri.to_string(),
+                    annotation_line,
+                    path: token.locator.path.clone(),
+                    line,
+                    columns,
+                });
+            }
+        }
+    }
+
+    records
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Serializes records as CSV; multiple columns share one field, separated by `;`.
+fn records_to_csv(records: &[AnnotationRecord]) -> String {
+    let mut out = String::from("source_uri,annotation_line,path,line,columns\n");
+    for record in records {
+        let columns = record
+            .columns
+            .iter()
+            .map(|column| column.to_string())
+            .collect::<Vec<_>>()
+            .join(";");
+        out.push_str(&format!(
+            "{},{},{},{},{}\n",
+            csv_field(&record.source_uri),
+            record.annotation_line,
+            csv_field(&record.path),
+            record.line,
+            columns
+        ));
+    }
+    out
+}
+
+fn records_to_json(records: &[AnnotationRecord]) -> String {
+    let rows: Vec<Value> = records
+        .iter()
+        .map(|record| {
+            json!({
+                "source_uri": record.source_uri,
+                "annotation_line": record.annotation_line,
+                "path": record.path,
+                "line": record.line,
+                "columns": record.columns,
+            })
+        })
+        .collect();
+    serde_json::to_string_pretty(&rows).unwrap_or_else(|_| "[]".to_string())
+}
+
+fn line_start_for_offset(offset: usize, line_starts: &[usize]) -> usize {
+    let line = match line_starts.binary_search(&offset) {
+        Ok(index) => index,
+        Err(0) => 0,
+        Err(index) => index - 1,
+    };
+    line_starts[line]
+}
+
+/// Returns where a `//` comment marker directly preceding the `@[` at `at_start`
+/// begins, allowing spaces or tabs between the marker and the annotation.
+fn comment_prefix_start(text: &str, at_start: usize, line_start: usize) -> Option<usize> {
+    let before = text[line_start..at_start].trim_end_matches([' ', '\t']);
+    before
+        .strip_suffix("//")
+        .map(|prefix| line_start + prefix.len())
+}
+
+fn summary_hover_byte_range(
+    text: &str,
+    annotation: &AnnotationSpan,
+    line_starts: &[usize],
+) -> (usize, usize) {
+    let at_start = annotation.full_start;
+    let trigger_end = annotation.inner_start.min(text.len());
+    let line_start = line_start_for_offset(at_start, line_starts);
+
+    let start = summary_prefix_start(text, at_start, line_start).unwrap_or(at_start);
+    (start, trigger_end)
+}
+
+/// Start of the `//` comment marker or FIRRTL `info =` prefix before the `@[`
+/// at `at_start`; hovering either shows the summary.
+fn summary_prefix_start(text: &str, at_start: usize, line_start: usize) -> Option<usize> {
+    comment_prefix_start(text, at_start, line_start)
+        .or_else(|| info_prefix_start(text, at_start, line_start))
+}
+
+/// Returns where an `info =` prefix (as in FIRRTL info strings) directly
+/// preceding the `@[` at `at_start` begins, allowing spaces or tabs around `=`.
+fn info_prefix_start(text: &str, at_start: usize, line_start: usize) -> Option<usize> {
+    let before = text[line_start..at_start].trim_end_matches([' ', '\t']);
+    let before = before.strip_suffix('=')?.trim_end_matches([' ', '\t']);
+    let prefix = before.strip_suffix("info")?;
+    if prefix.ends_with(|ch: char| ch.is_alphanumeric() || ch == '_') {
+        return None;
+    }
+    Some(line_start + prefix.len())
+}
+
+/// For `mergeSameLineAnnotations`: when `offset` is on the summary trigger of
+/// one of several annotations starting on its line, or between two of them,
+/// returns those annotations and the byte range of the merged hover.
+fn merged_line_annotations(
+    text: &str,
+    annotations: &[AnnotationSpan],
+    offset: usize,
+    line_starts: &[usize],
+) -> Option<(Vec<AnnotationSpan>, ByteSpan)> {
+    let line_start = line_start_for_offset(offset, line_starts);
+    let on_line: Vec<AnnotationSpan> = annotations
+        .iter()
+        .filter(|annotation| {
+            line_start_for_offset(annotation.full_start, line_starts) == line_start
+        })
+        .cloned()
+        .collect();
+    if on_line.len() < 2 {
+        return None;
+    }
+
+    let triggers: Vec<ByteSpan> = on_line
+        .iter()
+        .map(|annotation| summary_hover_byte_range(text, annotation, line_starts))
+        .collect();
+    let on_trigger = triggers
+        .iter()
+        .any(|(start, end)| offset >= *start && offset < *end);
+    let between = on_line
+        .iter()
+        .zip(&triggers[1..])
+        .any(|(previous, (next_start, _))| offset >= previous.full_end && offset < *next_start);
+    if !on_trigger && !between {
+        return None;
+    }
+
+    let end = on_line.last()?.full_end;
+    Some((on_line, (triggers[0].0, end)))
+}
+
+fn format_check_report(file: &str, report: &AnalysisReport) -> String {
+    let mut out = String::new();
+    for annotation in &report.annotations {
+        for token in &annotation.tokens {
+            let status = if token.exists { "ok" } else { "missing" };
+            out.push_str(&format!(
+                "{file}:{}: {} -> {} ({status})\n",
+                annotation.line,
+                format_locator(&token.locator),
+                token.resolved_path.display()
+            ));
+        }
+    }
+    out
+}
+
+/// `--check --json` entry for one analyzed file.
+fn check_report_json(file: &str, report: &AnalysisReport) -> Value {
+    let annotations: Vec<Value> = report
+        .annotations
+        .iter()
+        .map(|annotation| {
+            let tokens: Vec<Value> = annotation
+                .tokens
+                .iter()
+                .map(|token| {
+                    json!({
+                        "locator": format_locator(&token.locator),
+                        "path": token.locator.path,
+                        "lines": token
+                            .locator
+                            .line_groups()
+                            .into_iter()
+                            .map(|(line, columns)| json!({ "line": line, "columns": columns }))
+                            .collect::<Vec<_>>(),
+                        "resolvedPath": token.resolved_path,
+                        "exists": token.exists,
+                    })
+                })
+                .collect();
+            json!({ "line": annotation.line, "tokens": tokens })
+        })
+        .collect();
+    json!({ "file": file, "annotations": annotations })
+}
+
+/// Settings of a `--check` run, set up like the server's: `--config` names a JSON
+/// file holding `initializationOptions`, and relative paths in it are taken from
+/// the current directory. Ignored options are reported on stderr.
+fn check_config(config_file: Option<&str>) -> std::result::Result<Config, String> {
+    let options = match config_file {
+        Some(file) => {
+            let text = std::fs::read_to_string(file).map_err(|err| format!("{file}: {err}"))?;
+            Some(serde_json::from_str(&text).map_err(|err| format!("{file}: {err}"))?)
+        }
+        None => None,
+    };
+    let (mut config, mut warnings) = Config::from_initialization_options(options);
+    config.apply_base_dir_env(std::env::var_os(BASE_DIR_ENV).map(PathBuf::from));
+    if let Ok(current_dir) = std::env::current_dir() {
+        config.anchor_source_roots(&[current_dir]);
+    }
+    warnings.extend(config.validate_base_dir(&RealFileSystem));
+    for warning in warnings {
+        eprintln!("firrtl-source-locator: {warning}");
+    }
+    Ok(config)
+}
+
+/// `--check [--json] [--config <settings.json>] <file>...`: prints every locator with its resolution status
+/// (as one JSON document with `--json`) and returns a non-zero exit code when a
+/// file is unreadable or a target is missing.
+fn run_check(args: &[String]) -> i32 {
+    let mut as_json = false;
+    let mut config_file = None;
+    let mut files = args;
+    loop {
+        match files {
+            [flag, rest @ ..] if flag == "--json" => {
+                as_json = true;
+                files = rest;
+            }
+            [flag, file, rest @ ..] if flag == "--config" => {
+                config_file = Some(file.as_str());
+                files = rest;
+            }
+            _ => break,
+        }
+    }
+    if files.is_empty() {
+        eprintln!(
+            "usage: firrtl-source-locator-server --check [--json] [--config <settings.json>] <file>..."
+        );
+        return 2;
+    }
+    let config = match check_config(config_file) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            return 2;
+        }
+    };
+
+    let mut exit_code = 0;
+    let mut json_files = Vec::new();
+    for file in files {
+        let path = Path::new(file);
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => {
+                if as_json {
+                    json_files.push(json!({ "file": file, "error": err.to_string() }));
+                } else {
+                    eprintln!("{file}: {err}");
+                }
+                exit_code = 1;
+                continue;
+            }
+        };
+
+        let Some(uri) = std::path::absolute(path)
+            .ok()
+            .and_then(|path| Url::from_file_path(path).ok())
+        else {
+            eprintln!("{file}: has no absolute path");
+            exit_code = 1;
+            continue;
+        };
+        let report = analyze_text(&text, &uri, &config, &RealFileSystem);
+        if as_json {
+            json_files.push(check_report_json(file, &report));
+        } else {
+            print!("{}", format_check_report(file, &report));
+        }
+
+        let all_resolved = report
+            .annotations
+            .iter()
+            .flat_map(|annotation| &annotation.tokens)
+            .all(|token| token.exists);
+        if !all_resolved {
+            exit_code = 1;
+        }
+    }
+    if as_json {
+        let document = json!({ "files": json_files, "ok": exit_code == 0 });
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&document).unwrap_or_default()
+        );
+    }
+    exit_code
+}
+
+/// Where the language server talks to its client.
+#[derive(Debug, PartialEq, Eq)]
+enum Transport {
+    Stdio,
+    /// `--listen <addr>`: accept one client on a TCP socket, e.g. to attach an LSP inspector.
+    Tcp(std::net::SocketAddr),
+}
+
+/// The transport named by `args`, plus the arguments it ignored. `--stdio`, which
+/// many LSP clients pass, is accepted; only a malformed `--listen` is an error.
+fn parse_transport(args: &[String]) -> std::result::Result<(Transport, Vec<String>), String> {
+    let mut transport = Transport::Stdio;
+    let mut ignored = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--stdio" => {}
+            "--listen" => {
+                let addr = args.next().ok_or_else(|| {
+                    "usage: firrtl-source-locator-server [--listen <host:port> | --check <file>...]"
+                        .to_string()
+                })?;
+                transport = addr
+                    .parse()
+                    .map(Transport::Tcp)
+                    .map_err(|err| format!("invalid --listen address `{addr}`: {err}"))?;
+            }
+            _ => ignored.push(arg.clone()),
+        }
+    }
+    Ok((transport, ignored))
+}
+
+/// Serves the first client connecting to `listener` until it disconnects.
+async fn serve_tcp(listener: tokio::net::TcpListener) -> std::io::Result<()> {
+    let (stream, _) = listener.accept().await?;
+    let (read, write) = tokio::io::split(stream);
+    let (service, socket) = LspService::new(Backend::new);
+    Server::new(read, write, socket).serve(service).await;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--check") {
+        std::process::exit(run_check(&args[1..]));
+    }
+
+    let transport = parse_transport(&args).map(|(transport, ignored)| {
+        for arg in ignored {
+            eprintln!("firrtl-source-locator: ignoring unknown argument `{arg}`");
+        }
+        transport
+    });
+    match transport {
+        Ok(Transport::Stdio) => {
+            let stdin = tokio::io::stdin();
+            let stdout = tokio::io::stdout();
+
+            let (service, socket) = LspService::new(Backend::new);
+            Server::new(stdin, stdout, socket).serve(service).await;
+        }
+        Ok(Transport::Tcp(addr)) => {
+            let served = match tokio::net::TcpListener::bind(addr).await {
+                Ok(listener) => {
+                    eprintln!("firrtl-source-locator: listening on {addr}");
+                    serve_tcp(listener).await
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = served {
+                eprintln!("firrtl-source-locator: {addr}: {err}");
+                std::process::exit(1);
+            }
+        }
+        Err(usage) => {
+            eprintln!("{usage}");
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::IndicatorStyle;
+    use fs::MemoryFileSystem;
+
+    #[test]
+    fn transport_defaults_to_stdio_and_listens_on_request() {
+        let args = |list: &[&str]| list.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(parse_transport(&[]), Ok((Transport::Stdio, Vec::new())));
+        assert_eq!(
+            parse_transport(&args(&["--listen", "127.0.0.1:9257"])),
+            Ok((
+                Transport::Tcp("127.0.0.1:9257".parse().unwrap()),
+                Vec::new()
+            ))
+        );
+        assert!(parse_transport(&args(&["--listen", "localhost"])).is_err());
+        assert!(parse_transport(&args(&["--listen"])).is_err());
+        assert_eq!(
+            parse_transport(&args(&["--stdio"])),
+            Ok((Transport::Stdio, Vec::new()))
+        );
+        assert_eq!(
+            parse_transport(&args(&["--stdio", "--bogus"])),
+            Ok((Transport::Stdio, args(&["--bogus"])))
+        );
+    }
+
+    #[tokio::test]
+    async fn tcp_transport_answers_initialize() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_tcp(listener));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#;
+        stream
+            .write_all(format!("Content-Length: {}\r\n\r\n{body}", body.len()).as_bytes())
+            .await
+            .unwrap();
+
+        let mut response = Vec::new();
+        let mut buffer = [0u8; 4096];
+        while !String::from_utf8_lossy(&response).contains("\"capabilities\"") {
+            let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(read > 0, "server closed the connection");
+            response.extend_from_slice(&buffer[..read]);
+        }
+        assert!(String::from_utf8_lossy(&response).contains("\"id\":1"));
+
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn parse_inherited_path_token() {
+        let first = parse_locator_token("/tmp/Foo.scala:12:5", None, 0).unwrap();
+        assert_eq!(first.0.path, "/tmp/Foo.scala");
+        assert!(!first.1);
+
+        let inherited = parse_locator_token(":13:{7,9}", Some(&first.0.path), 0).unwrap();
+        assert_eq!(inherited.0.path, "/tmp/Foo.scala");
+        assert_eq!(inherited.0.columns, vec![7, 9]);
+        assert!(inherited.1);
+    }
+
+    #[test]
+    fn host_port_paths_keep_their_colons() {
+        let (locator, _) = parse_locator_token("server:8080/Foo.scala:10:3", None, 0).unwrap();
+        assert_eq!(locator.path, "server:8080/Foo.scala");
+        assert_eq!((locator.line, locator.columns), (10, vec![3]));
+
+        let (pairs, _) =
+            parse_locator_token("server:8080/Foo.scala:{10:3, 12:5}", None, 0).unwrap();
+        assert_eq!(pairs.path, "server:8080/Foo.scala");
+        assert_eq!(pairs.pairs, vec![(10, 3), (12, 5)]);
+
+        // Without a column the port must not be taken for the line.
+        assert!(parse_locator_token("server:8080/Foo.scala:10", None, 0).is_none());
+        assert_eq!(locator_path_text("server:8080/Foo.scala:10"), None);
+        assert!(locator_number_spans("server:8080/Foo.scala:10").is_none());
+
+        // A colon in a trailing tag is no field separator.
+        let tagged = "server:8080/Foo.scala:10:3 <host:port>";
+        assert_eq!(locator_path_text(tagged), Some("server:8080/Foo.scala"));
+        let (line, _) = locator_number_spans(tagged).unwrap();
+        assert_eq!(&tagged[line.0..line.1], "10");
+        assert_eq!(
+            locator_field_colons("server:8080/Foo.scala:10:3"),
+            Some((21, 24))
+        );
+
+        let text = "x // @[server:8080/Foo.scala:10:3]";
+        let lines = compute_line_starts(text);
+        let annotation = find_annotations(text, &Config::default()).pop().unwrap();
+        let token = &parse_tokens_from_annotation(text, &annotation, &lines, &Config::default())[0];
+        assert_eq!(
+            &text[token.path_span.0..token.path_span.1],
+            "server:8080/Foo.scala"
+        );
+        assert_eq!(
+            token.line_span.map(|(start, end)| &text[start..end]),
+            Some("10")
+        );
+
+        let uri = Url::parse("file:///tmp/host-port/Top.sv").unwrap();
+        assert_eq!(
+            resolve_target_url(
+                &token.locator.path,
+                &uri,
+                &Config::default(),
+                &RealFileSystem
+            ),
+            Ok(Url::from_file_path("/tmp/host-port/server:8080/Foo.scala").unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_relative_line_offsets() {
+        let (ahead, _) = parse_locator_token("/tmp/Foo.scala:+3:5", None, 10).unwrap();
+        assert_eq!(ahead.line, 13);
+        assert_eq!(ahead.columns, vec![5]);
+
+        let (behind, _) = parse_locator_token("/tmp/Foo.scala:-2:5", None, 10).unwrap();
+        assert_eq!(behind.line, 8);
+
+        assert!(parse_locator_token("/tmp/Foo.scala:-10:5", None, 10).is_none());
+    }
+
+    #[test]
+    fn relative_lines_default_to_annotation_line() {
+        let text = "\n\nwire x; // @[/tmp/A.scala:+3:5, :-2:{1,2}]";
+        let lines = compute_line_starts(text);
+        let annotation = find_annotations(text, &Config::default()).pop().unwrap();
+
+        let tokens = parse_tokens_from_annotation(text, &annotation, &lines, &Config::default());
+        assert_eq!(tokens[0].locator.line, 6);
+        assert_eq!(tokens[1].locator.line, 1);
+
+        let anchored = Config {
+            relative_line_base: Some(100),
+            ..Config::default()
+        };
+        let tokens = parse_tokens_from_annotation(text, &annotation, &lines, &anchored);
+        assert_eq!(tokens[0].locator.line, 103);
+        assert_eq!(tokens[1].locator.line, 98);
+    }
+
+    #[test]
+    fn split_tokens_respects_braces() {
+        let input = "/a.scala:1:2, :3:{4,5,6}, /b.scala:7:8";
+        let slices = split_locator_tokens(input);
+        let tokens: Vec<&str> = slices
+            .iter()
+            .map(|(start, end)| &input[*start..*end])
+            .collect();
+
+        assert_eq!(tokens, vec!["/a.scala:1:2", " :3:{4,5,6}", " /b.scala:7:8"]);
+    }
+
+    #[test]
+    fn split_tokens_keeps_escaped_and_quoted_commas() {
+        let input = r#"a\,b.scala:1:2, "c,d.scala":3:4, :5:6"#;
+        let tokens: Vec<&str> = split_locator_tokens(input)
+            .iter()
+            .map(|(start, end)| &input[*start..*end])
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![r"a\,b.scala:1:2", r#" "c,d.scala":3:4"#, " :5:6"]
+        );
+
+        let text = format!("x // @[{input}]");
+        let annotation = find_annotations(&text, &Config::default()).remove(0);
+        let lines = compute_line_starts(&text);
+        let paths: Vec<String> =
+            parse_tokens_from_annotation(&text, &annotation, &lines, &Config::default())
+                .into_iter()
+                .map(|token| token.locator.path)
+                .collect();
+        assert_eq!(paths, vec!["a,b.scala", "c,d.scala", "c,d.scala"]);
+    }
+
+    #[test]
+    fn bare_locator_strings_are_found_when_enabled() {
+        let text = r#"{"name": "x", "loc": "src/Foo.scala:12:5", "note": "see a:b", "cols": "Foo.scala:3:{1, 4}"}"#;
+        assert!(find_annotations(text, &Config::default()).is_empty());
+
+        let config = Config {
+            bare_locator_strings: true,
+            ..Config::default()
+        };
+        let annotations = find_annotations(text, &config);
+        let inners: Vec<&str> = annotations
+            .iter()
+            .map(|span| &text[span.inner_start..span.inner_end])
+            .collect();
+        assert_eq!(inners, vec!["src/Foo.scala:12:5", "Foo.scala:3:{1, 4}"]);
+
+        let lines = compute_line_starts(text);
+        let tokens = parse_tokens_from_annotation(text, &annotations[0], &lines, &config);
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].locator.path, "src/Foo.scala");
+        assert_eq!(
+            (tokens[0].locator.line, tokens[0].locator.columns.clone()),
+            (12, vec![5])
+        );
+
+        for rejected in [
+            "Foo:1:2",
+            "a b.scala:1:2",
+            "Foo.scala:1",
+            "Foo.scala:x:2",
+            "@[A.scala:1:2]",
+        ] {
+            assert!(!is_bare_locator(rejected), "{rejected}");
+        }
+        let mixed = r#"x // @[A.scala:1:1] "B.scala:2:2""#;
+        assert_eq!(find_annotations(mixed, &config).len(), 2);
+    }
+
+    #[test]
+    fn tokenizer_reports_empty_slots() {
+        let input = "A:1:2, ,B:3:4";
+        let slots = tokenize_locators(input);
+        let tokens: Vec<&str> = slots
+            .tokens
+            .iter()
+            .map(|(start, end)| &input[*start..*end])
+            .collect();
+        assert_eq!(tokens, vec!["A:1:2", "B:3:4"]);
+        assert_eq!(slots.empty, vec![(6, 7)]);
+    }
+
+    #[test]
+    fn empty_middle_token_is_flagged() {
+        let config = Config::default();
+        let text = "x // @[A:1:2,,B:3:4]";
+        let annotation = find_annotations(text, &config).remove(0);
+        let inner_start = annotation.inner_start;
+        assert_eq!(
+            find_empty_tokens(text, &annotation),
+            vec![(inner_start + 6, inner_start + 6)]
+        );
+
+        let single = "x // @[]";
+        let annotation = find_annotations(single, &config).remove(0);
+        assert!(find_empty_tokens(single, &annotation).is_empty());
+    }
+
+    #[test]
+    fn leading_token_without_a_path_is_flagged() {
+        let config = Config::default();
+        let text = "x // @[:10:3, A:1:2, :4:5]";
+        let line_starts = compute_line_starts(text);
+        let annotation = find_annotations(text, &config).remove(0);
+        let inner_start = annotation.inner_start;
+        assert_eq!(
+            find_orphan_tokens(text, &annotation, &line_starts, &config),
+            vec![(inner_start, inner_start + 5)]
+        );
+        assert_eq!(
+            parse_tokens_from_annotation(text, &annotation, &line_starts, &config).len(),
+            2
+        );
+        assert_eq!(
+            orphan_token_diagnostic(text, &line_starts, (inner_start, inner_start + 5)).message,
+            "first locator has no path to inherit"
+        );
+    }
+
+    #[test]
+    fn enclosing_symbol_finds_nearest_scala_definition() {
+        let text = "package demo\n\nclass Foo {\n  val a = 1\n\n  private def bar(x: Int) = {\n    val y = x\n    y\n  }\n  val z = 2\n}\n";
+        assert_eq!(
+            enclosing_symbol(text, 7, "Foo.scala"),
+            Some("def bar".to_string())
+        );
+        assert_eq!(
+            enclosing_symbol(text, 6, "Foo.scala"),
+            Some("def bar".to_string())
+        );
+        assert_eq!(
+            enclosing_symbol(text, 10, "Foo.scala"),
+            Some("class Foo".to_string())
+        );
+        assert_eq!(
+            enclosing_symbol(text, 4, "Foo.scala"),
+            Some("class Foo".to_string())
+        );
+        assert_eq!(enclosing_symbol(text, 1, "Foo.scala"), None);
+        assert_eq!(enclosing_symbol(text, 7, "Foo.txt"), None);
+
+        assert_eq!(
+            enclosing_symbol("case class Bundle(x: Int)\n", 1, "B.scala"),
+            Some("class Bundle".to_string())
+        );
+        assert_eq!(
+            enclosing_symbol("class A {\n  val define = 1\n}\n", 2, "A.scala"),
+            Some("class A".to_string())
+        );
+    }
+
+    #[test]
+    fn mlir_locations_parse_when_enabled() {
+        let text =
+            "%0 = comb.add %a, %b loc(\"A.scala\":10:3)\n%1 = alloc(\"x\") // @[B.scala:2:1]";
+        let uri = Url::parse("file:///tmp/Top.mlir").unwrap();
+
+        let default_spans = find_annotations(text, &Config::default());
+        assert_eq!(default_spans.len(), 1);
+
+        let config = Config {
+            location_syntax: LocationSyntax::All,
+            ..Config::default()
+        };
+        let spans = find_annotations(text, &config);
+        assert_eq!(spans.len(), 2);
+        assert_eq!(
+            &text[spans[0].full_start..spans[0].full_end],
+            "loc(\"A.scala\":10:3)"
+        );
+        assert_eq!(
+            &text[spans[0].inner_start..spans[0].inner_end],
+            "\"A.scala\":10:3"
+        );
+
+        let lines = compute_line_starts(text);
+        let tokens = parse_tokens_from_annotation(text, &spans[0], &lines, &config);
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].locator.path, "A.scala");
+        assert_eq!(
+            (tokens[0].locator.line, tokens[0].locator.columns.clone()),
+            (10, vec![3])
+        );
+        assert_eq!(
+            summary_hover_byte_range(text, &spans[0], &lines),
+            (spans[0].full_start, spans[0].inner_start)
+        );
+
+        let links = definition_links(
+            text,
+            &find_annotations(text, &config),
+            &uri,
+            Position::new(0, 28),
+            &config,
+            &TargetLines::default(),
+            &RealFileSystem,
+        );
+        assert_eq!(links[0].target_uri.path(), "/tmp/A.scala");
+        assert_eq!(links[0].target_range.start, Position::new(9, 2));
+
+        // An unclosed `loc(` does not hide a later `@[...]`.
+        let unclosed = "x loc(\"A.scala\":1:1 // @[B.scala:2:1]";
+        assert_eq!(find_annotations(unclosed, &config).len(), 1);
+    }
+
+    #[test]
+    fn parse_numbers_with_underscore_separators() {
+        let (locator, _) = parse_locator_token("Foo.scala:1_234:5", None, 1).unwrap();
+        assert_eq!(locator.line, 1234);
+        assert_eq!(parse_columns("{1_0, 20}"), Some(vec![10, 20]));
+        assert_eq!(parse_columns("0x1_f"), Some(vec![31]));
+        assert_eq!(parse_line("+1_0", 5), Some(15));
+        assert_eq!(parse_line("_12", 1), None);
+        assert_eq!(parse_columns("_"), None);
+        assert_eq!(parse_columns("+5"), None);
+        assert_eq!(parse_columns("+_5"), None);
+        assert_eq!(parse_columns("0x+f"), None);
+        assert_eq!(parse_line("+_5", 1), None);
+    }
+
+    #[test]
+    fn parse_non_decimal_columns() {
+        assert_eq!(parse_columns("0x1f"), Some(vec![31]));
+        assert_eq!(parse_columns("{0x10, 32}"), Some(vec![16, 32]));
+        assert_eq!(parse_columns("{0o17, 0B101, 0XA}"), Some(vec![15, 5, 10]));
+        assert_eq!(parse_columns("017"), Some(vec![17]));
+        assert_eq!(parse_columns("0xzz"), None);
+
+        let (locator, _) = parse_locator_token("Foo.scala:10:0x1f", None, 1).unwrap();
+        assert_eq!(locator.columns, vec![31]);
+    }
+
+    #[test]
+    fn parse_annotation_example() {
+        let text = "wire x; // @[/tmp/A.scala:10:3, :11:{4,9}, /tmp/B.scala:12:8]";
+        let lines = compute_line_starts(text);
+        let annotation = find_annotations(text, &Config::default()).pop().unwrap();
+        let tokens = parse_tokens_from_annotation(text, &annotation, &lines, &Config::default());
+
+        assert_eq!(tokens.len(), 3);
+        assert_eq!(tokens[0].locator.path, "/tmp/A.scala");
+        assert_eq!(tokens[1].locator.path, "/tmp/A.scala");
+        assert_eq!(tokens[2].locator.path, "/tmp/B.scala");
+        assert_eq!(tokens[1].locator.columns, vec![4, 9]);
+    }
+
+    #[test]
+    fn parse_line_column_pair_set() {
+        let (locator, inherited) =
+            parse_locator_token("/tmp/Foo.scala:{10:3, 12:5, 10:7}", None, 0).unwrap();
+        assert!(!inherited);
+        assert_eq!(locator.path, "/tmp/Foo.scala");
+        assert_eq!(locator.pairs, vec![(10, 3), (12, 5), (10, 7)]);
+        assert_eq!(locator.line, 10);
+        assert_eq!(locator.columns, vec![3, 7]);
+        assert_eq!(locator.line_groups(), vec![(10, vec![3, 7]), (12, vec![5])]);
+
+        let (inherited_pairs, inherited) =
+            parse_locator_token(":{1:2}", Some("/tmp/Foo.scala"), 0).unwrap();
+        assert!(inherited);
+        assert_eq!(inherited_pairs.pairs, vec![(1, 2)]);
+
+        assert!(parse_locator_token("/tmp/Foo.scala:{10:3, 12}", None, 0).is_none());
+        assert!(parse_locator_token("/tmp/Foo.scala{10:3}", None, 0).is_none());
+
+        let (braced_path, _) = parse_locator_token("/tmp/{x}/Foo.scala:1:2", None, 0).unwrap();
+        assert_eq!(braced_path.path, "/tmp/{x}/Foo.scala");
+    }
+
+    #[test]
+    fn parse_token_with_kind_tag() {
+        let (locator, _) = parse_locator_token("A.scala:10:3 <assert>", None, 0).unwrap();
+        assert_eq!(locator.path, "A.scala");
+        assert_eq!((locator.line, locator.columns.clone()), (10, vec![3]));
+        assert_eq!(locator.tag.as_deref(), Some("assert"));
+
+        let (set, _) = parse_locator_token(":12:{1,4}<cover>", Some("A.scala"), 0).unwrap();
+        assert_eq!(set.columns, vec![1, 4]);
+        assert_eq!(set.tag.as_deref(), Some("cover"));
+
+        let (untagged, _) = parse_locator_token("A.scala:10:3", None, 0).unwrap();
+        assert_eq!(untagged.tag, None);
+        assert!(parse_locator_token("A.scala:10:3 <>", None, 0).is_none());
+    }
+
+    #[tokio::test]
+    async fn hover_shows_kind_tag_badge() {
+        let root = temp_dir("kind-tag");
+        std::fs::write(root.join("A.scala"), "class A {\n  assert(ok)\n}\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(backend, &uri, "wire x; // @[A.scala:2:3 <assert>]").await;
+
+        assert_eq!(
+            hover_markdown(backend, &uri, 0, 15).await.unwrap(),
+            "```scala\n  assert(ok)\n  ^\n```\nA.scala:2:3 `<assert>`"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn pair_set_locator_formats_round_trip() {
+        let (locator, _) = parse_locator_token("/tmp/Foo.scala:{10:3,12:5}", None, 0).unwrap();
+        let formatted = format_locator(&locator);
+        assert_eq!(formatted, "/tmp/Foo.scala:{10:3, 12:5}");
+
+        let (reparsed, _) = parse_locator_token(&formatted, None, 0).unwrap();
+        assert_eq!(reparsed.pairs, locator.pairs);
+
+        let (single, _) = parse_locator_token("/tmp/Foo.scala:13:{7,9}", None, 0).unwrap();
+        assert_eq!(format_locator(&single), "/tmp/Foo.scala:13:{7,9}");
+    }
+
+    #[test]
+    fn pair_set_links_target_each_line() {
+        let text = "wire x; // @[/tmp/A.scala:{10:3, 12:5}, :12:5]";
+        let lines = compute_line_starts(text);
+        let annotation = find_annotations(text, &Config::default()).pop().unwrap();
+        let tokens = parse_tokens_from_annotation(text, &annotation, &lines, &Config::default());
+        let uri = Url::parse("file:///tmp/Top.sv").unwrap();
+
+        let links = collect_location_links(
+            tokens.iter(),
+            &uri,
+            &Config::default(),
+            &TargetLines::default(),
+            &RealFileSystem,
+        );
+        let targets: Vec<(u32, u32)> = links
+            .iter()
+            .map(|link| {
+                (
+                    link.target_range.start.line,
+                    link.target_range.start.character,
+                )
+            })
+            .collect();
+        assert_eq!(targets, vec![(9, 2), (11, 4)]);
+    }
+
+    #[test]
+    fn analyze_text_resolves_against_base_dir() {
+        let root = temp_dir("analyze");
+        std::fs::write(root.join("A.scala"), "class A\n").unwrap();
+        let text = "wire x; // @[A.scala:10:3, :11:{4,9}, /missing/B.scala:12:8]\nwire y;";
+
+        let report = analyze_text(
+            text,
+            &Url::from_file_path(root.join("top.sv")).unwrap(),
+            &Config::default(),
+            &RealFileSystem,
+        );
+        assert_eq!(report.annotations.len(), 1);
+        let annotation = &report.annotations[0];
+        assert_eq!(annotation.line, 1);
+
+        let resolved: Vec<(PathBuf, bool)> = annotation
+            .tokens
+            .iter()
+            .map(|token| (token.resolved_path.clone(), token.exists))
+            .collect();
+        assert_eq!(
+            resolved,
+            vec![
+                (root.join("A.scala"), true),
+                (root.join("A.scala"), true),
+                (PathBuf::from("/missing/B.scala"), false),
+            ]
+        );
+        assert_eq!(annotation.tokens[1].locator.columns, vec![4, 9]);
+
+        let output = format_check_report("top.sv", &report);
+        assert!(output.ends_with("top.sv:1: /missing/B.scala:12:8 -> /missing/B.scala (missing)\n"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn analyze_text_uses_the_server_resolution_settings() {
+        let fs = MemoryFileSystem::default();
+        fs.write("/proj/src/A.scala", "class A\n");
+        fs.write("/other/B.scala", "class B\n");
+        let uri = Url::parse("file:///proj/build/top.sv").unwrap();
+        let text = "wire x; // @[A.scala:1:1, /other/B.scala:1:1]";
+        let config = Config {
+            source_roots: vec![PathBuf::from("/proj/src")],
+            allowed_resolve_roots: vec![PathBuf::from("/proj")],
+            ..Config::default()
+        };
+
+        let report = analyze_text(text, &uri, &config, &fs);
+        let resolved: Vec<(PathBuf, bool)> = report.annotations[0]
+            .tokens
+            .iter()
+            .map(|token| (token.resolved_path.clone(), token.exists))
+            .collect();
+        assert_eq!(
+            resolved,
+            vec![
+                (PathBuf::from("/proj/src/A.scala"), true),
+                (PathBuf::from("/other/B.scala"), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_report_json_lists_tokens_with_status() {
+        let root = temp_dir("check-json");
+        std::fs::write(root.join("A.scala"), "class A\n").unwrap();
+        let text = "wire x;\nwire y; // @[A.scala:10:{3,4}, /missing/B.scala:12:8]";
+
+        let report = analyze_text(
+            text,
+            &Url::from_file_path(root.join("top.sv")).unwrap(),
+            &Config::default(),
+            &RealFileSystem,
+        );
+        assert_eq!(
+            check_report_json("top.sv", &report),
+            json!({
+                "file": "top.sv",
+                "annotations": [{
+                    "line": 2,
+                    "tokens": [
+                        {
+                            "locator": "A.scala:10:{3,4}",
+                            "path": "A.scala",
+                            "lines": [{ "line": 10, "columns": [3, 4] }],
+                            "resolvedPath": root.join("A.scala"),
+                            "exists": true,
+                        },
+                        {
+                            "locator": "/missing/B.scala:12:8",
+                            "path": "/missing/B.scala",
+                            "lines": [{ "line": 12, "columns": [8] }],
+                            "resolvedPath": "/missing/B.scala",
+                            "exists": false,
+                        },
+                    ],
+                }],
+            })
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn wrapped_annotation_end_lands_on_the_closing_line() {
+        for text in [
+            "wire x; // @[/tmp/A.scala:1:2,\n    /tmp/B.scala:3:4] assign y = x;\n",
+            "wire x; // @[/tmp/A.scala:1:2,\r\n    /tmp/B.scala:3:4] assign y = x;\r\n",
+        ] {
+            let lines = compute_line_starts(text);
+            let annotation = find_annotations(text, &Config::default()).pop().unwrap();
+            assert_eq!(&text[annotation.full_end - 1..annotation.full_end], "]");
+            assert_eq!(
+                offset_to_position(annotation.full_end - 1, text, &lines),
+                Position::new(1, 20)
+            );
+            assert_eq!(
+                offset_to_position(annotation.full_end, text, &lines),
+                Position::new(1, 21)
+            );
+
+            let tokens =
+                parse_tokens_from_annotation(text, &annotation, &lines, &Config::default());
+            assert_eq!(tokens[1].range.start, Position::new(1, 4));
+            assert_eq!(tokens[1].range.end, Position::new(1, 20));
+            assert_eq!(
+                summary_hover_byte_range(text, &annotation, &lines),
+                (8, annotation.inner_start)
+            );
+        }
+    }
+
+    #[test]
+    fn quoted_path_may_contain_closing_bracket() {
+        let text = "wire x; // @[\"/tmp/a]b.scala\":10:3, :11:4] // @[/tmp/C.scala:1:2]";
+        let lines = compute_line_starts(text);
+        let spans = find_annotations(text, &Config::default());
+        assert_eq!(spans.len(), 2);
+        assert_eq!(
+            &text[spans[0].inner_start..spans[0].inner_end],
+            "\"/tmp/a]b.scala\":10:3, :11:4"
+        );
+
+        let tokens = parse_tokens_from_annotation(text, &spans[0], &lines, &Config::default());
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[0].locator.path, "/tmp/a]b.scala");
+        assert_eq!(tokens[1].locator.path, "/tmp/a]b.scala");
+    }
+
+    #[test]
+    fn unterminated_quote_does_not_cross_lines() {
+        let text = "// @[\"/tmp/A.scala:1:2]\n// @[/tmp/B.scala:3:4]";
+        let spans = find_annotations(text, &Config::default());
+        assert_eq!(spans.len(), 2);
+        assert_eq!(
+            &text[spans[1].inner_start..spans[1].inner_end],
+            "/tmp/B.scala:3:4"
+        );
+    }
+
+    #[tokio::test]
+    async fn goto_in_new_tab_returns_location_with_flag() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/new-tab/Top.sv").unwrap();
+        open_document(
+            backend,
+            &uri,
+            "wire y; // @[/tmp/A.scala:10:3, /tmp/B.scala:2:1]",
+        )
+        .await;
+
+        let response = backend
+            .execute_command(ExecuteCommandParams {
+                command: GOTO_IN_NEW_TAB_COMMAND.to_string(),
+                arguments: vec![json!(uri.as_str()), json!({"line": 0, "character": 35})],
+                work_done_progress_params: WorkDoneProgressParams::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response["newTab"], true);
+        assert_eq!(response["location"]["uri"], "file:///tmp/B.scala");
+        assert_eq!(
+            response["location"]["range"]["start"],
+            json!({"line": 1, "character": 0})
+        );
+    }
+
+    #[test]
+    fn unterminated_annotation_gets_bracket_quick_fix() {
+        let text = "wire a; // @[A.scala:1:2  \nwire b; // @[B.scala:3:4 // @[C.scala:5:6]";
+        let uri = Url::parse("file:///tmp/Top.sv").unwrap();
+        let config = Config::default();
+
+        let spans = find_annotations(text, &config);
+        assert_eq!(spans.len(), 1);
+        assert_eq!(
+            &text[spans[0].inner_start..spans[0].inner_end],
+            "C.scala:5:6"
+        );
+
+        let unterminated = find_unterminated_annotations(text, &spans, &config);
+        assert_eq!(
+            unterminated
+                .iter()
+                .map(|u| &text[u.start..u.fix_offset])
+                .collect::<Vec<_>>(),
+            vec!["@[A.scala:1:2", "@[B.scala:3:4"]
+        );
+
+        let actions = malformed_annotation_fixes(
+            text,
+            &find_annotations(text, &config),
+            &uri,
+            Range::new(Position::new(0, 15), Position::new(0, 15)),
+            &config,
+        );
+        assert_eq!(actions.len(), 1);
+        let CodeActionOrCommand::CodeAction(action) = &actions[0] else {
+            panic!("expected a code action");
+        };
+        assert_eq!(action.kind, Some(CodeActionKind::QUICKFIX));
+        let edits = &action.edit.as_ref().unwrap().changes.as_ref().unwrap()[&uri];
+        assert_eq!(
+            edits,
+            &vec![TextEdit::new(
+                Range::new(Position::new(0, 24), Position::new(0, 24)),
+                "]".to_string()
+            )]
+        );
+        assert_eq!(
+            apply_edits(text, edits),
+            "wire a; // @[A.scala:1:2]  \nwire b; // @[B.scala:3:4 // @[C.scala:5:6]"
+        );
+
+        let terminated = "x // @[A.scala:1:2]";
+        assert!(malformed_annotation_fixes(
+            terminated,
+            &find_annotations(terminated, &config),
+            &uri,
+            Range::new(Position::new(0, 0), Position::new(0, 19)),
+            &config
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn links_are_sorted_unless_order_is_preserved() {
+        let text = "x // @[/tmp/B.scala:9:1, /tmp/A.scala:20:4, :3:{8,2}, /tmp/B.scala:1:1]";
+        let uri = Url::parse("file:///tmp/Top.sv").unwrap();
+        let targets = |config: &Config| -> Vec<(String, u32, u32)> {
+            definition_links(
+                text,
+                &find_annotations(text, config),
+                &uri,
+                Position::new(0, 5),
+                config,
+                &TargetLines::default(),
+                &RealFileSystem,
+            )
+            .into_iter()
+            .map(|link| {
+                let start = link.target_range.start;
+                (
+                    link.target_uri.path().to_string(),
+                    start.line,
+                    start.character,
+                )
+            })
+            .collect()
+        };
+        let target = |path: &str, line, character| (path.to_string(), line, character);
+
+        assert_eq!(
+            targets(&Config::default()),
+            vec![
+                target("/tmp/A.scala", 2, 1),
+                target("/tmp/A.scala", 2, 7),
+                target("/tmp/A.scala", 19, 3),
+                target("/tmp/B.scala", 0, 0),
+                target("/tmp/B.scala", 8, 0),
+            ]
+        );
+
+        let preserved = Config {
+            preserve_link_order: true,
+            ..Config::default()
+        };
+        assert_eq!(
+            targets(&preserved),
+            vec![
+                target("/tmp/B.scala", 8, 0),
+                target("/tmp/A.scala", 19, 3),
+                target("/tmp/A.scala", 2, 7),
+                target("/tmp/A.scala", 2, 1),
+                target("/tmp/B.scala", 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn adjacent_annotations_stay_separate() {
+        let text = "x // @[/tmp/A.scala:1:2]@[/tmp/B.scala:3:4]";
+        let uri = Url::parse("file:///tmp/Top.sv").unwrap();
+        let config = Config::default();
+        let lines = compute_line_starts(text);
+        let second_start = text.rfind("@[").unwrap();
+
+        let spans = find_annotations(text, &config);
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].full_end, second_start);
+        assert_eq!(
+            find_annotation_at_offset(text, second_start, &config),
+            Some(spans[1].clone())
+        );
+        assert_eq!(
+            find_annotation_at_offset(text, second_start - 1, &config),
+            Some(spans[0].clone())
+        );
+
+        // The second annotation has no `//` of its own; its trigger must not
+        // reach back over the first one.
+        assert_eq!(
+            summary_hover_byte_range(text, &spans[1], &lines),
+            (second_start, second_start + 2)
+        );
+        assert_eq!(summary_hover_byte_range(text, &spans[0], &lines), (2, 7));
+
+        let target = |character: usize| {
+            definition_links(
+                text,
+                &find_annotations(text, &config),
+                &uri,
+                Position::new(0, character as u32),
+                &config,
+                &TargetLines::default(),
+                &RealFileSystem,
+            )
+            .into_iter()
+            .map(|link| link.target_uri.path().to_string())
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(target(second_start), vec!["/tmp/B.scala"]);
+        assert_eq!(target(second_start - 1), vec!["/tmp/A.scala"]);
+    }
+
+    #[tokio::test]
+    async fn adjacent_annotations_hover_the_one_under_the_caret() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/adjacent/Top.sv").unwrap();
+        let text = "x // @[/tmp/A.scala:1:2]@[/tmp/B.scala:3:4]";
+        open_document(backend, &uri, text).await;
+        let second_start = text.rfind("@[").unwrap() as u32;
+
+        let hover = hover_markdown(backend, &uri, 0, second_start)
+            .await
+            .unwrap();
+        assert!(hover.contains("```scala\n<source line unavailable>"));
+        assert_eq!(hover.matches("```scala").count(), 1);
+        let first = hover_markdown(backend, &uri, 0, 5).await.unwrap();
+        assert_eq!(first.matches("```scala").count(), 1);
+
+        assert_eq!(
+            goto_links(backend, &uri, 0, second_start + 3).await[0]
+                .target_uri
+                .path(),
+            "/tmp/B.scala"
+        );
+    }
+
+    #[tokio::test]
+    async fn same_line_annotations_merge_into_one_hover() {
+        let root = temp_dir("merge-same-line");
+        std::fs::write(root.join("A.scala"), "val a = 1\n").unwrap();
+        std::fs::write(root.join("B.scala"), "val b = 2\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        let text = "x // @[A.scala:1:5] y // @[B.scala:1:5]";
+        open_document(backend, &uri, text).await;
+        let gap = text.find(" y ").unwrap() as u32 + 1;
+        let second_token = text.rfind("B.scala").unwrap() as u32;
+
+        assert!(hover_markdown(backend, &uri, 0, gap).await.is_none());
+
+        backend.config.write().await.merge_same_line_annotations = true;
+        let merged = "```scala\nval a = 1\n    ^\n```\n```scala\nval b = 2\n    ^\n```";
+        assert_eq!(hover_markdown(backend, &uri, 0, gap).await.unwrap(), merged);
+        assert_eq!(hover_markdown(backend, &uri, 0, 5).await.unwrap(), merged);
+        assert_eq!(
+            hover_markdown(backend, &uri, 0, second_token)
+                .await
+                .unwrap(),
+            "```scala\nval b = 2\n    ^\n```\nB.scala:1:5"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn messy_annotations_canonicalize() {
+        let config = Config::default();
+        let canonical = |text: &str, position| {
+            canonical_annotation_edit(text, &find_annotations(text, &config), position, &config)
+        };
+        let text = "x // @[A:1:2 ,  :3:{5,4}] y";
+        let edit = canonical(text, Position::new(0, 8)).unwrap();
+        assert_eq!(edit.new_text, "A:1:2, :3:{5,4}");
+        assert_eq!(apply_edits(text, &[edit]), "x // @[A:1:2, :3:{5,4}] y");
+
+        let edit = canonical(
+            "// @[ \"a b.scala\":{2:1,3:4} <assert>,B:1_6:0x1f]",
+            Position::new(0, 6),
+        )
+        .unwrap();
+        assert_eq!(edit.new_text, "\"a b.scala\":{2:1, 3:4} <assert>, B:16:31");
+
+        assert!(canonical("// @[A:1:2, :3:4]", Position::new(0, 6)).is_none());
+        assert!(canonical("// @[A:1:2, bogus]", Position::new(0, 6)).is_none());
+    }
+
+    #[tokio::test]
+    async fn canonicalize_command_returns_workspace_edit() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/canonicalize/Top.sv").unwrap();
+        open_document(backend, &uri, "x // @[A:1:2 ,:3:4]").await;
+
+        let edit = backend
+            .execute_command(ExecuteCommandParams {
+                command: CANONICALIZE_ANNOTATION_COMMAND.to_string(),
+                arguments: vec![json!(uri.as_str()), json!({"line": 0, "character": 8})],
+                work_done_progress_params: WorkDoneProgressParams::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let edit: WorkspaceEdit = serde_json::from_value(edit).unwrap();
+        assert_eq!(edit.changes.unwrap()[&uri][0].new_text, "A:1:2, :3:4");
+
+        let actions = backend
+            .code_action(CodeActionParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+                range: Range::new(Position::new(0, 8), Position::new(0, 8)),
+                context: CodeActionContext::default(),
+                work_done_progress_params: WorkDoneProgressParams::default(),
+                partial_result_params: PartialResultParams::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            &actions[..],
+            [CodeActionOrCommand::CodeAction(action)]
+                if action.kind == Some(CodeActionKind::REFACTOR_REWRITE)
+        ));
+    }
+
+    #[tokio::test]
+    async fn list_source_files_counts_references_per_file() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/list-files/Top.sv").unwrap();
+        let text = "a // @[/tmp/B.scala:1:1, :2:2]\nb // @[/tmp/A.scala:3:3, /tmp/B.scala:4:{1,2}]";
+        open_document(backend, &uri, text).await;
+
+        let response = backend
+            .list_source_files(&[json!(uri.as_str())])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            response,
+            json!({ "files": [
+                { "uri": "file:///tmp/A.scala", "count": 1 },
+                { "uri": "file:///tmp/B.scala", "count": 3 },
+            ] })
+        );
+        assert!(backend.list_source_files(&[]).await.is_err());
+    }
+
+    /// Stands in for the client, recording every partial result batch.
+    #[derive(Default)]
+    struct CapturedPartials(std::sync::Mutex<Vec<(ProgressToken, Vec<Location>)>>);
+
+    impl PartialResultSink for CapturedPartials {
+        async fn send_partial(&self, token: &ProgressToken, locations: Vec<Location>) {
+            self.0.lock().unwrap().push((token.clone(), locations));
+        }
+    }
+
+    #[tokio::test]
+    async fn references_stream_one_partial_result_per_document() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let first = Url::parse("file:///tmp/references/A.sv").unwrap();
+        let second = Url::parse("file:///tmp/references/B.sv").unwrap();
+        let other = Url::parse("file:///tmp/references/C.sv").unwrap();
+        open_document(backend, &first, "a // @[/tmp/Foo.scala:2:1, :2:5]").await;
+        open_document(backend, &second, "b // @[/tmp/Foo.scala:{2:3, 4:1}]").await;
+        open_document(
+            backend,
+            &other,
+            "c // @[/tmp/Foo.scala:3:1, /tmp/Bar.scala:2:1]",
+        )
+        .await;
+
+        let params = |partial_result_token: Option<ProgressToken>| ReferenceParams {
+            text_document_position: position_params(
+                &Url::parse("file:///tmp/Foo.scala").unwrap(),
+                1,
+                0,
+            ),
+            context: ReferenceContext {
+                include_declaration: true,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams {
+                partial_result_token,
+            },
+        };
+
+        let sink = CapturedPartials::default();
+        let token = ProgressToken::String("refs".to_string());
+        let result = backend
+            .find_references(params(Some(token.clone())), &sink)
+            .await
+            .unwrap();
+        assert_eq!(result, Some(Vec::new()));
+        let partials = sink.0.into_inner().unwrap();
+        let batches: Vec<(ProgressToken, Vec<Url>)> = partials
+            .into_iter()
+            .map(|(token, locations)| (token, locations.into_iter().map(|l| l.uri).collect()))
+            .collect();
+        assert_eq!(
+            batches,
+            vec![
+                (token.clone(), vec![first.clone(), first.clone()]),
+                (token, vec![second.clone()]),
+            ]
+        );
+
+        let sink = CapturedPartials::default();
+        let all = backend
+            .find_references(params(None), &sink)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[2].range.start, Position::new(0, 7));
+        assert!(sink.0.into_inner().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn statistics_counts_annotations_tokens_and_resolved_targets() {
+        let root = temp_dir("statistics");
+        std::fs::write(root.join("A.scala"), "a\nb\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        let text = "a // @[A.scala:1:1, :2:2]\nb // @[Missing.scala:3:3, A.scala:4:{1,2}]\nc";
+        open_document(backend, &uri, text).await;
+
+        let response = backend
+            .statistics(&[json!(uri.as_str())])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            response,
+            json!({
+                "annotations": 2,
+                "tokens": 4,
+                "sourceFiles": 2,
+                "resolvedTokens": 3,
+                "resolvedPercent": 75.0,
+            })
+        );
+        assert!(backend.statistics(&[]).await.is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn github_permalinks_are_built_from_https_and_ssh_remotes() {
+        let sha = "0123abcd";
+        let expected =
+            "https://github.com/MrAMS/demo/blob/0123abcd/src/main/scala/My%20Top.scala#L12";
+        for remote in [
+            "https://github.com/MrAMS/demo",
+            "https://github.com/MrAMS/demo.git",
+            "git@github.com:MrAMS/demo.git",
+            "ssh://git@github.com/MrAMS/demo.git",
+        ] {
+            assert_eq!(
+                github_permalink(remote, sha, "src/main/scala/My Top.scala", 12).as_deref(),
+                Some(expected),
+                "{remote}"
+            );
+        }
+        assert_eq!(
+            github_permalink("https://gitlab.com/MrAMS/demo.git", sha, "A.scala", 1),
+            None
+        );
+        assert_eq!(
+            github_permalink("https://github.com/MrAMS", sha, "A.scala", 1),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn permalinks_fall_back_to_the_path_outside_git() {
+        let fs = Arc::new(MemoryFileSystem::default());
+        let root = PathBuf::from("/permalink");
+        fs.write(root.join("A.scala"), "class A\n");
+        let (service, _socket) = memory_test_service(&fs);
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(backend, &uri, "x // @[A.scala:1:2]").await;
+
+        let permalink = backend
+            .copy_permalink(&[json!(uri.as_str()), json!({"line": 0, "character": 16})])
+            .await
+            .unwrap();
+        assert_eq!(
+            permalink,
+            Some(json!(root.join("A.scala").display().to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn summarize_range_covers_every_overlapping_annotation() {
+        let fs = Arc::new(MemoryFileSystem::default());
+        let root = PathBuf::from("/summarize-range");
+        fs.write(root.join("A.scala"), "val a = 1\nval b = 2\n");
+        let (service, _socket) = memory_test_service(&fs);
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(
+            backend,
+            &uri,
+            "wire a; // @[A.scala:1:5]\nwire b; // @[A.scala:2:5]\nwire c; // @[A.scala:9:1]\n",
+        )
+        .await;
+        let summarize = |start: (u32, u32), end: (u32, u32)| {
+            let arguments = [
+                json!(uri.as_str()),
+                json!({
+                    "start": {"line": start.0, "character": start.1},
+                    "end": {"line": end.0, "character": end.1},
+                }),
+            ];
+            async move { backend.summarize_range(&arguments).await.unwrap() }
+        };
+
+        let summary = summarize((0, 3), (1, 15)).await.unwrap();
+        let summary = summary.as_str().unwrap();
+        assert_eq!(
+            summary,
+            "**Line 1**: `A.scala:1:5`\n\n```scala\nval a = 1\n    ^\n```\
+             \n\n---\n\n\
+             **Line 2**: `A.scala:2:5`\n\n```scala\nval b = 2\n    ^\n```"
+        );
+        assert!(summarize((0, 0), (0, 2)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn open_source_file_falls_back_to_the_line_without_columns() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/open-source/Top.sv").unwrap();
+        open_document(
+            backend,
+            &uri,
+            "wire x; // @[/tmp/A.scala:10:0, /tmp/B.scala:3:{0,0}]",
+        )
+        .await;
+        assert!(goto_links(backend, &uri, 0, 15).await.is_empty());
+
+        let open = |character: u32| {
+            let arguments = [
+                json!(uri.as_str()),
+                json!({"line": 0, "character": character}),
+            ];
+            async move { backend.open_source_file(&arguments).await }
+        };
+        assert_eq!(
+            open(15).await.unwrap(),
+            Some(json!({
+                "uri": "file:///tmp/A.scala",
+                "range": {"start": {"line": 9, "character": 0}, "end": {"line": 9, "character": 0}},
+            }))
+        );
+        assert_eq!(
+            open(35).await.unwrap().unwrap()["uri"],
+            "file:///tmp/B.scala"
+        );
+        assert_eq!(
+            open(11).await.unwrap().unwrap()["uri"],
+            "file:///tmp/A.scala"
+        );
+        assert_eq!(open(2).await.unwrap(), None);
+        assert!(backend
+            .open_source_file(&[json!(uri.as_str())])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn validate_workspace_reports_files_with_missing_targets() {
+        let root = temp_dir("validate-workspace");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join("out/skip")).unwrap();
+        std::fs::write(root.join("src/A.scala"), "class A\n").unwrap();
+        std::fs::write(
+            root.join("out/good.fir"),
+            "node x = a @[../src/A.scala:1:2]\nnode y = b @[../src/A.scala:1:2, :1:4]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("out/bad.firrtl"),
+            "node z = c @[../src/A.scala:1:1, ../src/Gone.scala:3:4]\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("out/skip/bad.fir"), "x @[Gone.scala:1:1]\n").unwrap();
+        std::fs::write(root.join("out/top.sv"), "x // @[Gone.scala:1:1]\n").unwrap();
+
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        *backend.workspace_roots.write().await = vec![root.clone()];
+        backend.config.write().await.exclude_dirs = vec!["out/skip".to_string()];
+
+        let report = backend.validate_workspace().await.unwrap().unwrap();
+        assert_eq!(
+            report,
+            json!({
+                "files": 2,
+                "tokens": 5,
+                "unresolvedTokens": 1,
+                "filesWithUnresolved": [{
+                    "uri": Url::from_file_path(root.join("out/bad.firrtl")).unwrap(),
+                    "unresolved": ["../src/Gone.scala:3:4"],
+                }],
+                "unreadable": [],
+            })
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn validate_workspace_skips_oversized_files() {
+        let fs = Arc::new(MemoryFileSystem::default());
+        let root = PathBuf::from("/validate");
+        fs.write(root.join("A.scala"), "class A\n");
+        fs.write(root.join("small.fir"), "x @[A.scala:1:1]\n");
+        fs.write(root.join("large.fir"), "y @[Gone.scala:2:2]\n".repeat(4));
+        let (service, _socket) = memory_test_service(&fs);
+        let backend = service.inner();
+        *backend.workspace_roots.write().await = vec![root.clone()];
+        backend.config.write().await.max_document_bytes = 32;
+
+        let report = backend.validate_workspace().await.unwrap().unwrap();
+        assert_eq!(report["files"], 1);
+        assert_eq!(report["unresolvedTokens"], 0);
+        assert_eq!(report["unreadable"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn export_annotations_skips_oversized_workspace_files() {
+        let fs = Arc::new(MemoryFileSystem::default());
+        let root = PathBuf::from("/work");
+        fs.write(root.join("small.fir"), "x @[A.scala:1:1]\n");
+        fs.write(root.join("large.fir"), "y @[B.scala:2:2]\n".repeat(4));
+        let (service, _socket) = memory_test_service(&fs);
+        let backend = service.inner();
+        *backend.workspace_roots.write().await = vec![root.clone()];
+        backend.config.write().await.max_document_bytes = 32;
+
+        let output = root.join("out/annotations.json");
+        let export = |output: String| {
+            let arguments = [json!("json"), json!(output), json!(true)];
+            async move { backend.export_annotations(&arguments).await }
+        };
+        let result = export(output.display().to_string()).await.unwrap().unwrap();
+        assert_eq!(result["records"], 1);
+        assert!(fs.read_to_string(&output).unwrap().contains("A.scala"));
+
+        let error = export(root.join("small.fir/out.json").display().to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::InternalError);
+        assert!(error.message.contains("small.fir/out.json"));
+    }
+
+    #[test]
+    fn side_by_side_payload_includes_both_locations() {
+        let text = "wire x;\nwire y; // @[/tmp/A.scala:10:3, /tmp/B.scala:2:1]";
+        let uri = Url::parse("file:///tmp/Top.sv").unwrap();
+
+        let payload = side_by_side_payload(
+            text,
+            &uri,
+            Position::new(1, 35),
+            &Config::default(),
+            &TargetLines::default(),
+            &RealFileSystem,
+        )
+        .unwrap();
+        assert_eq!(payload["source"]["uri"], "file:///tmp/Top.sv");
+        assert_eq!(
+            payload["source"]["range"]["start"],
+            json!({"line": 1, "character": 32})
+        );
+        assert_eq!(payload["target"]["uri"], "file:///tmp/B.scala");
+        assert_eq!(
+            payload["target"]["range"]["start"],
+            json!({"line": 1, "character": 0})
+        );
+
+        assert!(side_by_side_payload(
+            text,
+            &uri,
+            Position::new(0, 5),
+            &Config::default(),
+            &TargetLines::default(),
+            &RealFileSystem
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn line_text_at_supports_crlf() {
+        let text = "line1\r\nline2\r\nline3";
+        assert_eq!(line_text_at(text, 1), Some("line1"));
+        assert_eq!(line_text_at(text, 2), Some("line2"));
+        assert_eq!(line_text_at(text, 3), Some("line3"));
+        assert_eq!(line_text_at(text, 4), None);
+    }
+
+    #[test]
+    fn position_past_line_end_clamps_to_last_character() {
+        let text = "wire x; // @[/tmp/A.scala:10:3]\nnext";
+        let lines = compute_line_starts(text);
+        let offset = position_to_offset(Position::new(0, 500), text, &lines).unwrap();
+        assert_eq!(&text[offset..offset + 1], "]");
+        assert!(find_annotation_at_offset(text, offset, &Config::default()).is_some());
+    }
+
+    #[test]
+    fn position_past_last_line_end_clamps_without_newline() {
+        let text = "first\r\n// @[/tmp/A.scala:10:3]";
+        let lines = compute_line_starts(text);
+        let offset = position_to_offset(Position::new(1, 500), text, &lines).unwrap();
+        assert_eq!(offset, text.len() - 1);
+        assert!(find_annotation_at_offset(text, offset, &Config::default()).is_some());
+
+        let empty = "a\n\nb";
+        let lines = compute_line_starts(empty);
+        assert_eq!(
+            position_to_offset(Position::new(1, 9), empty, &lines),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn line_text_at_supports_cr_only() {
+        let text = "line1\rline2\r\rline4";
+        assert_eq!(line_text_at(text, 1), Some("line1"));
+        assert_eq!(line_text_at(text, 2), Some("line2"));
+        assert_eq!(line_text_at(text, 3), Some(""));
+        assert_eq!(line_text_at(text, 4), Some("line4"));
+        assert_eq!(line_text_at(text, 5), None);
+
+        assert_eq!(*compute_line_starts(text), [0, 6, 12, 13]);
+        assert_eq!(*compute_line_starts("a\r\nb\rc\n"), [0, 3, 5, 7]);
+    }
+
+    #[test]
+    fn column_indicator_marks_all_columns() {
+        let marker = build_column_indicator_line("abcdef", &[2, 5], '^');
+        assert_eq!(marker, " ^  ^");
+    }
+
+    #[test]
+    fn long_source_lines_are_windowed_around_the_columns() {
+        let line: String = (0..200)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        let (window, columns) = truncate_source_line(&line, &[190, 195], 40);
+        assert_eq!(window, format!("…{}", &line[160..]));
+        assert_eq!(columns, vec![31, 36]);
+        let marker = build_column_indicator_line(&window, &columns, '^');
+        for (column, original) in [(31, 190), (36, 195)] {
+            assert_eq!(marker.chars().nth(column - 1), Some('^'));
+            assert_eq!(
+                window.chars().nth(column - 1),
+                line.chars().nth(original - 1)
+            );
+        }
+
+        let (window, columns) = truncate_source_line(&line, &[100], 20);
+        assert_eq!(window, format!("…{}…", &line[89..109]));
+        assert_eq!(columns, vec![12]);
+
+        let (window, columns) = truncate_source_line("short", &[2], 20);
+        assert_eq!((window.as_str(), columns), ("short", vec![2]));
+    }
+
+    #[test]
+    fn indicator_styles_mark_the_same_columns() {
+        let expected = [
+            (IndicatorStyle::Caret, "\t^  ^"),
+            (IndicatorStyle::Underline, "\t‾  ‾"),
+            (IndicatorStyle::Tilde, "\t~  ~"),
+        ];
+        for (style, line) in expected {
+            assert_eq!(
+                build_column_indicator_line("\tabcdef", &[2, 5], style.marker()),
+                line
+            );
+        }
+        assert_eq!(
+            build_column_indicator_line("abc", &[], IndicatorStyle::Tilde.marker()),
+            "~"
+        );
+    }
+
+    #[test]
+    fn markdown_language_from_extension() {
+        assert_eq!(markdown_language_from_path("/tmp/src/Foo.scala"), "scala");
+        assert_eq!(markdown_language_from_path("/tmp/src/foo.fir"), "firrtl");
+        assert_eq!(markdown_language_from_path("/tmp/src/foo.unknown"), "text");
+    }
+
+    #[test]
+    fn markdown_language_from_modeline_or_shebang() {
+        assert_eq!(
+            markdown_language_from_header("// -*- mode: firrtl -*-\ncircuit Top :\n"),
+            Some("firrtl")
+        );
+        assert_eq!(
+            markdown_language_from_header("#!/usr/bin/env -S python3 -u\n"),
+            Some("python")
+        );
+        assert_eq!(
+            markdown_language_from_header("#!/bin/sh\n// vim: set ft=scala:\n"),
+            Some("scala")
+        );
+        assert_eq!(markdown_language_from_header("-*- C++ -*-\n"), None);
+        assert_eq!(markdown_language_from_header("plain text\n"), None);
+    }
+
+    fn sample_records() -> Vec<AnnotationRecord> {
+        let uri = Url::parse("file:///tmp/Top.sv").unwrap();
+        let text =
+            "wire x; // @[/tmp/A.scala:10:3, :11:{4,9}]\nwire y; // @[/tmp/My \"B\".scala:2:1]";
+        collect_annotation_records(&uri, text, &Config::default())
+    }
+
+    #[test]
+    fn export_records_as_csv() {
+        let records = sample_records();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2].annotation_line, 2);
+
+        assert_eq!(
+            records_to_csv(&records),
+            "source_uri,annotation_line,path,line,columns\n\
+             file:///tmp/Top.sv,1,/tmp/A.scala,10,3\n\
+             file:///tmp/Top.sv,1,/tmp/A.scala,11,4;9\n\
+             file:///tmp/Top.sv,2,\"/tmp/My \"\"B\"\".scala\",2,1\n"
+        );
+    }
+
+    #[test]
+    fn export_records_as_json() {
+        let records = sample_records();
+        let parsed: Value = serde_json::from_str(&records_to_json(&records)).unwrap();
+        let rows = parsed.as_array().unwrap();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[1]["path"], "/tmp/A.scala");
+        assert_eq!(rows[1]["line"], 11);
+        assert_eq!(rows[1]["columns"], json!([4, 9]));
+        assert_eq!(rows[2]["annotation_line"], 2);
+        assert_eq!(rows[0]["source_uri"], "file:///tmp/Top.sv");
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "firrtl-source-locator-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_service() -> (LspService<Backend>, tower_lsp::ClientSocket) {
+        LspService::new(Backend::new)
+    }
+
+    fn memory_test_service(
+        fs: &Arc<MemoryFileSystem>,
+    ) -> (LspService<Backend>, tower_lsp::ClientSocket) {
+        let fs: Arc<dyn FileSystem> = fs.clone();
+        LspService::new(move |client| Backend::with_file_system(client, fs))
+    }
+
+    async fn open_document(backend: &Backend, uri: &Url, text: &str) {
+        backend
+            .did_open(DidOpenTextDocumentParams {
+                text_document: TextDocumentItem::new(
+                    uri.clone(),
+                    "verilog".to_string(),
+                    1,
+                    text.to_string(),
+                ),
+            })
+            .await;
+    }
+
+    fn position_params(uri: &Url, line: u32, character: u32) -> TextDocumentPositionParams {
+        TextDocumentPositionParams::new(
+            TextDocumentIdentifier::new(uri.clone()),
+            Position::new(line, character),
+        )
+    }
+
+    async fn hover_markdown(
+        backend: &Backend,
+        uri: &Url,
+        line: u32,
+        character: u32,
+    ) -> Option<String> {
+        let hover = backend
+            .hover(HoverParams {
+                text_document_position_params: position_params(uri, line, character),
+                work_done_progress_params: WorkDoneProgressParams::default(),
+            })
+            .await
+            .unwrap()?;
+        match hover.contents {
+            HoverContents::Markup(content) => Some(content.value),
+            _ => None,
+        }
+    }
+
+    async fn goto_links(
+        backend: &Backend,
+        uri: &Url,
+        line: u32,
+        character: u32,
+    ) -> Vec<LocationLink> {
+        let response = backend
+            .goto_definition(GotoDefinitionParams {
+                text_document_position_params: position_params(uri, line, character),
+                work_done_progress_params: WorkDoneProgressParams::default(),
+                partial_result_params: PartialResultParams::default(),
+            })
+            .await
+            .unwrap();
+        match response {
+            Some(GotoDefinitionResponse::Link(links)) => links,
+            _ => Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn recent_jumps_lists_gotos_most_recent_first() {
+        let fs = Arc::new(MemoryFileSystem::default());
+        let root = PathBuf::from("/recent-jumps");
+        fs.write(root.join("A.scala"), "val a = 1\nval b = 2\n");
+        let (service, _socket) = memory_test_service(&fs);
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(
+            backend,
+            &uri,
+            "wire a; // @[A.scala:1:5]\nwire b; // @[A.scala:2:5]\nwire c;\n",
+        )
+        .await;
+
+        assert_eq!(goto_links(backend, &uri, 0, 15).await.len(), 1);
+        assert_eq!(goto_links(backend, &uri, 1, 15).await.len(), 1);
+        assert!(goto_links(backend, &uri, 2, 3).await.is_empty());
+
+        let history = backend
+            .execute_command(ExecuteCommandParams {
+                command: RECENT_JUMPS_COMMAND.to_string(),
+                arguments: Vec::new(),
+                work_done_progress_params: WorkDoneProgressParams::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let jumps: Vec<(u32, u32)> = history
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|jump| {
+                let line =
+                    |location: &Value| location["range"]["start"]["line"].as_u64().unwrap() as u32;
+                (line(&jump["source"]), line(&jump["targets"][0]))
+            })
+            .collect();
+        assert_eq!(jumps, vec![(1, 1), (0, 0)]);
+        assert_eq!(
+            history[0]["targets"][0]["uri"],
+            json!(Url::from_file_path(root.join("A.scala")).unwrap().as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn goto_definition_end_to_end() {
+        let root = temp_dir("e2e-goto");
+        std::fs::write(root.join("A.scala"), "class A {\n  val x = 1\n}\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(backend, &uri, "wire x; // @[A.scala:2:7, :3:{1,1}]").await;
+
+        let links = goto_links(backend, &uri, 0, 15).await;
+        let targets: Vec<(Url, Position)> = links
+            .iter()
+            .map(|link| (link.target_uri.clone(), link.target_range.start))
+            .collect();
+        let target_uri = Url::from_file_path(root.join("A.scala")).unwrap();
+        assert_eq!(
+            targets,
+            vec![
+                (target_uri.clone(), Position::new(1, 6)),
+                (target_uri, Position::new(2, 0)),
+            ]
+        );
+
+        assert!(goto_links(backend, &uri, 0, 2).await.is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn zero_based_targets_skip_the_column_adjustment() {
+        let root = temp_dir("zero-based");
+        std::fs::write(root.join("A.scala"), "val abc = 1\n").unwrap();
+        std::fs::write(root.join("a.td"), "val abc = 1\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        backend.config.write().await.zero_based_columns_for = vec![".td".to_string()];
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(
+            backend,
+            &uri,
+            "wire x; // @[A.scala:1:5]\nwire y; // @[a.td:1:4]",
+        )
+        .await;
+
+        let one_based = hover_markdown(backend, &uri, 0, 15).await.unwrap();
+        let zero_based = hover_markdown(backend, &uri, 1, 15).await.unwrap();
+        assert_eq!(one_based, "```scala\nval abc = 1\n    ^\n```\nA.scala:1:5");
+        assert_eq!(zero_based, "```text\nval abc = 1\n    ^\n```\na.td:1:4");
+
+        let start = |links: Vec<LocationLink>| links[0].target_range.start;
+        assert_eq!(
+            start(goto_links(backend, &uri, 0, 15).await),
+            Position::new(0, 4)
+        );
+        assert_eq!(
+            start(goto_links(backend, &uri, 1, 15).await),
+            Position::new(0, 4)
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn goto_on_line_or_column_number_narrows_the_target() {
+        let root = temp_dir("goto-numbers");
+        std::fs::write(root.join("A.scala"), "class A {\n  val x = 1\n}\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(backend, &uri, "wire x; // @[A.scala:2:{7,9}]").await;
+
+        let starts = |links: Vec<LocationLink>| -> Vec<Position> {
+            links.iter().map(|link| link.target_range.start).collect()
+        };
+        assert_eq!(
+            starts(goto_links(backend, &uri, 0, 15).await),
+            vec![Position::new(1, 6), Position::new(1, 8)]
+        );
+        assert_eq!(
+            starts(goto_links(backend, &uri, 0, 21).await),
+            vec![Position::new(1, 0)]
+        );
+        assert_eq!(
+            starts(goto_links(backend, &uri, 0, 24).await),
+            vec![Position::new(1, 6)]
+        );
+        assert_eq!(
+            starts(goto_links(backend, &uri, 0, 26).await),
+            vec![Position::new(1, 8)]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn number_spans_cover_line_and_each_column() {
+        let token = "A.scala:12:{7, 0x1f} <assert>";
+        let (line, columns) = locator_number_spans(token).unwrap();
+        assert_eq!(&token[line.0..line.1], "12");
+        let columns: Vec<&str> = columns.iter().map(|(s, e)| &token[*s..*e]).collect();
+        assert_eq!(columns, vec!["7", "0x1f"]);
+
+        let (line, columns) = locator_number_spans(":+3:4").unwrap();
+        assert_eq!((line, columns), ((1, 3), vec![(4, 5)]));
+        assert!(locator_number_spans("A.scala:{10:3, 12:5}").is_none());
+    }
+
+    #[tokio::test]
+    async fn hover_fence_follows_modeline_of_unknown_extension() {
+        let root = temp_dir("modeline");
+        std::fs::write(
+            root.join("Top.gen"),
+            "; -*- mode: firrtl -*-\ncircuit Top :\n",
+        )
+        .unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(backend, &uri, "wire x; // @[Top.gen:2:1]").await;
+
+        assert_eq!(
+            hover_markdown(backend, &uri, 0, 15).await.unwrap(),
+            "```firrtl\ncircuit Top :\n^\n```\nTop.gen:2:1"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hover_follows_locators_into_firrtl_files_one_level() {
+        let root = temp_dir("follow-firrtl");
+        std::fs::write(root.join("A.scala"), "class A {\n  val x = 1\n}\n").unwrap();
+        std::fs::write(
+            root.join("Core.fir"),
+            "circuit Core :\n  node x = a @[A.scala:2:7, Top.sv:1:1, Core.fir:1:1]\n",
+        )
+        .unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(backend, &uri, "wire x; // @[Core.fir:2:3]").await;
+
+        let plain = "```firrtl\n  node x = a @[A.scala:2:7, Top.sv:1:1, Core.fir:1:1]\n  ^\n```\nCore.fir:2:3";
+        assert_eq!(hover_markdown(backend, &uri, 0, 15).await.unwrap(), plain);
+
+        backend.config.write().await.follow_firrtl_targets = true;
+        assert_eq!(
+            hover_markdown(backend, &uri, 0, 15).await.unwrap(),
+            format!("{plain}\n\nFollowed into `A.scala:2:7`:\n```scala\n  val x = 1\n      ^\n```")
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hover_end_to_end() {
+        let root = temp_dir("e2e-hover");
+        std::fs::write(root.join("A.scala"), "class A {\n  val x = 1\n}\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(backend, &uri, "wire x; // @[A.scala:2:7]").await;
+
+        let token_hover = hover_markdown(backend, &uri, 0, 15).await.unwrap();
+        assert_eq!(
+            token_hover,
+            "```scala\n  val x = 1\n      ^\n```\nA.scala:2:7"
+        );
+
+        let summary_hover = hover_markdown(backend, &uri, 0, 11).await.unwrap();
+        assert_eq!(summary_hover, "```scala\n  val x = 1\n      ^\n```");
+
+        assert!(hover_markdown(backend, &uri, 0, 2).await.is_none());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn strip_display_prefix_only_affects_the_hover_text() {
+        let root = temp_dir("strip-prefix");
+        std::fs::write(root.join("A.scala"), "class A {\n  val x = 1\n}\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let prefix = format!("{}/", root.display());
+        backend.config.write().await.strip_display_prefix = Some(prefix.clone());
+        let uri = Url::parse("file:///tmp/strip-prefix-doc/Top.sv").unwrap();
+        open_document(backend, &uri, &format!("wire x; // @[{prefix}A.scala:2:7]")).await;
+
+        let hover = hover_markdown(backend, &uri, 0, 15).await.unwrap();
+        assert_eq!(hover, "```scala\n  val x = 1\n      ^\n```\nA.scala:2:7");
+        let links = goto_links(backend, &uri, 0, 15).await;
+        assert_eq!(
+            links[0].target_uri,
+            Url::from_file_path(root.join("A.scala")).unwrap()
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn columns_past_the_line_end_clamp_to_the_last_column() {
+        let root = temp_dir("column-clamp");
+        std::fs::write(root.join("A.scala"), "val x\nclass A {}\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(backend, &uri, "wire x; // @[A.scala:1:40]").await;
+
+        let hover = hover_markdown(backend, &uri, 0, 15).await.unwrap();
+        assert_eq!(
+            hover,
+            "```scala\nval x\n    ^\n```\n\
+             _Column 40 is past the end of line 1 (5 columns); marked at the last column._\n\
+             A.scala:1:40"
+        );
+        let links = goto_links(backend, &uri, 0, 15).await;
+        assert_eq!(links[0].target_range.start, Position::new(0, 4));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn explanatory_hover_describes_the_mapped_columns() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        backend.config.write().await.explanatory_hover = true;
+        let uri = Url::parse("file:///tmp/explain/Top.sv").unwrap();
+        open_document(
+            backend,
+            &uri,
+            "a // @[A.scala:10:{3,9}]\nb // @[A.scala:10:{3,9}, :12:1, B.scala:{1:1, 2:2}]",
+        )
+        .await;
+
+        let single = hover_markdown(backend, &uri, 0, 5).await.unwrap();
+        assert!(single.starts_with(
+            "_This annotation links generated code to 2 source columns on line 10 of A.scala._\n\n```"
+        ));
+        let several = hover_markdown(backend, &uri, 1, 5).await.unwrap();
+        assert!(several.starts_with(
+            "_This annotation links generated code to 2 source columns on line 10 of A.scala, \
+             1 source column on line 12 of A.scala, 1 source column on line 1 of B.scala \
+             and 1 source column on line 2 of B.scala._"
+        ));
+
+        backend.config.write().await.explanatory_hover = false;
+        assert!(hover_markdown(backend, &uri, 0, 5)
+            .await
+            .unwrap()
+            .starts_with("```"));
+    }
+
+    #[tokio::test]
+    async fn chain_context_lists_neighbouring_tokens() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        backend.config.write().await.show_chain_context = true;
+        let uri = Url::parse("file:///tmp/chain-context/Top.sv").unwrap();
+        open_document(
+            backend,
+            &uri,
+            "x // @[/tmp/A.scala:1:2, /tmp/B.scala:3:4, :5:{6,7}]",
+        )
+        .await;
+
+        let middle = hover_markdown(backend, &uri, 0, 40).await.unwrap();
+        assert!(middle
+            .ends_with("/tmp/B.scala:3:4\nfrom `/tmp/A.scala:1:2`\ninto `/tmp/B.scala:5:{6,7}`"));
+        let first = hover_markdown(backend, &uri, 0, 21).await.unwrap();
+        assert!(first.ends_with("/tmp/A.scala:1:2\ninto `/tmp/B.scala:3:4`"));
+        let last = hover_markdown(backend, &uri, 0, 45).await.unwrap();
+        assert!(last.ends_with("/tmp/B.scala:5:{6,7}\nfrom `/tmp/B.scala:3:4`"));
+
+        backend.config.write().await.show_chain_context = false;
+        let plain = hover_markdown(backend, &uri, 0, 40).await.unwrap();
+        assert!(plain.ends_with("```\n/tmp/B.scala:3:4"));
+    }
+
+    #[tokio::test]
+    async fn generated_line_heads_token_hovers_when_enabled() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/generated-line/Top.fir").unwrap();
+        open_document(
+            backend,
+            &uri,
+            "circuit Top :\n    node x = add(a, b) @[/tmp/A.scala:1:2]\n",
+        )
+        .await;
+
+        let plain = hover_markdown(backend, &uri, 1, 38).await.unwrap();
+        assert!(plain.starts_with("```scala\n"));
+        backend.config.write().await.show_generated_line = true;
+        let with_generated = hover_markdown(backend, &uri, 1, 38).await.unwrap();
+        assert_eq!(
+            with_generated,
+            format!("```firrtl\nnode x = add(a, b) @[/tmp/A.scala:1:2]\n```\n{plain}")
+        );
+    }
+
+    #[tokio::test]
+    async fn reference_count_counts_tokens_at_the_same_location() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        backend.config.write().await.show_reference_count = true;
+        let uri = Url::parse("file:///tmp/reference-count/Top.sv").unwrap();
+        open_document(
+            backend,
+            &uri,
+            "x // @[/tmp/A.scala:1:2, /tmp/B.scala:3:4]\ny // @[/tmp/A.scala:1:2, :1:3]\n",
+        )
+        .await;
+
+        let repeated = hover_markdown(backend, &uri, 0, 21).await.unwrap();
+        assert!(repeated.ends_with("/tmp/A.scala:1:2\nreferenced 2× in this file"));
+        let single = hover_markdown(backend, &uri, 0, 40).await.unwrap();
+        assert!(single.ends_with("/tmp/B.scala:3:4\nreferenced 1× in this file"));
+
+        backend.config.write().await.show_reference_count = false;
+        let plain = hover_markdown(backend, &uri, 0, 21).await.unwrap();
+        assert!(plain.ends_with("```\n/tmp/A.scala:1:2"));
+    }
+
+    #[tokio::test]
+    async fn goto_response_kind_selects_links_or_locations() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/response-kind/Top.sv").unwrap();
+        open_document(backend, &uri, "x // @[/tmp/A.scala:2:4, :3:1]").await;
+        let goto = || {
+            backend.goto_definition(GotoDefinitionParams {
+                text_document_position_params: position_params(&uri, 0, 5),
+                work_done_progress_params: WorkDoneProgressParams::default(),
+                partial_result_params: PartialResultParams::default(),
+            })
+        };
+
+        let Some(GotoDefinitionResponse::Link(links)) = goto().await.unwrap() else {
+            panic!("expected links by default");
+        };
+        assert_eq!(links.len(), 2);
+
+        backend.config.write().await.goto_response_kind = GotoResponseKind::Location;
+        let Some(GotoDefinitionResponse::Array(locations)) = goto().await.unwrap() else {
+            panic!("expected plain locations");
+        };
+        let target = Url::parse("file:///tmp/A.scala").unwrap();
+        assert_eq!(
+            locations,
+            vec![
+                Location::new(
+                    target.clone(),
+                    Range::new(Position::new(1, 3), Position::new(1, 4))
+                ),
+                Location::new(target, Range::new(Position::new(2, 0), Position::new(2, 1))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn max_goto_links_caps_summary_definitions() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        backend.config.write().await.max_goto_links = Some(5);
+        let uri = Url::parse("file:///tmp/max-goto/Top.sv").unwrap();
+        let columns: Vec<String> = (1..=30).map(|column| column.to_string()).collect();
+        let text = format!(
+            "x // @[/tmp/A.scala:7:{{{}}}] // @[/tmp/B.scala:1:2]",
+            columns.join(",")
+        );
+        open_document(backend, &uri, &text).await;
+
+        let links = goto_links(backend, &uri, 0, 5).await;
+        assert_eq!(links.len(), 5);
+        let characters: Vec<u32> = links
+            .iter()
+            .map(|link| link.target_range.start.character)
+            .collect();
+        assert_eq!(characters, vec![0, 1, 2, 3, 4]);
+
+        let single = text.rfind("1:2").unwrap() as u32 + 2;
+        assert_eq!(goto_links(backend, &uri, 0, single).await.len(), 1);
+
+        let pairs: Vec<String> = (1..=30).map(|line| format!("{line}:1")).collect();
+        let pair_set = format!("y // @[/tmp/C.scala:{{{}}}]", pairs.join(", "));
+        let pair_uri = Url::parse("file:///tmp/max-goto/Pairs.sv").unwrap();
+        open_document(backend, &pair_uri, &pair_set).await;
+        let on_pair = pair_set.find("2:1").unwrap() as u32;
+        assert_eq!(goto_links(backend, &pair_uri, 0, on_pair).await.len(), 30);
+
+        backend.config.write().await.max_goto_links = None;
+        assert_eq!(goto_links(backend, &uri, 0, 5).await.len(), 30);
+    }
+
+    #[tokio::test]
+    async fn line_past_end_of_target_is_diagnosed() {
+        let root = temp_dir("line-overflow");
+        std::fs::write(root.join("A.scala"), "class A {\n  val x = 1\n}\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        let text = "wire x; // @[A.scala:3:1, A.scala:40:2]";
+
+        assert!(backend.compute_diagnostics(&uri, text).await.is_empty());
+
+        *backend.config.write().await = Config {
+            diagnose_line_overflow: true,
+            ..Config::default()
+        };
+        let diagnostics = backend.compute_diagnostics(&uri, text).await;
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(
+            diagnostics[0].range,
+            Range::new(Position::new(0, 26), Position::new(0, 38))
+        );
+        assert_eq!(
+            diagnostics[0].message,
+            "line 40 is past the end of `A.scala` (3 lines)"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn blank_target_line_hovers_placeholder() {
+        let root = temp_dir("blank-line");
+        std::fs::write(root.join("A.scala"), "class A {\n    \n}\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(backend, &uri, "wire x; // @[A.scala:2:3]").await;
+
+        assert_eq!(
+            hover_markdown(backend, &uri, 0, 15).await.unwrap(),
+            "```scala\n<blank line>\n```\n_Line 2 of the target is blank._\nA.scala:2:3"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn copy_source_line_returns_trimmed_lines() {
+        let root = temp_dir("copy-source-line");
+        std::fs::write(
+            root.join("A.scala"),
+            "class A {\n  val x = 1\n  val y = 2\n}\n",
+        )
+        .unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(
+            backend,
+            &uri,
+            "wire x; // @[A.scala:2:7, :3:7, Missing.scala:4:1]",
+        )
+        .await;
+
+        let copy = |character: u32| {
+            let arguments = [
+                json!(uri.as_str()),
+                json!({"line": 0, "character": character}),
+            ];
+            async move { backend.copy_source_line(&arguments).await.unwrap() }
+        };
+        assert_eq!(copy(15).await, Some(json!("val x = 1")));
+        assert_eq!(
+            copy(37).await,
+            Some(json!("<source line unavailable: Missing.scala:4>"))
+        );
+        assert_eq!(
+            copy(11).await,
+            Some(json!(
+                "val x = 1\nval y = 2\n<source line unavailable: Missing.scala:4>"
+            ))
+        );
+        assert_eq!(copy(2).await, None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn slow_disk_read_times_out() {
+        let root = temp_dir("read-timeout");
+        let fifo = root.join("stalled.scala");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        // Opening a FIFO for reading blocks until a writer shows up.
+        let fs: Arc<dyn FileSystem> = Arc::new(RealFileSystem);
+        assert_eq!(
+            read_file_with_timeout(&fs, &fifo, Duration::from_millis(50)).await,
+            Err(ResolveError::ReadError(
+                fifo.clone(),
+                "timed out after 50 ms".to_string()
+            ))
+        );
+
+        // Unblock the abandoned read so the runtime can shut down.
+        drop(std::fs::OpenOptions::new().write(true).open(&fifo).unwrap());
+        std::fs::write(root.join("A.scala"), "class A\n").unwrap();
+        assert_eq!(
+            read_file_with_timeout(&fs, &root.join("A.scala"), Duration::from_secs(5)).await,
+            Ok(("class A\n".to_string(), false))
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_files_are_not_read_from_disk() {
+        let root = temp_dir("max-document-bytes");
+        let big = root.join("Big.scala");
+        std::fs::write(&big, "x".repeat(64)).unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        *backend.config.write().await = Config {
+            max_document_bytes: 32,
+            ..Config::default()
+        };
+        let big_uri = Url::from_file_path(&big).unwrap();
+
+        assert_eq!(backend.read_document(&big_uri).await, None);
+
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(backend, &uri, "wire x; // @[Big.scala:1:1]").await;
+        assert_eq!(
+            hover_markdown(backend, &uri, 0, 15).await.unwrap(),
+            "```scala\n<source line unavailable>\n^\n```\nBig.scala:1:1"
+        );
+
+        // Buffers already open in the editor are exempt from the limit.
+        open_document(backend, &big_uri, &"x".repeat(64)).await;
+        assert!(backend.read_document(&big_uri).await.is_some());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unresolved_path_hover_lists_candidates() {
+        let root = temp_dir("unresolved-path");
+        let extra_root = root.join("extra");
+        std::fs::create_dir_all(&extra_root).unwrap();
+        std::fs::write(root.join("A.scala"), "class A {\n  val x = 1\n}\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        *backend.config.write().await = Config {
+            source_roots: vec![extra_root.clone()],
+            ..Config::default()
+        };
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        open_document(backend, &uri, "wire x; // @[Gone.scala:2:7, A.scala:2:7]").await;
+
+        let hover = hover_markdown(backend, &uri, 0, 15).await.unwrap();
+        assert_eq!(
+            hover,
+            format!(
+                "**Unresolved path** `Gone.scala`\n\nTried:\n- `{}` (missing)\n- `{}` (missing)",
+                root.join("Gone.scala").display(),
+                extra_root.join("Gone.scala").display()
+            )
+        );
+
+        // Line/column part and resolvable paths keep the source-line hover.
+        assert!(hover_markdown(backend, &uri, 0, 25)
+            .await
+            .unwrap()
+            .starts_with("```scala"));
+        assert!(hover_markdown(backend, &uri, 0, 31)
+            .await
+            .unwrap()
+            .starts_with("```scala"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resolution_failures_report_their_reason() {
+        let untitled = Url::parse("untitled:Top.sv").unwrap();
+        assert_eq!(
+            resolve_target_url("A.scala", &untitled, &Config::default(), &RealFileSystem),
+            Err(ResolveError::SourceHasNoParent(untitled.clone()))
+        );
+        let relative_root = Config {
+            source_roots: vec![PathBuf::from("gen")],
+            ..Config::default()
+        };
+        assert_eq!(
+            resolve_target_url("A.scala", &untitled, &relative_root, &RealFileSystem),
+            Err(ResolveError::NotAbsolute(PathBuf::from("gen/A.scala")))
+        );
+        // The reason names the allowed candidate that failed, not the first one tried.
+        let behind_base_dir = Config {
+            base_dir: Some(PathBuf::from("/elsewhere")),
+            allowed_resolve_roots: vec![PathBuf::from("gen")],
+            ..relative_root.clone()
+        };
+        assert_eq!(
+            resolve_target_url(
+                "A.scala",
+                &untitled,
+                &behind_base_dir,
+                &MemoryFileSystem::default()
+            ),
+            Err(ResolveError::NotAbsolute(PathBuf::from("gen/A.scala")))
+        );
+
+        let root = temp_dir("resolve-errors");
+        std::fs::write(root.join("Big.scala"), "class Big\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        assert_eq!(
+            backend
+                .read_locator_line("Missing.scala", 1, &uri, RequestToken::DETACHED)
+                .await,
+            Err(ResolveError::FileNotFound(root.join("Missing.scala")))
+        );
+
+        backend.config.write().await.max_document_bytes = 4;
+        assert!(matches!(
+            backend
+                .read_locator_line("Big.scala", 1, &uri, RequestToken::DETACHED)
+                .await,
+            Err(ResolveError::ReadError(path, _)) if path == root.join("Big.scala")
+        ));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn gzip_sources_are_read_when_enabled() {
+        use std::io::Write;
+
+        let root = temp_dir("gzip-sources");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"class A {\n  val x = 1\n}\n").unwrap();
+        std::fs::write(root.join("A.scala.gz"), encoder.finish().unwrap()).unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+
+        assert_eq!(
+            backend
+                .read_locator_line("A.scala", 2, &uri, RequestToken::DETACHED)
+                .await,
+            Err(ResolveError::FileNotFound(root.join("A.scala")))
+        );
+
+        backend.config.write().await.support_gzip_sources = true;
+        for path in ["A.scala", "A.scala.gz"] {
+            assert_eq!(
+                backend
+                    .read_locator_line(path, 2, &uri, RequestToken::DETACHED)
+                    .await,
+                Ok(Some("  val x = 1".to_string())),
+                "{path}"
+            );
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn latin1_targets_are_decoded_lossily_with_a_note() {
+        let fs = Arc::new(MemoryFileSystem::default());
+        // `café` in Latin-1: `é` is the lone byte 0xE9.
+        fs.write("/mem/latin1/A.scala", b"// caf\xe9\nval x = 1\n".to_vec());
+        let (service, _socket) = memory_test_service(&fs);
+        let backend = service.inner();
+        let uri = Url::parse("file:///mem/latin1/Top.sv").unwrap();
+
+        assert_eq!(
+            backend
+                .read_locator_line("A.scala", 1, &uri, RequestToken::DETACHED)
+                .await,
+            Ok(Some("// caf\u{FFFD}".to_string()))
+        );
+        open_document(backend, &uri, "x // @[A.scala:1:4]").await;
+        let hover = hover_markdown(backend, &uri, 0, 17).await.unwrap();
+        assert!(hover.contains("// caf\u{FFFD}\n   ^\n```\n_The target is not valid UTF-8"));
+
+        fs.write("/mem/latin1/A.scala", "// café\n");
+        backend.source_lines.lock().await.clear();
+        let hover = hover_markdown(backend, &uri, 0, 17).await.unwrap();
+        assert!(hover.contains("// café\n") && !hover.contains("not valid UTF-8"));
+    }
+
+    #[tokio::test]
+    async fn jar_entries_are_read_when_enabled() {
+        use std::io::Write;
+
+        let fs = Arc::new(MemoryFileSystem::default());
+        let root = PathBuf::from("/jar-sources");
+        let jar = root.join("sources.jar");
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        writer
+            .start_file("pkg/Foo.scala", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        writer
+            .write_all(b"package pkg\nclass Foo { val x = 1 }\n")
+            .unwrap();
+        fs.write(&jar, writer.finish().unwrap().into_inner());
+
+        let (service, _socket) = memory_test_service(&fs);
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.fir")).unwrap();
+        let locator = format!("jar:{}!/pkg/Foo.scala", Url::from_file_path(&jar).unwrap());
+        let (parsed, _) = parse_locator_token(&format!("{locator}:2:13"), None, 0).unwrap();
+        assert_eq!(parsed.path, locator);
+
+        assert!(backend
+            .read_locator_line(&locator, 2, &uri, RequestToken::DETACHED)
+            .await
+            .is_err());
+        backend.config.write().await.support_jar_sources = true;
+        assert_eq!(
+            backend
+                .read_locator_line(&locator, 2, &uri, RequestToken::DETACHED)
+                .await,
+            Ok(Some("class Foo { val x = 1 }".to_string()))
+        );
+        let missing = format!("jar:{}!/pkg/Gone.scala", Url::from_file_path(&jar).unwrap());
+        assert_eq!(
+            backend
+                .read_locator_line(&missing, 1, &uri, RequestToken::DETACHED)
+                .await,
+            Err(ResolveError::ReadError(
+                jar.clone(),
+                "no entry `pkg/Gone.scala`".to_string()
+            ))
+        );
+        let resolved = {
+            let config = backend.config.read().await;
+            resolve_target_url(&locator, &uri, &config, fs.as_ref())
+        };
+        assert_eq!(
+            resolved,
+            Ok(jarsource::entry_url(&jar, "pkg/Foo.scala").unwrap())
+        );
+
+        backend.config.write().await.max_document_bytes = 8;
+        assert_eq!(
+            backend
+                .read_locator_line(&locator, 1, &uri, RequestToken::DETACHED)
+                .await,
+            Err(ResolveError::ReadError(
+                jar.clone(),
+                "larger than maxDocumentBytes (8)".to_string()
+            ))
+        );
+        backend.config.write().await.max_document_bytes = Config::default().max_document_bytes;
+
+        backend.config.write().await.allowed_resolve_roots = vec![root.join("elsewhere")];
+        assert_eq!(
+            backend
+                .read_locator_line(&locator, 1, &uri, RequestToken::DETACHED)
+                .await,
+            Err(ResolveError::OutsideAllowedRoots(jar.clone()))
+        );
+        backend.config.write().await.allowed_resolve_roots = vec![root.clone()];
+        assert_eq!(
+            backend
+                .read_locator_line(&locator, 1, &uri, RequestToken::DETACHED)
+                .await,
+            Ok(Some("package pkg".to_string()))
+        );
+
+        // Go to Definition opens an extracted copy, extracted again once it is gone.
+        open_document(backend, &uri, &format!("wire x; // @[{locator}:2:13]\n")).await;
+        let extracted = || async {
+            let links = goto_links(backend, &uri, 0, 15).await;
+            assert_eq!(links.len(), 1);
+            links[0].target_uri.to_file_path().unwrap()
+        };
+        let copy = extracted().await;
+        assert!(copy.ends_with("pkg/Foo.scala"));
+        fs.remove(&copy);
+        assert_eq!(extracted().await, copy);
+        assert_eq!(
+            fs.read_to_string(&copy).unwrap(),
+            "package pkg\nclass Foo { val x = 1 }\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn source_lines_are_cached_until_the_file_changes() {
+        let root = temp_dir("line-cache");
+        let target = root.join("A.scala");
+        std::fs::write(&target, "class A {\n  val x = 1\n}\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        let read = || backend.read_locator_line("A.scala", 2, &uri, RequestToken::DETACHED);
+
+        assert_eq!(read().await, Ok(Some("  val x = 1".to_string())));
+        std::fs::write(&target, "class A {\n  val y = 2\n}\n").unwrap();
+        assert_eq!(read().await, Ok(Some("  val x = 1".to_string())));
+
+        backend
+            .did_change_watched_files(DidChangeWatchedFilesParams {
+                changes: vec![FileEvent::new(
+                    Url::from_file_path(&target).unwrap(),
+                    FileChangeType::CHANGED,
+                )],
+            })
+            .await;
+        assert_eq!(read().await, Ok(Some("  val y = 2".to_string())));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn resolution_runs_against_an_in_memory_tree() {
+        let fs = MemoryFileSystem::default();
+        let uri = Url::parse("file:///mem/out/Top.sv").unwrap();
+        let config = Config {
+            source_roots: vec![PathBuf::from("/mem/src")],
+            ..Config::default()
+        };
+        let resolve = || resolve_target_url("A.scala", &uri, &config, &fs);
+
+        // Nothing exists yet, so the first candidate is returned as a plain join.
+        assert_eq!(
+            resolve(),
+            Ok(Url::parse("file:///mem/out/A.scala").unwrap())
+        );
+        fs.write("/mem/src/A.scala", "class A\n");
+        assert_eq!(
+            resolve(),
+            Ok(Url::parse("file:///mem/src/A.scala").unwrap())
+        );
+        assert!(unresolved_path_markdown("A.scala", &uri, &config, &fs).is_none());
+
+        // The manifest is reloaded when a rewrite advances its mtime.
+        fs.write(
+            "/mem/out/Top.sv.sourcemap",
+            r#"{"A.scala": "/mem/gen/A.scala"}"#,
+        );
+        assert_eq!(
+            resolve(),
+            Ok(Url::parse("file:///mem/gen/A.scala").unwrap())
+        );
+        fs.write(
+            "/mem/out/Top.sv.sourcemap",
+            r#"{"A.scala": "/mem/src/A.scala"}"#,
+        );
+        assert_eq!(
+            resolve(),
+            Ok(Url::parse("file:///mem/src/A.scala").unwrap())
+        );
+
+        fs.write(
+            "/mem/build/compile_commands.json",
+            r#"[{"directory": "/mem/lib", "file": "/mem/out/Top.sv"}]"#,
+        );
+        fs.write("/mem/lib/B.scala", "class B\n");
+        let with_database = Config {
+            compile_commands: Some(PathBuf::from("/mem/build/compile_commands.json")),
+            ..config.clone()
+        };
+        assert_eq!(
+            resolve_target_url("B.scala", &uri, &with_database, &fs),
+            Ok(Url::parse("file:///mem/lib/B.scala").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn in_memory_targets_are_read_and_evicted_on_change() {
+        let fs = Arc::new(MemoryFileSystem::default());
+        fs.write("/mem/cache/A.scala", "class A {\n  val x = 1\n}\n");
+        let (service, _socket) = memory_test_service(&fs);
+        let backend = service.inner();
+        let uri = Url::parse("file:///mem/cache/Top.sv").unwrap();
+        let read = || backend.read_locator_line("A.scala", 2, &uri, RequestToken::DETACHED);
+
+        assert_eq!(read().await, Ok(Some("  val x = 1".to_string())));
+        fs.write("/mem/cache/A.scala", "class A {\n  val y = 2\n}\n");
+        assert_eq!(read().await, Ok(Some("  val x = 1".to_string())));
+
+        backend
+            .did_change_watched_files(DidChangeWatchedFilesParams {
+                changes: vec![FileEvent::new(
+                    Url::parse("file:///mem/cache/A.scala").unwrap(),
+                    FileChangeType::CHANGED,
+                )],
+            })
+            .await;
+        assert_eq!(read().await, Ok(Some("  val y = 2".to_string())));
+
+        fs.remove(Path::new("/mem/cache/A.scala"));
+        backend.source_lines.lock().await.clear();
+        assert_eq!(
+            read().await,
+            Err(ResolveError::FileNotFound(PathBuf::from(
+                "/mem/cache/A.scala"
+            )))
+        );
+    }
+
+    #[test]
+    fn source_line_cache_evicts_least_recently_used() {
+        let a = Url::parse("file:///A.scala").unwrap();
+        let mut cache = SourceLineCache::default();
+        cache.insert(&a, 1, "one".to_string(), 2);
+        cache.insert(&a, 2, "two".to_string(), 2);
+        assert_eq!(cache.get(&a, 1), Some("one".to_string()));
+        cache.insert(&a, 3, "three".to_string(), 2);
+        assert_eq!(cache.get(&a, 2), None);
+        assert_eq!(cache.get(&a, 1), Some("one".to_string()));
+        assert_eq!(cache.get(&a, 3), Some("three".to_string()));
+
+        cache.insert(&a, 4, "four".to_string(), 0);
+        assert_eq!(cache.get(&a, 1), None);
+    }
+
+    #[test]
+    fn git_show_output_yields_the_requested_line() {
+        let text = git_blob_text(b"class A {\r\n  val x = 1\r\n}");
+        assert_eq!(line_text_at(&text, 2), Some("  val x = 1"));
+        assert_eq!(line_text_at(&text, 3), Some("}"));
+        assert_eq!(line_text_at(&text, 4), None);
+        assert_eq!(line_text_at(&text, 0), None);
+    }
+
+    #[tokio::test]
+    async fn source_revision_reads_committed_lines() {
+        let root = temp_dir("source-revision");
+        let git = |args: &[&str]| {
+            std::process::Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .arg("-C")
+                .arg(&root)
+                .args(args)
+                .output()
+                .is_ok_and(|output| output.status.success())
+        };
+        if !git(&["init", "-q"]) {
+            // No git available; the parser test above still covers the output handling.
+            std::fs::remove_dir_all(&root).unwrap();
+            return;
+        }
+        std::fs::write(root.join("A.scala"), "class A {\n  val x = 1\n}\n").unwrap();
+        assert!(git(&["add", "A.scala"]));
+        assert!(git(&["commit", "-q", "-m", "init"]));
+        std::fs::write(root.join("A.scala"), "class A {\n  val x = 2\n}\n").unwrap();
+        std::fs::write(root.join("B.scala"), "class B {\n  val y = 3\n}\n").unwrap();
+
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        backend.config.write().await.source_revision = Some("HEAD".to_string());
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+        let read =
+            |path: &'static str| backend.read_locator_line(path, 2, &uri, RequestToken::DETACHED);
+        assert_eq!(read("A.scala").await, Ok(Some("  val x = 1".to_string())));
+        assert_eq!(read("B.scala").await, Ok(Some("  val y = 3".to_string())));
+
+        // Within one request the blob is read once, even when HEAD moves meanwhile.
+        let git_blobs = GitBlobs::default();
+        let request = RequestToken::DETACHED.with_git_blobs(&git_blobs);
+        let read_in_request = || backend.read_locator_line("A.scala", 2, &uri, request);
+        assert_eq!(read_in_request().await, Ok(Some("  val x = 1".to_string())));
+        assert!(git(&["commit", "-q", "-a", "-m", "bump"]));
+        assert_eq!(read_in_request().await, Ok(Some("  val x = 1".to_string())));
+        assert_eq!(read("A.scala").await, Ok(Some("  val x = 2".to_string())));
+        assert_eq!(git_blobs.lock().await.len(), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn targets_outside_allowed_roots_are_refused() {
+        let root = temp_dir("allowed-roots");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join("secret")).unwrap();
+        std::fs::write(root.join("src/A.scala"), "class A {\n  val x = 1\n}\n").unwrap();
+        std::fs::write(root.join("secret/key.txt"), "hunter2\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let mut config = Config {
+            allowed_resolve_roots: vec![PathBuf::from("src")],
+            ..Config::default()
+        };
+        config.anchor_source_roots(std::slice::from_ref(&root));
+        *backend.config.write().await = config.clone();
+        let uri = Url::from_file_path(root.join("src/Top.sv")).unwrap();
+
+        assert_eq!(
+            backend
+                .read_locator_line("A.scala", 2, &uri, RequestToken::DETACHED)
+                .await,
+            Ok(Some("  val x = 1".to_string()))
+        );
+        let secret = root.join("secret/key.txt");
+        for path in [
+            secret.to_str().unwrap().to_string(),
+            "../secret/key.txt".to_string(),
+        ] {
+            assert_eq!(
+                resolve_target_url(&path, &uri, &config, &RealFileSystem),
+                Err(ResolveError::OutsideAllowedRoots(
+                    resolution_candidates(&path, &uri, &config, &RealFileSystem)[0].clone()
+                ))
+            );
+            assert!(backend
+                .read_locator_line(&path, 1, &uri, RequestToken::DETACHED)
+                .await
+                .is_err());
+        }
+        assert!(backend
+            .read_document(&Url::from_file_path(&secret).unwrap())
+            .await
+            .is_none());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn superseded_requests_skip_disk_reads() {
+        let root = temp_dir("cancellation");
+        std::fs::write(root.join("A.scala"), "class A {\n  val x = 1\n}\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::from_file_path(root.join("Top.sv")).unwrap();
+
+        let stale = RequestToken::begin(&backend.hover_generation);
+        let current = RequestToken::begin(&backend.hover_generation);
+        assert!(stale.is_cancelled());
+        assert!(!current.is_cancelled());
+        assert!(!RequestToken::DETACHED.is_cancelled());
+
+        assert_eq!(
+            backend.read_locator_line("A.scala", 2, &uri, stale).await,
+            Ok(None)
+        );
+        let (locator, _) = parse_locator_token("A.scala:2:7", None, 1).unwrap();
+        assert!(backend
+            .locator_source_blocks(&locator, &uri, stale)
+            .await
+            .is_empty());
+        assert_eq!(
+            backend.read_locator_line("A.scala", 2, &uri, current).await,
+            Ok(Some("  val x = 1".to_string()))
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restrict_to_extensions_skips_other_documents() {
+        let root = temp_dir("restrict-extensions");
+        std::fs::write(root.join("A.scala"), "class A {\n  val x = 1\n}\n").unwrap();
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        *backend.config.write().await = Config {
+            restrict_to_extensions: vec!["fir".to_string()],
+            ..Config::default()
+        };
+
+        let text_uri = Url::from_file_path(root.join("notes.txt")).unwrap();
+        let fir_uri = Url::from_file_path(root.join("Top.fir")).unwrap();
+        for uri in [&text_uri, &fir_uri] {
+            open_document(backend, uri, "node x = a @[A.scala:2:7]").await;
+        }
+
+        assert!(hover_markdown(backend, &text_uri, 0, 15).await.is_none());
+        assert!(goto_links(backend, &text_uri, 0, 15).await.is_empty());
+        assert!(hover_markdown(backend, &fir_uri, 0, 15).await.is_some());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn initialization_options_apply_valid_keys() {
+        let (config, warnings) = Config::from_initialization_options(Some(json!({
+            "excludeDirs": ["build"],
+            "ambiguousResolution": "all",
+            "relativeLineBase": 7,
+        })));
+        assert!(warnings.is_empty());
+        assert_eq!(config.exclude_dirs, vec!["build".to_string()]);
+        assert_eq!(config.ambiguous_resolution, AmbiguousResolution::All);
+        assert_eq!(config.relative_line_base, Some(7));
+
+        assert!(Config::from_initialization_options(None).1.is_empty());
+    }
+
+    #[test]
+    fn initialization_options_warn_on_bad_keys() {
+        let (config, warnings) = Config::from_initialization_options(Some(json!({
+            "sourceRoots": "not-a-list",
+            "excludeDirs": ["build"],
+            "hoverStlye": "compact",
+        })));
+        assert_eq!(config.exclude_dirs, vec!["build".to_string()]);
+        assert!(config.source_roots.is_empty());
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings
+            .iter()
+            .any(|w| w.starts_with("ignoring option `sourceRoots`: invalid type")));
+        assert!(warnings
+            .iter()
+            .any(|w| w.starts_with("ignoring option `hoverStlye`: unknown field")));
+
+        let (_, warnings) = Config::from_initialization_options(Some(json!(["excludeDirs"])));
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn root_aliases_resolve_prefixed_paths() {
+        let uri = Url::parse("file:///work/gen/Top.sv").unwrap();
+        let config = Config {
+            root_aliases: HashMap::from([
+                ("chisel3/".to_string(), PathBuf::from("/opt/chisel3")),
+                ("rocket".to_string(), PathBuf::from("/opt/rocket-chip")),
+                ("rocket/tile".to_string(), PathBuf::from("/opt/tiles")),
+            ]),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            resolution_candidates("chisel3/src/Mux.scala", &uri, &config, &RealFileSystem),
+            vec![PathBuf::from("/opt/chisel3/src/Mux.scala")]
+        );
+        assert_eq!(
+            resolution_candidates("rocket/tile/Core.scala", &uri, &config, &RealFileSystem),
+            vec![PathBuf::from("/opt/tiles/Core.scala")]
+        );
+        assert_eq!(
+            resolution_candidates("rocketry/Fuel.scala", &uri, &config, &RealFileSystem),
+            vec![PathBuf::from("/work/gen/rocketry/Fuel.scala")]
+        );
+        assert_eq!(
+            resolution_candidates("src/Top.scala", &uri, &config, &RealFileSystem),
+            vec![PathBuf::from("/work/gen/src/Top.scala")]
+        );
+    }
+
+    #[test]
+    fn source_map_manifest_translates_paths() {
+        let root = temp_dir("sourcemap");
+        std::fs::write(
+            root.join("Top.fir.sourcemap"),
+            r#"{"Top.scala": "/src/design/Top.scala", "gen/Util.scala": "vendored/Util.scala"}"#,
+        )
+        .unwrap();
+        let uri = Url::from_file_path(root.join("Top.fir")).unwrap();
+        let config = Config::default();
+
+        assert_eq!(
+            resolution_candidates("Top.scala", &uri, &config, &RealFileSystem),
+            vec![PathBuf::from("/src/design/Top.scala")]
+        );
+        assert_eq!(
+            resolution_candidates("gen/Util.scala", &uri, &config, &RealFileSystem),
+            vec![root.join("vendored/Util.scala")]
+        );
+        assert_eq!(
+            resolution_candidates("Other.scala", &uri, &config, &RealFileSystem),
+            vec![root.join("Other.scala")]
+        );
+
+        let other_uri = Url::from_file_path(root.join("Other.fir")).unwrap();
+        assert_eq!(
+            resolution_candidates("Top.scala", &other_uri, &config, &RealFileSystem),
+            vec![root.join("Top.scala")]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn base_dir_is_tried_before_the_annotated_files_directory() {
+        let root = temp_dir("base-dir");
+        std::fs::create_dir_all(root.join("ci/src")).unwrap();
+        std::fs::create_dir_all(root.join("gen/src")).unwrap();
+        std::fs::write(root.join("ci/src/A.scala"), "class A\n").unwrap();
+        std::fs::write(root.join("gen/src/A.scala"), "class A\n").unwrap();
+        std::fs::write(root.join("gen/src/B.scala"), "class B\n").unwrap();
+        let uri = Url::from_file_path(root.join("gen/Top.sv")).unwrap();
+
+        let mut config = Config::default();
+        config.apply_base_dir_env(Some(PathBuf::from("ci")));
+        config.anchor_source_roots(std::slice::from_ref(&root));
+        assert_eq!(config.validate_base_dir(&RealFileSystem), None);
+        assert_eq!(
+            resolve_target_url("src/A.scala", &uri, &config, &RealFileSystem),
+            Ok(Url::from_file_path(root.join("ci/src/A.scala")).unwrap())
+        );
+        assert_eq!(
+            resolve_target_url("src/B.scala", &uri, &config, &RealFileSystem),
+            Ok(Url::from_file_path(root.join("gen/src/B.scala")).unwrap())
+        );
+
+        let mut explicit = Config {
+            base_dir: Some(root.join("missing")),
+            ..Config::default()
+        };
+        explicit.apply_base_dir_env(Some(root.join("ci")));
+        assert!(explicit.validate_base_dir(&RealFileSystem).is_some());
+        assert_eq!(explicit.base_dir, None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn unc_locators_resolve_to_host_file_urls() {
+        let (locator, _) =
+            parse_locator_token(r"\\server\share\src\Foo.scala:10:3", None, 1).unwrap();
+        assert_eq!(locator.path, r"\\server\share\src\Foo.scala");
+        assert_eq!((locator.line, locator.columns.clone()), (10, vec![3]));
+
+        let uri = Url::parse("file:///tmp/unc/Top.sv").unwrap();
+        let url =
+            resolve_target_url(&locator.path, &uri, &Config::default(), &RealFileSystem).unwrap();
+        assert_eq!(url.host_str(), Some("server"));
+        assert_eq!(
+            url.path_segments().unwrap().collect::<Vec<_>>(),
+            ["share", "src", "Foo.scala"]
+        );
+
+        let text = r"wire x; // @[\\server\share\Foo.scala:2:1, :3:1]";
+        let links = definition_links(
+            text,
+            &find_annotations(text, &Config::default()),
+            &uri,
+            Position::new(0, 15),
+            &Config::default(),
+            &TargetLines::default(),
+            &RealFileSystem,
+        );
+        assert_eq!(links.len(), 2);
+        assert_eq!(
+            links[1].target_uri.as_str(),
+            "file://server/share/Foo.scala"
+        );
+
+        assert!(unc_parts(r"\\?\C:\Foo.scala").is_none());
+        assert!(unc_parts(r"\\server").is_none());
+    }
+
+    #[test]
+    fn compile_commands_directories_resolve_relative_paths() {
+        let root = temp_dir("compile-commands");
+        std::fs::create_dir_all(root.join("src/core")).unwrap();
+        std::fs::write(root.join("src/core/Core.scala"), "class Core\n").unwrap();
+        std::fs::write(
+            root.join("compile_commands.json"),
+            r#"[
+                {"directory": "elsewhere", "file": "Other.sv", "command": "firtool Other.fir"},
+                {"directory": "src/core", "file": "../../gen/Top.sv", "arguments": ["firtool"]}
+            ]"#,
+        )
+        .unwrap();
+        let mut config = Config {
+            compile_commands: Some(PathBuf::from("compile_commands.json")),
+            ..Config::default()
+        };
+        config.anchor_source_roots(std::slice::from_ref(&root));
+        let uri = Url::from_file_path(root.join("gen/Top.sv")).unwrap();
+
+        assert_eq!(
+            resolution_candidates("Core.scala", &uri, &config, &RealFileSystem),
+            vec![
+                root.join("src/core/Core.scala"),
+                root.join("gen/Core.scala")
+            ]
+        );
+        assert_eq!(
+            resolve_target_url("Core.scala", &uri, &config, &RealFileSystem),
+            Ok(Url::from_file_path(root.join("src/core/Core.scala")).unwrap())
+        );
+
+        let unlisted = Url::from_file_path(root.join("gen/Unlisted.sv")).unwrap();
+        assert_eq!(
+            resolution_candidates("Core.scala", &unlisted, &config, &RealFileSystem),
+            vec![root.join("gen/Core.scala")]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn project_root_marker_anchors_relative_paths() {
+        let root = temp_dir("project-root");
+        std::fs::create_dir_all(root.join("src/main/scala")).unwrap();
+        std::fs::create_dir_all(root.join("out/gen")).unwrap();
+        std::fs::write(root.join("build.sbt"), "").unwrap();
+        std::fs::write(root.join("src/main/scala/Top.scala"), "class Top\n").unwrap();
+        let uri = Url::from_file_path(root.join("out/gen/Top.sv")).unwrap();
+        let path = "src/main/scala/Top.scala";
+
+        let mut config = Config {
+            resolve_from_project_root: true,
+            ..Config::default()
+        };
+        assert_eq!(
+            resolve_target_url(path, &uri, &config, &RealFileSystem),
+            Ok(Url::from_file_path(root.join(path)).unwrap())
+        );
+
+        config.project_root_markers = vec!["pom.xml".to_string()];
+        assert!(
+            !resolution_candidates(path, &uri, &config, &RealFileSystem).contains(&root.join(path))
+        );
+        config.resolve_from_project_root = false;
+        config.project_root_markers = Config::default().project_root_markers;
+        assert!(
+            !resolution_candidates(path, &uri, &config, &RealFileSystem).contains(&root.join(path))
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn module_index_maps_module_names_to_files() {
+        let root = temp_dir("module-index");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(
+            root.join("src/Core.scala"),
+            "package core\nclass MyModule\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("modules.json"),
+            r#"{"MyModule": "src/Core.scala"}"#,
+        )
+        .unwrap();
+        let mut config = Config {
+            module_index: Some(PathBuf::from("modules.json")),
+            ..Config::default()
+        };
+        config.anchor_source_roots(std::slice::from_ref(&root));
+        let uri = Url::from_file_path(root.join("gen/Top.sv")).unwrap();
+
+        let text = "wire x; // @[MyModule:2:7]";
+        let links = definition_links(
+            text,
+            &find_annotations(text, &config),
+            &uri,
+            Position::new(0, 15),
+            &config,
+            &TargetLines::default(),
+            &RealFileSystem,
+        );
+        assert_eq!(links.len(), 1);
+        assert_eq!(
+            links[0].target_uri,
+            Url::from_file_path(root.join("src/Core.scala")).unwrap()
+        );
+        assert_eq!(links[0].target_range.start, Position::new(1, 6));
+
+        assert_eq!(
+            resolution_candidates("Unknown", &uri, &config, &RealFileSystem),
+            vec![root.join("gen/Unknown")]
+        );
+        assert_eq!(
+            resolution_candidates("MyModule.scala", &uri, &config, &RealFileSystem),
+            vec![root.join("gen/MyModule.scala")]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ambiguous_roots_link_counts_per_mode() {
+        let root = temp_dir("ambiguous");
+        for dir in ["gen", "rootA/pkg", "rootB/pkg"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        std::fs::write(root.join("rootA/pkg/A.scala"), "class A\n").unwrap();
+        std::fs::write(root.join("rootB/pkg/A.scala"), "class A\n").unwrap();
+
+        let text = "wire x; // @[pkg/A.scala:1:1, :1:{3,5}]";
+        let lines = compute_line_starts(text);
+        let annotation = find_annotations(text, &Config::default()).pop().unwrap();
+        let tokens = parse_tokens_from_annotation(text, &annotation, &lines, &Config::default());
+        let uri = Url::from_file_path(root.join("gen/Top.sv")).unwrap();
+
+        let config_for = |mode| Config {
+            source_roots: vec![root.join("rootA"), root.join("rootB")],
+            ambiguous_resolution: mode,
+            ..Config::default()
+        };
+
+        let first = collect_location_links(
+            tokens.iter(),
+            &uri,
+            &config_for(AmbiguousResolution::First),
+            &TargetLines::default(),
+            &RealFileSystem,
+        );
+        assert_eq!(first.len(), 3);
+        assert!(first
+            .iter()
+            .all(|link| link.target_uri
+                == Url::from_file_path(root.join("rootA/pkg/A.scala")).unwrap()));
+        assert!(!has_ambiguous_targets(&first));
+
+        let all = collect_location_links(
+            tokens.iter(),
+            &uri,
+            &config_for(AmbiguousResolution::All),
+            &TargetLines::default(),
+            &RealFileSystem,
+        );
+        assert_eq!(all.len(), 6);
+        assert!(has_ambiguous_targets(&all));
+
+        let prompt = collect_location_links(
+            tokens.iter(),
+            &uri,
+            &config_for(AmbiguousResolution::Prompt),
+            &TargetLines::default(),
+            &RealFileSystem,
+        );
+        assert_eq!(prompt.len(), 6);
+
+        let missing = Config {
+            source_roots: vec![root.join("rootA")],
+            ..Config::default()
+        };
+        assert_eq!(
+            resolve_target_url("pkg/Missing.scala", &uri, &missing, &RealFileSystem).ok(),
+            Url::from_file_path(root.join("gen/pkg/Missing.scala")).ok()
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn apply_edits(text: &str, edits: &[TextEdit]) -> String {
+        let lines = compute_line_starts(text);
+        let mut result = text.to_string();
+        let mut sorted = edits.to_vec();
+        sorted.sort_by_key(|edit| {
+            std::cmp::Reverse((edit.range.start.line, edit.range.start.character))
+        });
+        for edit in sorted {
+            let start = position_to_offset(edit.range.start, text, &lines).unwrap();
+            let end = position_to_offset(edit.range.end, text, &lines).unwrap();
+            result.replace_range(start..end, &edit.new_text);
+        }
+        result
+    }
+
+    #[test]
+    fn rename_rewrites_matching_paths_only() {
+        let text = "a // @[/tmp/A.scala:1:2, :3:4, /tmp/B.scala:5:6, :7:8]\n\
+                    b // @[/tmp/A.scala:9:1, /tmp/B.scala:1:1, :2:2]";
+        let uri = Url::parse("file:///tmp/Top.sv").unwrap();
+        let config = Config::default();
+
+        let edits = rename_path_edits(
+            text,
+            &find_annotations(text, &config),
+            &uri,
+            Position::new(0, 9),
+            "/tmp/C.scala",
+            &config,
+            &RealFileSystem,
+        );
+        assert_eq!(edits.len(), 2);
+        let renamed = apply_edits(text, &edits);
+        assert_eq!(
+            renamed,
+            "a // @[/tmp/C.scala:1:2, :3:4, /tmp/B.scala:5:6, :7:8]\n\
+             b // @[/tmp/C.scala:9:1, /tmp/B.scala:1:1, :2:2]"
+        );
+
+        let lines = compute_line_starts(&renamed);
+        let annotation = find_annotations(&renamed, &config).remove(0);
+        let paths: Vec<String> =
+            parse_tokens_from_annotation(&renamed, &annotation, &lines, &config)
+                .into_iter()
+                .map(|token| token.locator.path)
+                .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/tmp/C.scala",
+                "/tmp/C.scala",
+                "/tmp/B.scala",
+                "/tmp/B.scala"
+            ]
+        );
+    }
+
+    #[test]
+    fn rename_quotes_paths_with_delimiters_and_ignores_numbers() {
+        let text = "a // @[/tmp/A.scala:1:2]";
+        let uri = Url::parse("file:///tmp/Top.sv").unwrap();
+        let config = Config::default();
+
+        let edits = rename_path_edits(
+            text,
+            &find_annotations(text, &config),
+            &uri,
+            Position::new(0, 9),
+            "/tmp/a]b.scala",
+            &config,
+            &RealFileSystem,
+        );
+        assert_eq!(apply_edits(text, &edits), "a // @[\"/tmp/a]b.scala\":1:2]");
+
+        let new_path = "/tmp/say \"hi\".scala";
+        let edits = rename_path_edits(
+            text,
+            &find_annotations(text, &config),
+            &uri,
+            Position::new(0, 9),
+            new_path,
+            &config,
+            &RealFileSystem,
+        );
+        let renamed = apply_edits(text, &edits);
+        let lines = compute_line_starts(&renamed);
+        let annotation = find_annotations(&renamed, &config).remove(0);
+        let tokens = parse_tokens_from_annotation(&renamed, &annotation, &lines, &config);
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].locator.path, new_path);
+        assert_eq!(tokens[0].locator.line_groups(), vec![(1, vec![2])]);
+
+        assert!(rename_path_edits(
+            text,
+            &find_annotations(text, &config),
+            &uri,
+            Position::new(0, 21),
+            "/tmp/C.scala",
+            &config,
+            &RealFileSystem
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn token_path_span_covers_path_portion() {
+        let text = "// @[/tmp/A.scala:12:{3,4}, :7:1, \"B.scala\":{1:2, 3:4}]";
+        let lines = compute_line_starts(text);
+        let annotation = find_annotations(text, &Config::default()).pop().unwrap();
+        let tokens = parse_tokens_from_annotation(text, &annotation, &lines, &Config::default());
+        let slice = |(start, end): ByteSpan| &text[start..end];
 
-    for (raw_start, raw_end) in split_locator_tokens(inner) {
-        if raw_start >= raw_end || raw_end > inner.len() {
-            continue;
-        }
+        assert_eq!(slice(tokens[0].path_span), "/tmp/A.scala");
+        assert_eq!(slice(tokens[1].path_span), "");
+        assert_eq!(slice(tokens[2].path_span), "\"B.scala\"");
+    }
 
-        let raw = &inner[raw_start..raw_end];
-        let leading = raw.len() - raw.trim_start().len();
-        let trailing = raw.len() - raw.trim_end().len();
-        if leading + trailing >= raw.len() {
-            continue;
+    #[test]
+    fn prepare_rename_only_on_path_portion() {
+        let text = "a // @[/tmp/A.scala:12:5, :13:6]";
+        let config = Config::default();
+
+        let range = prepare_rename_range(
+            text,
+            &find_annotations(text, &config),
+            Position::new(0, 10),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(range, Range::new(Position::new(0, 7), Position::new(0, 19)));
+
+        for character in [20, 23, 26, 28, 2] {
+            assert!(
+                prepare_rename_range(
+                    text,
+                    &find_annotations(text, &config),
+                    Position::new(0, character),
+                    &config
+                )
+                .is_none(),
+                "character {character}"
+            );
         }
+    }
+
+    #[test]
+    fn file_dedup_scope_collapses_repeated_destinations() {
+        let text = "a // @[/tmp/A.scala:1:2, :3:4]\nb // @[/tmp/A.scala:3:4, /tmp/B.scala:5:6]";
+        let uri = Url::parse("file:///tmp/Top.sv").unwrap();
+        let targets = |links: Vec<LocationLink>| -> Vec<(String, u32)> {
+            links
+                .into_iter()
+                .map(|link| {
+                    (
+                        link.target_uri.path().to_string(),
+                        link.target_range.start.line,
+                    )
+                })
+                .collect()
+        };
 
-        let token_start = raw_start + leading;
-        let token_end = raw_end - trailing;
-        let token_text = inner[token_start..token_end].to_string();
+        let per_annotation = definition_links(
+            text,
+            &find_annotations(text, &Config::default()),
+            &uri,
+            Position::new(0, 5),
+            &Config::default(),
+            &TargetLines::default(),
+            &RealFileSystem,
+        );
+        assert_eq!(
+            targets(per_annotation),
+            vec![
+                ("/tmp/A.scala".to_string(), 0),
+                ("/tmp/A.scala".to_string(), 2)
+            ]
+        );
 
-        let Some((locator, used_inherited_path)) =
-            parse_locator_token(&token_text, last_path.as_deref())
-        else {
-            continue;
+        let file_scope = Config {
+            goto_dedup_scope: DedupScope::File,
+            ..Config::default()
         };
+        assert_eq!(
+            targets(definition_links(
+                text,
+                &find_annotations(text, &file_scope),
+                &uri,
+                Position::new(0, 5),
+                &file_scope,
+                &TargetLines::default(),
+                &RealFileSystem
+            )),
+            vec![
+                ("/tmp/A.scala".to_string(), 0),
+                ("/tmp/A.scala".to_string(), 2),
+                ("/tmp/B.scala".to_string(), 4),
+            ]
+        );
 
-        if !used_inherited_path {
-            last_path = Some(locator.path.clone());
-        }
+        assert_eq!(
+            targets(definition_links(
+                text,
+                &find_annotations(text, &file_scope),
+                &uri,
+                Position::new(1, 10),
+                &file_scope,
+                &TargetLines::default(),
+                &RealFileSystem
+            )),
+            vec![
+                ("/tmp/A.scala".to_string(), 2),
+                ("/tmp/B.scala".to_string(), 4)
+            ]
+        );
+    }
 
-        let byte_start = annotation.inner_start + token_start;
-        let byte_end = annotation.inner_start + token_end;
+    #[test]
+    fn selection_range_is_empty_when_select_target_on_jump_is_off() {
+        let text = "a // @[/tmp/A.scala:3:4]";
+        let uri = Url::parse("file:///tmp/Top.sv").unwrap();
 
-        parsed.push(ParsedToken {
-            byte_start,
-            byte_end,
-            range: Range::new(
-                offset_to_position(byte_start, text, line_starts),
-                offset_to_position(byte_end, text, line_starts),
-            ),
-            locator,
-        });
+        let links = definition_links(
+            text,
+            &find_annotations(text, &Config::default()),
+            &uri,
+            Position::new(0, 12),
+            &Config::default(),
+            &TargetLines::default(),
+            &RealFileSystem,
+        );
+        assert_eq!(links[0].target_selection_range, links[0].target_range);
+
+        let config = Config {
+            select_target_on_jump: false,
+            ..Config::default()
+        };
+        let links = definition_links(
+            text,
+            &find_annotations(text, &config),
+            &uri,
+            Position::new(0, 12),
+            &config,
+            &TargetLines::default(),
+            &RealFileSystem,
+        );
+        assert_eq!(links[0].target_range.start, Position::new(2, 3));
+        assert_eq!(
+            links[0].target_selection_range,
+            Range::new(Position::new(2, 3), Position::new(2, 3))
+        );
     }
 
-    parsed
-}
+    #[tokio::test]
+    async fn target_lines_break_lines_like_hovers() {
+        let memory = Arc::new(MemoryFileSystem::default());
+        memory.write("/mem/lines/A.scala", "one\rtwo\r\nthree\n");
+        let fs: Arc<dyn FileSystem> = memory;
+        let uri = Url::parse("file:///mem/lines/Top.sv").unwrap();
+        let text = "a // @[A.scala:3:1]";
+        let config = Config::default();
+        let tokens = link_candidate_tokens(
+            text,
+            &find_annotations(text, &config),
+            Position::new(0, 2),
+            &config,
+        );
+        let target_lines = TargetLines::load(&tokens, &uri, &config, &fs).await;
 
-fn format_locator(locator: &Locator) -> String {
-    if locator.columns.len() == 1 {
-        format!("{}:{}:{}", locator.path, locator.line, locator.columns[0])
-    } else {
-        let columns = locator
-            .columns
-            .iter()
-            .map(|column| column.to_string())
-            .collect::<Vec<_>>()
-            .join(",");
-        format!("{}:{}:{{{columns}}}", locator.path, locator.line)
+        let target = Url::parse("file:///mem/lines/A.scala").unwrap();
+        let lines: Vec<Option<&str>> = (0..3).map(|line| target_lines.get(&target, line)).collect();
+        assert_eq!(lines, vec![Some("one"), Some("two"), Some("three")]);
+        assert_eq!(target_lines.get(&target, 9), None);
     }
-}
 
-fn line_text_at(text: &str, one_based_line: u32) -> Option<&str> {
-    let line_index = usize::try_from(one_based_line.checked_sub(1)?).ok()?;
-    let line = text.split('\n').nth(line_index)?;
-    Some(line.strip_suffix('\r').unwrap_or(line))
-}
+    #[tokio::test]
+    async fn identifier_targets_snap_to_the_identifier_start() {
+        let memory = Arc::new(MemoryFileSystem::default());
+        memory.write("/mem/ident/A.scala", "  val myWire = Wire(UInt(8.W))\n");
+        let fs: Arc<dyn FileSystem> = memory;
+        let uri = Url::parse("file:///mem/ident/Top.sv").unwrap();
+        // Column 9 is the `W` in the middle of `myWire`.
+        let text = "a // @[A.scala:1:9, :1:14]";
+        let mut config = Config::default();
+        let position = Position::new(0, 2);
+        let target_lines = TargetLines::load(
+            &link_candidate_tokens(text, &find_annotations(text, &config), position, &config),
+            &uri,
+            &config,
+            &fs,
+        )
+        .await;
+        let ranges = |config: &Config| {
+            definition_links(
+                text,
+                &find_annotations(text, config),
+                &uri,
+                position,
+                config,
+                &target_lines,
+                fs.as_ref(),
+            )
+            .into_iter()
+            .map(|link| link.target_range)
+            .collect::<Vec<_>>()
+        };
+        let range = |start, end| Range::new(Position::new(0, start), Position::new(0, end));
 
-fn build_column_indicator_line(source_line: &str, columns: &[u32]) -> String {
-    let mut indicators: Vec<char> = source_line
-        .chars()
-        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
-        .collect();
+        assert_eq!(ranges(&config), vec![range(8, 9), range(13, 14)]);
+        config.select_target_identifier = true;
+        // The second column is on ` = `, which is no identifier.
+        assert_eq!(ranges(&config), vec![range(6, 12), range(13, 14)]);
 
-    let mut has_valid_column = false;
-    for &column in columns {
-        if column == 0 {
-            continue;
+        assert_eq!(identifier_span("é_x$1 y", 2), Some((0, 5)));
+        assert_eq!(identifier_span("ab", 7), None);
+    }
+
+    fn ranged_change(
+        start: (u32, u32),
+        end: (u32, u32),
+        text: &str,
+    ) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: Some(Range::new(
+                Position::new(start.0, start.1),
+                Position::new(end.0, end.1),
+            )),
+            range_length: None,
+            text: text.to_string(),
         }
-        has_valid_column = true;
-        let index = (column - 1) as usize;
-        if index >= indicators.len() {
-            indicators.resize(index + 1, ' ');
+    }
+
+    #[test]
+    fn reindex_shifts_annotations_after_the_edit() {
+        let config = Config::default();
+        let mut text =
+            "a // @[A.scala:1:1]\nb // @[B.scala:2:2]\nc // @[C.scala:3:3]\n".to_string();
+        let before = find_annotations(&text, &config);
+
+        let edit =
+            apply_content_change(&mut text, &ranged_change((1, 0), (1, 1), "bus_b")).unwrap();
+        assert_eq!(
+            edit,
+            EditSpan {
+                start: 20,
+                old_end: 21,
+                new_end: 25
+            }
+        );
+        let after = reindex_annotations(&text, &before, edit, &config);
+
+        assert_eq!(after, find_annotations(&text, &config));
+        assert_eq!(after[0], before[0]);
+        for (old, new) in before[1..].iter().zip(&after[1..]) {
+            assert_eq!(new.full_start, old.full_start + 4);
+            assert_eq!(new.inner_end, old.inner_end + 4);
         }
-        indicators[index] = '^';
     }
 
-    if !has_valid_column {
-        return "^".to_string();
+    #[test]
+    fn reindex_matches_full_scan_for_structural_edits() {
+        let config = Config::default();
+        let original = "a // @[A.scala:1:1]\nb // @[B.scala:2:2]\nc // @[C.scala:3:3]\n";
+        let edits = [
+            ranged_change((1, 13), (1, 14), "9"),
+            ranged_change((1, 18), (1, 19), ""),
+            ranged_change((0, 19), (0, 19), " @["),
+            ranged_change((0, 5), (2, 5), ""),
+            ranged_change((3, 0), (3, 0), "d // @[D.scala:4:4]"),
+            ranged_change((0, 40), (0, 40), "// @[E.scala:5:5]"),
+        ];
+        for change in edits {
+            let mut text = original.to_string();
+            let before = find_annotations(&text, &config);
+            let edit = apply_content_change(&mut text, &change).unwrap();
+            assert_eq!(
+                reindex_annotations(&text, &before, edit, &config),
+                find_annotations(&text, &config),
+                "after {change:?}: {text:?}"
+            );
+        }
     }
 
-    if let Some(last) = indicators.iter().rposition(|ch| *ch == '^') {
-        indicators.truncate(last + 1);
+    #[tokio::test]
+    async fn incremental_changes_update_document_and_index() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/incremental/Top.sv").unwrap();
+        open_document(backend, &uri, "wire x; // @[/tmp/A.scala:2:7]\nwire y;\n").await;
+
+        backend
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+                content_changes: vec![
+                    ranged_change((1, 7), (1, 7), " // @[/tmp/B.scala:4:1]"),
+                    ranged_change((0, 0), (0, 4), "logic"),
+                ],
+            })
+            .await;
+
+        let text = backend.documents.read().await[&uri].clone();
+        assert_eq!(
+            text,
+            "logic x; // @[/tmp/A.scala:2:7]\nwire y; // @[/tmp/B.scala:4:1]\n"
+        );
+        assert_eq!(
+            backend.annotation_index.read().await[&uri],
+            find_annotations(&text, &Config::default())
+        );
+        let links = goto_links(backend, &uri, 1, 15).await;
+        assert_eq!(links[0].target_uri.path(), "/tmp/B.scala");
     }
 
-    indicators.into_iter().collect()
-}
+    #[tokio::test]
+    async fn annotation_free_documents_answer_from_the_index() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/annotation-free/Top.sv").unwrap();
+        open_document(backend, &uri, "wire x;\nwire y;\n").await;
+        assert!(backend.known_annotation_free(&uri).await);
+        assert!(backend.clean_documents.read().await.contains(&uri));
 
-fn markdown_language_from_path(path: &str) -> &'static str {
-    let lower = path.to_ascii_lowercase();
-    if lower.ends_with(".scala") {
-        "scala"
-    } else if lower.ends_with(".fir") || lower.ends_with(".firrtl") {
-        "firrtl"
-    } else if lower.ends_with(".rs") {
-        "rust"
-    } else if lower.ends_with(".py") {
-        "python"
-    } else if lower.ends_with(".sv") || lower.ends_with(".svh") || lower.ends_with(".v") {
-        "verilog"
-    } else {
-        "text"
+        // Swap in annotated text behind the index: requests must not rescan it.
+        backend
+            .documents
+            .write()
+            .await
+            .insert(uri.clone(), "wire x; // @[/tmp/A.scala:1:1]\n".to_string());
+        assert!(goto_links(backend, &uri, 0, 15).await.is_empty());
+        assert!(hover_markdown(backend, &uri, 0, 15).await.is_none());
+
+        backend
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: "wire x; // @[/tmp/A.scala:1:1]\n".to_string(),
+                }],
+            })
+            .await;
+        assert!(!backend.known_annotation_free(&uri).await);
+        assert_eq!(goto_links(backend, &uri, 0, 15).await.len(), 1);
     }
-}
 
-fn line_start_for_offset(offset: usize, line_starts: &[usize]) -> usize {
-    let line = match line_starts.binary_search(&offset) {
-        Ok(index) => index,
-        Err(0) => 0,
-        Err(index) => index - 1,
-    };
-    line_starts[line]
-}
+    #[tokio::test]
+    async fn did_change_advances_the_document_version() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/versions/Top.sv").unwrap();
+        open_document(backend, &uri, "wire x;\n").await;
+        assert_eq!(backend.document_version(&uri).await, Some(1));
 
-fn summary_hover_byte_range(
-    text: &str,
-    annotation: &AnnotationSpan,
-    line_starts: &[usize],
-) -> (usize, usize) {
-    let at_start = annotation.full_start;
-    let fallback_end = (at_start + 2).min(text.len());
-    let line_start = line_start_for_offset(at_start, line_starts);
-    let bytes = text.as_bytes();
+        let change = |version: i32, text: &str| DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(uri.clone(), version),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: text.to_string(),
+            }],
+        };
+        backend.did_change(change(3, "wire y;\n")).await;
+        assert_eq!(backend.document_version(&uri).await, Some(3));
 
-    if at_start + 2 <= bytes.len() {
-        if at_start >= 3 {
-            let start = at_start - 3;
-            if start >= line_start && bytes[start..at_start + 2] == *b"// @[" {
-                return (start, at_start + 2);
-            }
-        }
+        // Older or repeated versions must neither rewind the version nor the text.
+        backend.did_change(change(2, "wire z;\n")).await;
+        backend.did_change(change(3, "wire z;\n")).await;
+        assert_eq!(backend.document_version(&uri).await, Some(3));
+        assert_eq!(backend.documents.read().await[&uri], "wire y;\n");
 
-        if at_start >= 2 {
-            let start = at_start - 2;
-            if start >= line_start && bytes[start..at_start + 2] == *b"//@[" {
-                return (start, at_start + 2);
-            }
-        }
+        backend
+            .did_close(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+            })
+            .await;
+        assert_eq!(backend.document_version(&uri).await, None);
     }
 
-    (at_start, fallback_end)
-}
+    #[tokio::test]
+    async fn rename_reads_annotations_from_the_index() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/indexed/Top.sv").unwrap();
+        open_document(backend, &uri, "wire x; // @[/tmp/A.scala:2:7]\n").await;
+        let params = TextDocumentPositionParams::new(
+            TextDocumentIdentifier::new(uri.clone()),
+            Position::new(0, 15),
+        );
+        assert!(backend
+            .prepare_rename(params.clone())
+            .await
+            .unwrap()
+            .is_some());
 
-#[tokio::main]
-async fn main() {
-    let stdin = tokio::io::stdin();
-    let stdout = tokio::io::stdout();
+        // A request trusts the index over the text, so an emptied index hides the annotation.
+        backend
+            .annotation_index
+            .write()
+            .await
+            .insert(uri.clone(), Vec::new());
+        assert!(backend.prepare_rename(params).await.unwrap().is_none());
+    }
 
-    let (service, socket) = LspService::new(Backend::new);
-    Server::new(stdin, stdout, socket).serve(service).await;
-}
+    #[tokio::test]
+    async fn empty_change_list_keeps_the_document() {
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/empty-change/Top.sv").unwrap();
+        let text = "wire x; // @[/tmp/A.scala:2:7]\n";
+        open_document(backend, &uri, text).await;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        backend
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+                content_changes: Vec::new(),
+            })
+            .await;
+
+        assert_eq!(backend.documents.read().await[&uri], text);
+        assert_eq!(
+            backend.annotation_index.read().await[&uri],
+            find_annotations(text, &Config::default())
+        );
+    }
 
     #[test]
-    fn parse_inherited_path_token() {
-        let first = parse_locator_token("/tmp/Foo.scala:12:5", None).unwrap();
-        assert_eq!(first.0.path, "/tmp/Foo.scala");
-        assert!(!first.1);
+    fn long_single_line_positions_use_bounded_scans() {
+        let chunk = "wire é𝒳 // @[/tmp/A.scala:1:2] ";
+        let text = chunk.repeat(1024 * 1024 / chunk.len());
+        let lines = compute_line_starts(&text);
+        assert_eq!(lines.len(), 1);
 
-        let inherited = parse_locator_token(":13:{7,9}", Some(&first.0.path)).unwrap();
-        assert_eq!(inherited.0.path, "/tmp/Foo.scala");
-        assert_eq!(inherited.0.columns, vec![7, 9]);
-        assert!(inherited.1);
+        let annotations = find_annotations(&text, &Config::default());
+        assert!(annotations.len() > 20_000);
+        let chunk_utf16 = chunk.encode_utf16().count() as u32;
+        let at_utf16 = chunk[..chunk.find('@').unwrap()].encode_utf16().count() as u32;
+        for (index, annotation) in annotations.iter().enumerate() {
+            let (checkpoint, _) = lines.checkpoint_for(annotation.full_start);
+            assert!(annotation.full_start - checkpoint < UTF16_CHECKPOINT_BYTES + 4);
+            assert_eq!(
+                offset_to_position(annotation.full_start, &text, &lines),
+                Position::new(0, index as u32 * chunk_utf16 + at_utf16)
+            );
+        }
     }
 
     #[test]
-    fn split_tokens_respects_braces() {
-        let input = "/a.scala:1:2, :3:{4,5,6}, /b.scala:7:8";
-        let slices = split_locator_tokens(input);
-        let tokens: Vec<&str> = slices
+    fn workspace_walk_and_allowed_roots_run_against_memory() {
+        let fs = MemoryFileSystem::default();
+        for file in [
+            "/ws/top.fir",
+            "/ws/b/z.fir",
+            "/ws/a/y.fir",
+            "/ws/target/x.fir",
+        ] {
+            fs.write(file, "");
+        }
+        assert_eq!(
+            walk_source_roots(&[PathBuf::from("/ws")], &[], &fs),
+            ["/ws/top.fir", "/ws/a/y.fir", "/ws/b/z.fir"].map(PathBuf::from)
+        );
+
+        let config = Config {
+            allowed_resolve_roots: vec![PathBuf::from("/ws/a")],
+            ..Config::default()
+        };
+        assert!(config.allows_target(Path::new("/ws/b/../a/y.fir"), &fs));
+        assert!(!config.allows_target(Path::new("/ws/a/../b/z.fir"), &fs));
+        assert!(fs.is_dir(Path::new("/ws/a")) && !fs.is_dir(Path::new("/ws/top.fir")));
+    }
+
+    #[test]
+    fn walker_skips_excluded_directories() {
+        let root = temp_dir("walker");
+        for dir in ["src/core", "target/debug", "gen/skip", ".git"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "top.sv",
+            "src/core/A.scala",
+            "target/debug/out.sv",
+            "gen/skip/B.scala",
+            ".git/HEAD",
+        ] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+
+        let files = walk_source_roots(
+            std::slice::from_ref(&root),
+            &["gen/skip".to_string()],
+            &RealFileSystem,
+        );
+        let relative: Vec<PathBuf> = files
             .iter()
-            .map(|(start, end)| &input[*start..*end])
+            .map(|file| file.strip_prefix(&root).unwrap().to_path_buf())
             .collect();
 
-        assert_eq!(tokens, vec!["/a.scala:1:2", " :3:{4,5,6}", " /b.scala:7:8"]);
+        assert_eq!(
+            relative,
+            vec![PathBuf::from("top.sv"), PathBuf::from("src/core/A.scala")]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
     #[test]
-    fn parse_annotation_example() {
-        let text = "wire x; // @[/tmp/A.scala:10:3, :11:{4,9}, /tmp/B.scala:12:8]";
+    fn summary_hover_range_expands_to_comment_prefix() {
+        let text = "wire x; // @[/tmp/A.scala:10:3]";
         let lines = compute_line_starts(text);
-        let annotation = find_annotations(text).pop().unwrap();
-        let tokens = parse_tokens_from_annotation(text, &annotation, &lines);
-
-        assert_eq!(tokens.len(), 3);
-        assert_eq!(tokens[0].locator.path, "/tmp/A.scala");
-        assert_eq!(tokens[1].locator.path, "/tmp/A.scala");
-        assert_eq!(tokens[2].locator.path, "/tmp/B.scala");
-        assert_eq!(tokens[1].locator.columns, vec![4, 9]);
+        let annotation = find_annotations(text, &Config::default()).pop().unwrap();
+        let (start, end) = summary_hover_byte_range(text, &annotation, &lines);
+        assert_eq!(&text[start..end], "// @[");
     }
 
     #[test]
-    fn line_text_at_supports_crlf() {
-        let text = "line1\r\nline2\r\nline3";
-        assert_eq!(line_text_at(text, 1), Some("line1"));
-        assert_eq!(line_text_at(text, 2), Some("line2"));
-        assert_eq!(line_text_at(text, 3), Some("line3"));
-        assert_eq!(line_text_at(text, 4), None);
+    fn summary_hover_range_accepts_unspaced_and_wide_prefixes() {
+        for (text, expected) in [
+            ("wire x; //@[/tmp/A.scala:10:3]", "//@["),
+            ("wire x; //\t  @[/tmp/A.scala:10:3]", "//\t  @["),
+        ] {
+            let lines = compute_line_starts(text);
+            let annotation = find_annotations(text, &Config::default()).pop().unwrap();
+            let (start, end) = summary_hover_byte_range(text, &annotation, &lines);
+            assert_eq!(&text[start..end], expected);
+        }
     }
 
     #[test]
-    fn column_indicator_marks_all_columns() {
-        let marker = build_column_indicator_line("abcdef", &[2, 5]);
-        assert_eq!(marker, " ^  ^");
+    fn comment_prefix_requirement_skips_bare_annotations() {
+        let text = "assign y = x@[3]; // @[/tmp/A.scala:10:3]\nfoo @[/tmp/B.scala:1:1]";
+        let config = Config {
+            annotation_require_comment_prefix: true,
+            ..Config::default()
+        };
+
+        let spans = find_annotations(text, &config);
+        assert_eq!(spans.len(), 1);
+        assert_eq!(
+            &text[spans[0].inner_start..spans[0].inner_end],
+            "/tmp/A.scala:10:3"
+        );
+
+        assert_eq!(find_annotations(text, &Config::default()).len(), 3);
     }
 
-    #[test]
-    fn markdown_language_from_extension() {
-        assert_eq!(markdown_language_from_path("/tmp/src/Foo.scala"), "scala");
-        assert_eq!(markdown_language_from_path("/tmp/src/foo.fir"), "firrtl");
-        assert_eq!(markdown_language_from_path("/tmp/src/foo.unknown"), "text");
+    #[tokio::test]
+    async fn summary_hover_range_covers_info_prefix() {
+        let text = "  node x = add(a, b)\n    info = @[A:10:3]\n";
+        let lines = compute_line_starts(text);
+        let annotation = find_annotations(text, &Config::default()).pop().unwrap();
+        let (start, end) = summary_hover_byte_range(text, &annotation, &lines);
+        assert_eq!(&text[start..end], "info = @[");
+
+        let other_info = "    myinfo = @[A:10:3]";
+        let annotation = find_annotations(other_info, &Config::default())
+            .pop()
+            .unwrap();
+        let (start, end) =
+            summary_hover_byte_range(other_info, &annotation, &compute_line_starts(other_info));
+        assert_eq!(&other_info[start..end], "@[");
+
+        let (service, _socket) = test_service();
+        let backend = service.inner();
+        let uri = Url::parse("file:///tmp/info-prefix/Top.fir").unwrap();
+        open_document(backend, &uri, text).await;
+        let summary = Some(Range::new(Position::new(1, 4), Position::new(1, 13)));
+        for (character, expected) in [(4, summary), (11, summary), (3, None)] {
+            let range = backend
+                .hover(HoverParams {
+                    text_document_position_params: position_params(&uri, 1, character),
+                    work_done_progress_params: WorkDoneProgressParams::default(),
+                })
+                .await
+                .unwrap()
+                .and_then(|hover| hover.range);
+            assert_eq!(range, expected, "hover at character {character}");
+        }
+        assert_eq!(goto_links(backend, &uri, 1, 5).await.len(), 1);
     }
 
     #[test]
-    fn summary_hover_range_expands_to_comment_prefix() {
-        let text = "wire x; // @[/tmp/A.scala:10:3]";
+    fn indented_bare_annotation_summary_covers_only_the_opener() {
+        let text = "assign y = x;\n  \t@[A:10:3]\n";
         let lines = compute_line_starts(text);
-        let annotation = find_annotations(text).pop().unwrap();
+        let annotation = find_annotations(text, &Config::default()).pop().unwrap();
         let (start, end) = summary_hover_byte_range(text, &annotation, &lines);
-        assert_eq!(&text[start..end], "// @[");
+        assert_eq!(&text[start..end], "@[");
+        assert_eq!(start, text.find('@').unwrap());
+
+        // The indentation itself is not part of the annotation.
+        assert!(find_annotation_at_offset(text, start - 1, &Config::default()).is_none());
+        assert!(find_annotation_at_offset(text, start, &Config::default()).is_some());
+    }
+
+    #[test]
+    fn fullwidth_brackets_are_annotations_with_unicode_brackets() {
+        let text = "wire é; // ＠［/tmp/A.scala:2:3, :4:5］ // @[/tmp/B.scala:1:1]";
+        let config = Config {
+            unicode_brackets: true,
+            ..Config::default()
+        };
+        assert_eq!(find_annotations(text, &Config::default()).len(), 1);
+
+        let annotations = find_annotations(text, &config);
+        assert_eq!(annotations.len(), 2);
+        let fullwidth = &annotations[0];
+        assert_eq!(
+            &text[fullwidth.full_start..fullwidth.full_end],
+            "＠［/tmp/A.scala:2:3, :4:5］"
+        );
+        assert_eq!(
+            &text[fullwidth.inner_start..fullwidth.inner_end],
+            "/tmp/A.scala:2:3, :4:5"
+        );
+
+        let lines = compute_line_starts(text);
+        let tokens = parse_tokens_from_annotation(text, fullwidth, &lines, &config);
+        assert_eq!(tokens.len(), 2);
+        // UTF-16: `é` and each fullwidth bracket are one code unit.
+        assert_eq!(
+            tokens[0].range,
+            Range::new(Position::new(0, 13), Position::new(0, 29))
+        );
+        assert_eq!(
+            summary_hover_byte_range(text, fullwidth, &lines),
+            (text.find("//").unwrap(), fullwidth.inner_start)
+        );
+        assert_eq!(
+            offset_to_position(fullwidth.full_end, text, &lines),
+            Position::new(0, 36)
+        );
+    }
+
+    #[test]
+    fn escaped_openers_are_not_annotations() {
+        let text = "doc: write \\@[not:an:annotation] or \\@[open\nwire x; // @[A.scala:1:2 \\@[x]";
+        let annotations = find_annotations(text, &Config::default());
+        assert_eq!(annotations.len(), 1);
+        assert_eq!(
+            &text[annotations[0].inner_start..annotations[0].inner_end],
+            "A.scala:1:2 \\@[x"
+        );
+        assert!(find_unterminated_annotations(text, &annotations, &Config::default()).is_empty());
     }
 
     #[test]
     fn summary_hover_range_falls_back_to_at_block() {
         let text = "@[/tmp/A.scala:10:3]";
         let lines = compute_line_starts(text);
-        let annotation = find_annotations(text).pop().unwrap();
+        let annotation = find_annotations(text, &Config::default()).pop().unwrap();
         let (start, end) = summary_hover_byte_range(text, &annotation, &lines);
         assert_eq!(&text[start..end], "@[");
     }
+
+    #[test]
+    fn duplicate_adjacent_tokens_collapse_when_enabled() {
+        let text = "x // @[A.scala:1:2, A.scala:1:2, :3:4, A.scala:1:2]";
+        let lines = compute_line_starts(text);
+        let mut config = Config::default();
+        let annotation = find_annotations(text, &config).pop().unwrap();
+        let parse = |config: &Config| {
+            parse_tokens_from_annotation(text, &annotation, &lines, config)
+                .iter()
+                .map(|token| (format_locator(&token.locator), token.byte_start))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(parse(&config).len(), 4);
+        config.collapse_duplicate_tokens = true;
+        assert_eq!(
+            parse(&config),
+            vec![
+                ("A.scala:1:2".to_string(), 7),
+                ("A.scala:3:4".to_string(), 33),
+                ("A.scala:1:2".to_string(), 39),
+            ]
+        );
+    }
+
+    #[test]
+    fn annotation_sigil_selects_the_opener() {
+        let config = Config {
+            annotation_sigil: '#',
+            location_syntax: LocationSyntax::All,
+            ..Config::default()
+        };
+        let text = "wire x; // #[A:10:3] @[B:1:2]\nnode y = loc(\"C\":2:1) #[D:4:5";
+        let lines = compute_line_starts(text);
+        let annotations = find_annotations(text, &config);
+        let inner: Vec<&str> = annotations
+            .iter()
+            .map(|annotation| &text[annotation.inner_start..annotation.inner_end])
+            .collect();
+        assert_eq!(inner, vec!["A:10:3", "\"C\":2:1"]);
+
+        let tokens = parse_tokens_from_annotation(text, &annotations[0], &lines, &config);
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(format_locator(&tokens[0].locator), "A:10:3");
+        let (start, end) = summary_hover_byte_range(text, &annotations[0], &lines);
+        assert_eq!(&text[start..end], "// #[");
+
+        let unterminated = find_unterminated_annotations(text, &annotations, &config);
+        assert_eq!(unterminated.len(), 1);
+        assert_eq!(
+            unterminated_diagnostic(text, &lines, &unterminated[0], &config).message,
+            "unterminated locator: `#[` has no closing `]`"
+        );
+    }
 }
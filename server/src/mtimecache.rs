@@ -0,0 +1,42 @@
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Values derived from files on disk, keyed by `K` with the mtime they were
+/// derived at, so a file is only parsed again once it changed. Meant for statics.
+pub struct MtimeCache<K, V> {
+    entries: Mutex<BTreeMap<K, (SystemTime, Arc<V>)>>,
+}
+
+impl<K: Ord, V> MtimeCache<K, V> {
+    pub const fn new() -> Self {
+        Self {
+            entries: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The value stored for `key`, unless its file changed since: `modified` is
+    /// the mtime the file has now.
+    pub fn get<Q>(&self, key: &Q, modified: SystemTime) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let entries = self
+            .entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (cached_at, value) = entries.get(key)?;
+        (*cached_at == modified).then(|| Arc::clone(value))
+    }
+
+    pub fn insert(&self, key: K, modified: SystemTime, value: V) -> Arc<V> {
+        let value = Arc::new(value);
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(key, (modified, Arc::clone(&value)));
+        value
+    }
+}
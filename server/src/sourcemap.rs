@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::fs::FileSystem;
+use crate::mtimecache::MtimeCache;
+
+/// Suffix appended to an annotated file's name to find its source map, e.g.
+/// `Top.fir` -> `Top.fir.sourcemap`.
+pub const SOURCE_MAP_SUFFIX: &str = ".sourcemap";
+
+type SourceMap = HashMap<String, PathBuf>;
+
+/// Loaded source maps keyed by manifest path.
+static CACHE: MtimeCache<PathBuf, SourceMap> = MtimeCache::new();
+
+/// Path of the source map belonging to `annotated_file`.
+pub fn source_map_path(annotated_file: &Path) -> Option<PathBuf> {
+    let mut name = annotated_file.file_name()?.to_os_string();
+    name.push(SOURCE_MAP_SUFFIX);
+    Some(annotated_file.with_file_name(name))
+}
+
+/// Looks `locator_path` up in the source map next to `annotated_file`.
+///
+/// The manifest is a JSON object mapping locator paths to target paths; relative
+/// targets are taken relative to the manifest's directory. A missing or malformed
+/// manifest maps nothing.
+pub fn lookup(fs: &dyn FileSystem, annotated_file: &Path, locator_path: &str) -> Option<PathBuf> {
+    lookup_in(fs, &source_map_path(annotated_file)?, locator_path)
+}
+
+/// Looks a module name up in the `moduleIndex` file `index_file`, a JSON object
+/// mapping module names to files in the same shape as a source map.
+pub fn lookup_module(fs: &dyn FileSystem, index_file: &Path, module: &str) -> Option<PathBuf> {
+    lookup_in(fs, index_file, module)
+}
+
+fn lookup_in(fs: &dyn FileSystem, manifest: &Path, key: &str) -> Option<PathBuf> {
+    let target = load(fs, manifest)?.get(key)?.clone();
+    if target.is_absolute() {
+        Some(target)
+    } else {
+        Some(manifest.parent()?.join(target))
+    }
+}
+
+/// Returns the parsed manifest, rereading it only when its mtime changed.
+fn load(fs: &dyn FileSystem, manifest: &Path) -> Option<Arc<SourceMap>> {
+    let modified = fs.metadata(manifest).ok()?.modified;
+    if let Some(map) = CACHE.get(manifest, modified) {
+        return Some(map);
+    }
+
+    let text = fs.read_to_string(manifest).ok()?;
+    let map: SourceMap = serde_json::from_str(&text).ok()?;
+    Some(CACHE.insert(manifest.to_path_buf(), modified, map))
+}
@@ -2,8 +2,8 @@ use std::fs;
 
 use zed_extension_api::{
     self as zed, current_platform, download_file, github_release_by_tag_name, make_file_executable,
-    set_language_server_installation_status, Architecture, DownloadedFileType, GithubRelease,
-    LanguageServerInstallationStatus, Os, Result,
+    serde_json, set_language_server_installation_status, settings::LspSettings, Architecture,
+    DownloadedFileType, GithubRelease, LanguageServerInstallationStatus, Os, Result,
 };
 
 const SERVER_BIN_NAME: &str = "firrtl-source-locator-server";
@@ -292,6 +292,18 @@ impl zed::Extension for FirrtlSourceLocatorExtension {
             env: Default::default(),
         })
     }
+
+    fn language_server_initialization_options(
+        &mut self,
+        language_server_id: &zed::LanguageServerId,
+        worktree: &zed::Worktree,
+    ) -> Result<Option<serde_json::Value>> {
+        Ok(
+            LspSettings::for_worktree(language_server_id.as_ref(), worktree)
+                .ok()
+                .and_then(|settings| settings.initialization_options),
+        )
+    }
 }
 
 zed::register_extension!(FirrtlSourceLocatorExtension);